@@ -0,0 +1,356 @@
+extern crate image;
+
+use std::f32;
+use std::io;
+use std::path::Path;
+
+use math::*;
+use core::raytracer::*;
+use core::film::*;
+use core::exr::*;
+use core::tonemap::*;
+
+/// Arbitrary output variable: per-pixel information about the first visible
+/// surface, rendered alongside the beauty image for compositing and as
+/// guides for denoising.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Aov {
+    /// Distance from the camera along the ray.
+    Depth,
+    /// World space shading normal.
+    Normal,
+    Albedo,
+    /// World space position.
+    Position,
+    /// Index into `Scene::objects` plus one, 0 where nothing was hit.
+    ObjectId,
+    /// Index of the material in order of first appearance plus one, 0 where
+    /// nothing was hit.
+    MaterialId,
+}
+
+pub const ALL_AOVS: [Aov; 6] = [
+    Aov::Depth,
+    Aov::Normal,
+    Aov::Albedo,
+    Aov::Position,
+    Aov::ObjectId,
+    Aov::MaterialId,
+];
+
+impl Aov {
+    pub fn from_name(name: &str) -> Option<Aov> {
+        ALL_AOVS.iter().cloned().find(|aov| aov.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Aov::Depth => "depth",
+            Aov::Normal => "normal",
+            Aov::Albedo => "albedo",
+            Aov::Position => "position",
+            Aov::ObjectId => "object",
+            Aov::MaterialId => "material",
+        }
+    }
+
+    /// OpenEXR channels of the layer, in the order of `AovBuffers::planes`.
+    pub fn channels(&self) -> &'static [&'static str] {
+        match *self {
+            Aov::Depth => &["depth.Z"],
+            Aov::Normal => &["normal.X", "normal.Y", "normal.Z"],
+            Aov::Albedo => &["albedo.R", "albedo.G", "albedo.B"],
+            Aov::Position => &["position.X", "position.Y", "position.Z"],
+            Aov::ObjectId => &["object.id"],
+            Aov::MaterialId => &["material.id"],
+        }
+    }
+
+    fn is_id(&self) -> bool {
+        *self == Aov::ObjectId || *self == Aov::MaterialId
+    }
+}
+
+/// Accumulates the enabled AOVs over all passes. Depth, normal, albedo and
+/// position are averaged over the samples that hit something, so edges are
+/// antialiased like the beauty image. IDs can't be blended and keep the
+/// first hit of each pixel.
+pub struct AovBuffers {
+    pub width: u32,
+    pub height: u32,
+    pub enabled: Vec<Aov>,
+    hits: Vec<f32>,
+    depth: Vec<f32>,
+    normal: Vec<Vec3>,
+    albedo: Vec<Vec3>,
+    position: Vec<Vec3>,
+    object: Vec<u32>,
+    material: Vec<u32>,
+    // materials seen so far, a material's ID is its index plus one
+    materials: Vec<Material>,
+}
+
+impl AovBuffers {
+    pub fn new(dimensions: (u32, u32), enabled: Vec<Aov>) -> AovBuffers {
+        let count = (dimensions.0 * dimensions.1) as usize;
+        AovBuffers {
+            width: dimensions.0,
+            height: dimensions.1,
+            enabled,
+            hits: vec![0.; count],
+            depth: vec![0.; count],
+            normal: vec![Vec3::zero(); count],
+            albedo: vec![Vec3::zero(); count],
+            position: vec![Vec3::zero(); count],
+            object: vec![0; count],
+            material: vec![0; count],
+            materials: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = AovBuffers::new((self.width, self.height), self.enabled.clone());
+    }
+
+    fn material_id(&mut self, material: &Material) -> u32 {
+        let index = match self.materials.iter().position(|m| m == material) {
+            Some(index) => index,
+            None => {
+                self.materials.push(material.clone());
+                self.materials.len() - 1
+            }
+        };
+        index as u32 + 1
+    }
+
+    /// Records the first visible surface of a camera sample, `None` if it
+    /// escaped to the sky.
+    pub fn add_sample(&mut self, x: u32, y: u32, ray: &Ray, hit: Option<&IntersectionResult>) {
+        let i = (y * self.width + x) as usize;
+        let hit = match hit {
+            Some(hit) => hit,
+            None => return,
+        };
+        self.hits[i] += 1.;
+        self.depth[i] += hit.t;
+        self.normal[i] = self.normal[i] + hit.n;
+        self.albedo[i] = self.albedo[i] + hit.material.albedo();
+        self.position[i] = self.position[i] + ray.point(hit.t);
+        if self.object[i] == 0 {
+            self.object[i] = hit.object as u32 + 1;
+            self.material[i] = self.material_id(&hit.material);
+        }
+    }
+
+    fn mean(&self, i: usize, v: Vec3) -> Vec3 {
+        if self.hits[i] > 0. {
+            v / self.hits[i]
+        } else {
+            Vec3::zero()
+        }
+    }
+
+    fn mean_depth(&self, i: usize) -> f32 {
+        if self.hits[i] > 0. {
+            self.depth[i] / self.hits[i]
+        } else {
+            0.
+        }
+    }
+
+    /// Per-pixel values of `aov`, rows from the top. Scalars are repeated
+    /// in all three components.
+    pub fn pixels(&self, aov: Aov) -> Vec<Vec3> {
+        (0..self.hits.len())
+            .map(|i| match aov {
+                Aov::Depth => {
+                    let d = self.mean_depth(i);
+                    Vec3::new(d, d, d)
+                }
+                Aov::Normal => {
+                    let n = self.mean(i, self.normal[i]);
+                    if n.square_length() > 0. { n.normalize() } else { n }
+                }
+                Aov::Albedo => self.mean(i, self.albedo[i]),
+                Aov::Position => self.mean(i, self.position[i]),
+                Aov::ObjectId => {
+                    let id = self.object[i] as f32;
+                    Vec3::new(id, id, id)
+                }
+                Aov::MaterialId => {
+                    let id = self.material[i] as f32;
+                    Vec3::new(id, id, id)
+                }
+            })
+            .collect()
+    }
+
+    /// Channel planes of `aov`, matching `Aov::channels`.
+    pub fn planes(&self, aov: Aov) -> Vec<Vec<f32>> {
+        let pixels = self.pixels(aov);
+        match aov.channels().len() {
+            1 => vec![pixels.iter().map(|v| v.x).collect()],
+            _ => vec![
+                pixels.iter().map(|v| v.x).collect(),
+                pixels.iter().map(|v| v.y).collect(),
+                pixels.iter().map(|v| v.z).collect(),
+            ],
+        }
+    }
+
+    /// Writes the beauty image from `film` together with every enabled AOV
+    /// as layers of one OpenEXR file. IDs are always stored as 32-bit floats
+    /// so they stay exact.
+    pub fn save_exr<P: AsRef<Path>>(&self, path: P, film: &Film, pixel_type: ExrPixelType) -> io::Result<()> {
+        let beauty = film.planes();
+        let layers: Vec<(Aov, Vec<Vec<f32>>)> =
+            self.enabled.iter().map(|&aov| (aov, self.planes(aov))).collect();
+
+        let mut channels = vec![
+            ExrChannel::new("R", &beauty[0], pixel_type),
+            ExrChannel::new("G", &beauty[1], pixel_type),
+            ExrChannel::new("B", &beauty[2], pixel_type),
+        ];
+        for &(aov, ref planes) in &layers {
+            let layer_type = if aov.is_id() { ExrPixelType::Float } else { pixel_type };
+            for (name, plane) in aov.channels().iter().zip(planes) {
+                channels.push(ExrChannel::new(name, plane, layer_type));
+            }
+        }
+        save_exr(path, self.width, self.height, &channels)
+    }
+
+    /// Viewable rendering of `aov`: depth normalized to the farthest hit,
+    /// normals and positions mapped to [0, 1], albedo sRGB encoded and IDs
+    /// as distinct colors.
+    pub fn to_image(&self, aov: Aov) -> image::DynamicImage {
+        let pixels = self.pixels(aov);
+        let hit = |i: usize| self.hits[i] > 0.;
+
+        let mut lo = Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut hi = -lo;
+        for (i, p) in pixels.iter().enumerate() {
+            if hit(i) {
+                lo = lo.min(*p);
+                hi = hi.max(*p);
+            }
+        }
+        let range = |v: f32, lo: f32, hi: f32| if hi > lo { (v - lo) / (hi - lo) } else { 0. };
+
+        let colors: Vec<Vec3> = pixels
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| if !hit(i) {
+                Vec3::zero()
+            } else {
+                match aov {
+                    Aov::Depth => {
+                        let d = range(v.x, 0., hi.x);
+                        Vec3::new(d, d, d)
+                    }
+                    Aov::Normal => v * 0.5 + Vec3::new(0.5, 0.5, 0.5),
+                    Aov::Albedo => Vec3::new(srgb_encode(v.x), srgb_encode(v.y), srgb_encode(v.z)),
+                    Aov::Position => Vec3::new(
+                        range(v.x, lo.x, hi.x),
+                        range(v.y, lo.y, hi.y),
+                        range(v.z, lo.z, hi.z),
+                    ),
+                    Aov::ObjectId | Aov::MaterialId => id_color(v.x as u32),
+                }
+            })
+            .collect();
+
+        let quantize = |v: f32| (v * 255. + 0.5).clamp(0., 255.) as u8;
+        let width = self.width;
+        let buffer = image::ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let c = colors[(y * width + x) as usize];
+            image::Rgb([quantize(c.x), quantize(c.y), quantize(c.z)])
+        });
+        image::DynamicImage::ImageRgb8(buffer)
+    }
+}
+
+/// Stable, well separated color for an ID, black for 0.
+fn id_color(id: u32) -> Vec3 {
+    if id == 0 {
+        return Vec3::zero();
+    }
+    // golden ratio steps around the hue circle keep consecutive IDs apart
+    let hue = (id as f32 * 0.618034).fract() * 6.;
+    let channel = |offset: f32| {
+        let k = (offset + hue) % 6.;
+        0.9 - 0.7 * k.min(4. - k).clamp(0., 1.)
+    };
+    Vec3::new(channel(5.), channel(3.), channel(1.))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-5
+    }
+
+    fn hit(t: f32, n: Vec3, material: Material, object: usize) -> IntersectionResult {
+        IntersectionResult {
+            t,
+            n,
+            uv: (0., 0.),
+            material,
+            object,
+        }
+    }
+
+    #[test]
+    fn accumulate_test() {
+        let mut aovs = AovBuffers::new((3, 2), ALL_AOVS.to_vec());
+        let red = Material::Lambertian(Vec3::new(0.8, 0.1, 0.1));
+        let blue = Material::Metal(Vec3::new(0.1, 0.1, 0.9));
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0., 0., 1.));
+        let tilted = Ray::new(Vec3::new(1., 0., 0.), Vec3::new(0., 0.6, 0.8));
+
+        // pixel (2, 1) sees an edge: two samples on the red object, one on
+        // the blue one and one escaping to the sky
+        aovs.add_sample(2, 1, &ray, Some(&hit(2., Vec3::new(0., 0., -1.), red.clone(), 4)));
+        aovs.add_sample(2, 1, &tilted, Some(&hit(5., Vec3::new(0., 1., 0.), blue.clone(), 7)));
+        aovs.add_sample(2, 1, &ray, None);
+        aovs.add_sample(2, 1, &ray, Some(&hit(5., Vec3::new(0., 0., -1.), red.clone(), 4)));
+        // pixel (0, 0) only hits blue, which is the second material seen
+        aovs.add_sample(0, 0, &ray, Some(&hit(1., Vec3::new(1., 0., 0.), blue.clone(), 7)));
+
+        let i = 5;
+        // misses don't count towards the averages
+        assert!(close(aovs.pixels(Aov::Depth)[i], Vec3::new(4., 4., 4.)));
+        let albedo = (Vec3::new(0.8, 0.1, 0.1) * 2. + Vec3::new(0.1, 0.1, 0.9)) / 3.;
+        assert!(close(aovs.pixels(Aov::Albedo)[i], albedo));
+        let position = (Vec3::new(0., 0., 2.) + Vec3::new(1., 3., 4.) + Vec3::new(0., 0., 5.)) / 3.;
+        assert!(close(aovs.pixels(Aov::Position)[i], position));
+        // the mean normal is renormalized
+        assert!(close(aovs.pixels(Aov::Normal)[i], Vec3::new(0., 1., -2.).normalize()));
+        // IDs keep the first hit, and materials are numbered by appearance
+        assert!(close(aovs.pixels(Aov::ObjectId)[i], Vec3::new(5., 5., 5.)));
+        assert!(close(aovs.pixels(Aov::MaterialId)[i], Vec3::new(1., 1., 1.)));
+        assert!(close(aovs.pixels(Aov::ObjectId)[0], Vec3::new(8., 8., 8.)));
+        assert!(close(aovs.pixels(Aov::MaterialId)[0], Vec3::new(2., 2., 2.)));
+
+        // pixels that never hit anything stay zero everywhere
+        for &aov in &ALL_AOVS {
+            for &j in &[1, 2, 3, 4] {
+                assert!(close(aovs.pixels(aov)[j], Vec3::zero()), "{} at {}", aov.name(), j);
+            }
+            let planes = aovs.planes(aov);
+            assert_eq!(planes.len(), aov.channels().len());
+            assert!(planes.iter().all(|plane| plane.len() == 6));
+        }
+        assert_eq!(aovs.planes(Aov::Normal)[2][i], aovs.pixels(Aov::Normal)[i].z);
+
+        aovs.clear();
+        for &aov in &ALL_AOVS {
+            assert!(aovs.pixels(aov).iter().all(|&v| close(v, Vec3::zero())));
+        }
+        // material numbering starts over as well
+        aovs.add_sample(0, 0, &ray, Some(&hit(1., Vec3::new(1., 0., 0.), blue, 7)));
+        assert!(close(aovs.pixels(Aov::MaterialId)[0], Vec3::new(1., 1., 1.)));
+    }
+}
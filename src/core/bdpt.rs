@@ -0,0 +1,651 @@
+use std::f32;
+use std::f32::consts::PI;
+
+use math::*;
+use core::util::*;
+use core::medium::*;
+use core::raytracer::*;
+use core::path::*;
+use core::integrator::*;
+
+/// What happens to light at a subpath vertex.
+#[derive(Clone)]
+enum VertexKind {
+    /// Point on the lens.
+    Camera,
+    /// Point light, it emits into every direction alike.
+    Light,
+    /// Diffuse surface. It reflects on whichever side light arrives from.
+    Diffuse(Vec3),
+    /// Perfect mirror or glass, paths can't be connected here.
+    Specular,
+    Medium(Medium),
+}
+
+/// One vertex of a camera or light subpath. Densities are per unit area at
+/// the vertex, or per unit volume inside media.
+#[derive(Clone)]
+struct Vertex {
+    kind: VertexKind,
+    point: Vec3,
+    /// Surface normal, zero off surfaces.
+    normal: Vec3,
+    /// Unit direction back to the previous vertex of the subpath.
+    wo: Vec3,
+    /// Medium connections leave the vertex through.
+    medium: Option<Medium>,
+    /// Camera importance or emitted radiance carried to the vertex, over
+    /// the density of the subpath so far.
+    beta: Vec3,
+    /// Density of sampling the vertex from the previous one, and of
+    /// sampling it from the next one when tracing the other way.
+    pdf_fwd: f32,
+    pdf_rev: f32,
+    /// Recorded segment that ends here, for camera subpaths being recorded.
+    segment: Option<usize>,
+}
+
+impl Vertex {
+    fn endpoint(kind: VertexKind, point: Vec3, medium: Option<Medium>, beta: Vec3, pdf: f32) -> Vertex {
+        Vertex {
+            kind,
+            point,
+            normal: Vec3::zero(),
+            wo: Vec3::zero(),
+            medium,
+            beta,
+            pdf_fwd: pdf,
+            pdf_rev: 0.,
+            segment: None,
+        }
+    }
+
+    fn is_delta(&self) -> bool {
+        matches!(self.kind, VertexKind::Specular)
+    }
+
+    fn on_surface(&self) -> bool {
+        matches!(self.kind, VertexKind::Diffuse(_) | VertexKind::Specular)
+    }
+
+    /// Cosine factor of the geometry term for a connection leaving along
+    /// `dir`, 1 off surfaces.
+    fn cos(&self, dir: Vec3) -> f32 {
+        if self.on_surface() { self.normal.dot(dir).abs() } else { 1. }
+    }
+
+    /// BSDF or phase function for light going between `wo` and `wi`.
+    fn f(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        match self.kind {
+            VertexKind::Diffuse(albedo) if wo.dot(self.normal) * wi.dot(self.normal) > 0. => albedo / PI,
+            VertexKind::Medium(ref medium) => {
+                let p = medium.phase(-wo, wi);
+                Vec3::new(p, p, p)
+            }
+            _ => Vec3::zero(),
+        }
+    }
+
+    /// Solid angle density of continuing along `wi` after arriving from
+    /// `wo`, which the endpoints ignore.
+    fn pdf_dir(&self, tracer: &RayTracer, wo: Vec3, wi: Vec3) -> f32 {
+        match self.kind {
+            VertexKind::Camera => tracer.ray_pdf(wi),
+            VertexKind::Light => 1. / (4. * PI),
+            VertexKind::Diffuse(_) if wo.dot(self.normal) * wi.dot(self.normal) > 0. => {
+                wi.dot(self.normal).abs() / PI
+            }
+            VertexKind::Medium(ref medium) => medium.phase(-wo, wi),
+            _ => 0.,
+        }
+    }
+
+    /// Density of sampling `next` from this vertex when the path came from
+    /// `prev`.
+    fn pdf(&self, tracer: &RayTracer, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let wo = prev.map_or(Vec3::zero(), |prev| direction(self, prev));
+        area_density(self.pdf_dir(tracer, wo, direction(self, next)), self.point, next)
+    }
+}
+
+/// Unit direction from `a` to `b`.
+fn direction(a: &Vertex, b: &Vertex) -> Vec3 {
+    (b.point - a.point).normalize()
+}
+
+fn is_black(c: Vec3) -> bool {
+    c.x == 0. && c.y == 0. && c.z == 0.
+}
+
+/// Turns a solid angle density at `from` into one per unit area at `to`.
+fn area_density(pdf: f32, from: Vec3, to: &Vertex) -> f32 {
+    let d = to.point - from;
+    let distance_sq = d.square_length();
+    if distance_sq == 0. {
+        return 0.;
+    }
+    pdf * to.cos(d / distance_sq.sqrt()) / distance_sq
+}
+
+/// Bidirectional path tracer. Every camera sample also traces a light path
+/// from one of the point lights and connects the vertices of both with
+/// shadow rays, weighting the ways to build each path by multiple
+/// importance sampling. Light path vertices connected straight to the
+/// camera land elsewhere on the image and become splats.
+///
+/// The background only reaches the image through camera paths that escape,
+/// light paths start at point lights alone.
+pub struct BidirectionalPathTracer {
+    /// Maximum number of scattering vertices between camera and light.
+    pub max_depth: u8,
+}
+
+impl Default for BidirectionalPathTracer {
+    fn default() -> BidirectionalPathTracer {
+        BidirectionalPathTracer::new()
+    }
+}
+
+impl BidirectionalPathTracer {
+    pub fn new() -> BidirectionalPathTracer {
+        BidirectionalPathTracer { max_depth: 10 }
+    }
+
+    /// Extends the subpath in `vertices` along `ray`, which leaves its last
+    /// vertex with density `pdf` and carries `beta`. Returns the background
+    /// radiance picked up if it is a camera subpath that escapes; those also
+    /// get recorded if `path` asks for it.
+    fn walk(
+        &self,
+        tracer: &RayTracer,
+        ray: Ray,
+        beta: Vec3,
+        pdf: f32,
+        vertices: &mut Vec<Vertex>,
+        path: &mut PathState,
+    ) -> Vec3 {
+        let scene = &tracer.scene;
+        let (mut ray, mut beta, mut pdf) = (ray, beta, pdf);
+        let mut medium = vertices[vertices.len() - 1].medium.clone();
+        let camera = matches!(vertices[0].kind, VertexKind::Camera);
+        let mut min_t = if camera { 0. } else { EPSILON };
+        let mut depth = 0;
+        loop {
+            path.rays += 1;
+            let intersection = scene.intersect(ray, min_t);
+            min_t = EPSILON;
+            if camera {
+                path.throughput = beta;
+            }
+
+            let mut transmittance = Vec3::new(1., 1., 1.);
+            if let Some(m) = medium.clone() {
+                let t_max = intersection.as_ref().map_or(f32::INFINITY, |r| r.t);
+                match m.sample(&ray, t_max) {
+                    MediumEvent::Scatter { point, weight } => {
+                        beta = beta * weight;
+                        let prev = vertices.len() - 1;
+                        let mut vertex = Vertex {
+                            kind: VertexKind::Medium(m.clone()),
+                            point,
+                            normal: Vec3::zero(),
+                            wo: -ray.dir,
+                            medium: Some(m.clone()),
+                            beta,
+                            pdf_fwd: 0.,
+                            pdf_rev: 0.,
+                            segment: None,
+                        };
+                        vertex.pdf_fwd = area_density(pdf, vertices[prev].point, &vertex);
+                        depth += 1;
+                        let scattered = if depth < self.max_depth {
+                            Some(m.sample_phase(ray.dir))
+                        } else {
+                            None
+                        };
+                        if camera {
+                            vertex.segment = path.vertices.as_ref().map(|v| v.len());
+                            path.record(depth - 1, ray, transmittance, || PathEvent::Medium {
+                                point,
+                                weight,
+                                direct: Vec3::zero(),
+                                direction: scattered,
+                            });
+                        }
+                        vertices.push(vertex);
+                        let dir = match scattered {
+                            Some(dir) => dir,
+                            None => return Vec3::zero(),
+                        };
+                        // sampling the phase function exactly leaves beta as is
+                        pdf = m.phase(ray.dir, dir);
+                        let rev = m.phase(-dir, -ray.dir);
+                        vertices[prev].pdf_rev = area_density(rev, point, &vertices[prev]);
+                        ray = Ray::new(point, dir);
+                        continue;
+                    }
+                    MediumEvent::Pass { weight } => transmittance = weight,
+                }
+            }
+            beta = beta * transmittance;
+
+            let result = match intersection {
+                Some(result) => result,
+                None => {
+                    if !camera {
+                        return Vec3::zero();
+                    }
+                    let sky = scene.background;
+                    path.record(depth, ray, transmittance, || PathEvent::Escaped { radiance: sky });
+                    return beta * sky;
+                }
+            };
+            let point = ray.point(result.t);
+            let kind = match result.material {
+                Material::Lambertian(albedo) => VertexKind::Diffuse(albedo),
+                Material::Metal(_) | Material::Dielectric(_) => VertexKind::Specular,
+                Material::Volume(ref m) => {
+                    // not a vertex, the path goes on into the other medium
+                    medium = if ray.dir.dot(result.n) < 0. {
+                        Some(m.clone())
+                    } else {
+                        scene.atmosphere.clone()
+                    };
+                    if camera {
+                        path.record(depth, ray, transmittance, || PathEvent::Surface {
+                            t: result.t,
+                            point,
+                            normal: result.n,
+                            uv: result.uv,
+                            object: result.object,
+                            material: result.material.clone(),
+                            direct: Vec3::zero(),
+                            sample: Some(BsdfSample {
+                                direction: ray.dir,
+                                weight: Vec3::new(1., 1., 1.),
+                                pdf: None,
+                            }),
+                        });
+                    }
+                    ray = Ray::new(point, ray.dir);
+                    continue;
+                }
+            };
+            let prev = vertices.len() - 1;
+            let mut vertex = Vertex {
+                kind,
+                point,
+                normal: result.n,
+                wo: -ray.dir,
+                medium: medium.clone(),
+                beta,
+                pdf_fwd: 0.,
+                pdf_rev: 0.,
+                segment: None,
+            };
+            vertex.pdf_fwd = area_density(pdf, vertices[prev].point, &vertex);
+            depth += 1;
+
+            // (direction, weight, density of the direction, density of
+            // scattering back along the incoming ray)
+            let scattered = if depth >= self.max_depth {
+                None
+            } else {
+                match vertex.kind {
+                    VertexKind::Diffuse(albedo) => {
+                        let n = if vertex.wo.dot(result.n) < 0. { -result.n } else { result.n };
+                        let dir = (n + rnd_unit_vector()).normalize();
+                        let pdf = vertex.pdf_dir(tracer, vertex.wo, dir);
+                        let rev = vertex.pdf_dir(tracer, dir, vertex.wo);
+                        if pdf > 0. { Some((dir, albedo, pdf, rev)) } else { None }
+                    }
+                    _ => result.material.scatter(&ray, &result).map(|(r, weight)| (r.dir, weight, 0., 0.)),
+                }
+            };
+            if camera {
+                vertex.segment = path.vertices.as_ref().map(|v| v.len());
+                path.record(depth - 1, ray, transmittance, || PathEvent::Surface {
+                    t: result.t,
+                    point,
+                    normal: result.n,
+                    uv: result.uv,
+                    object: result.object,
+                    material: result.material.clone(),
+                    direct: Vec3::zero(),
+                    sample: scattered.map(|(dir, weight, pdf, _)| BsdfSample {
+                        direction: dir,
+                        weight,
+                        pdf: if pdf > 0. { Some(pdf) } else { None },
+                    }),
+                });
+            }
+            vertices.push(vertex);
+            let (dir, weight, next_pdf, rev) = match scattered {
+                Some(s) => s,
+                None => return Vec3::zero(),
+            };
+            beta = beta * weight;
+            pdf = next_pdf;
+            vertices[prev].pdf_rev = area_density(rev, point, &vertices[prev]);
+            ray = Ray::new(point, dir);
+        }
+    }
+
+    /// Traces a light subpath from a randomly picked point light.
+    fn light_subpath(&self, tracer: &RayTracer, path: &mut PathState) -> Vec<Vertex> {
+        let scene = &tracer.scene;
+        let mut vertices = Vec::new();
+        path.light_paths += 1;
+        if scene.lights.is_empty() {
+            return vertices;
+        }
+        let pick = 1. / scene.lights.len() as f32;
+        let light = scene.lights[rnd_index(scene.lights.len())];
+        let beta = light.intensity / pick;
+        let medium = scene.atmosphere.clone();
+        vertices.push(Vertex::endpoint(VertexKind::Light, light.position, medium, beta, pick));
+        let ray = Ray::new(light.position, rnd_unit_vector());
+        let pdf = 1. / (4. * PI);
+        self.walk(tracer, ray, beta / pdf, pdf, &mut vertices, path);
+        vertices
+    }
+
+    /// Light arriving at camera vertex `t - 1` through a connection to light
+    /// vertex `s - 1`, MIS weighted and not yet multiplied by the camera
+    /// vertex's beta. `s == 1` connects to a freshly sampled light instead.
+    fn connect(
+        &self,
+        tracer: &RayTracer,
+        light: &[Vertex],
+        camera: &[Vertex],
+        s: usize,
+        t: usize,
+        path: &mut PathState,
+    ) -> Vec3 {
+        let scene = &tracer.scene;
+        let pt = &camera[t - 1];
+        if pt.is_delta() {
+            return Vec3::zero();
+        }
+        if s == 1 {
+            if scene.lights.is_empty() {
+                return Vec3::zero();
+            }
+            let pick = 1. / scene.lights.len() as f32;
+            let point_light = scene.lights[rnd_index(scene.lights.len())];
+            let (dir, distance, radiance) = point_light.illuminate(pt.point);
+            let unoccluded = pt.f(pt.wo, dir) * radiance * (pt.cos(dir) / pick);
+            if is_black(unoccluded) {
+                return Vec3::zero();
+            }
+            let visible = visibility(scene, pt.point, dir, distance, pt.medium.as_ref(), path);
+            if is_black(visible) {
+                return Vec3::zero();
+            }
+            let sampled = Vertex::endpoint(
+                VertexKind::Light,
+                point_light.position,
+                scene.atmosphere.clone(),
+                point_light.intensity / pick,
+                pick,
+            );
+            let weight = self.mis_weight(tracer, single(&sampled), camera, s, t);
+            return unoccluded * visible * weight;
+        }
+
+        let qs = &light[s - 1];
+        if qs.is_delta() {
+            return Vec3::zero();
+        }
+        let d = qs.point - pt.point;
+        let distance = d.length();
+        let dir = d / distance;
+        let g = pt.cos(dir) * qs.cos(dir) / (distance * distance);
+        let unoccluded = pt.f(pt.wo, dir) * qs.f(qs.wo, -dir) * qs.beta * g;
+        if is_black(unoccluded) {
+            return Vec3::zero();
+        }
+        let visible = visibility(scene, pt.point, dir, distance, pt.medium.as_ref(), path);
+        if is_black(visible) {
+            return Vec3::zero();
+        }
+        unoccluded * visible * self.mis_weight(tracer, &light[..s], camera, s, t)
+    }
+
+    /// Connects light vertex `s - 1` straight to the camera.
+    fn splat(&self, tracer: &RayTracer, light: &[Vertex], s: usize, path: &mut PathState) -> Option<Splat> {
+        let qs = &light[s - 1];
+        if qs.is_delta() {
+            return None;
+        }
+        let connection = tracer.connect_camera(qs.point)?;
+        let d = connection.origin - qs.point;
+        let distance = d.length();
+        let dir = d / distance;
+        let unoccluded = qs.beta * qs.f(qs.wo, dir) * (qs.cos(dir) * connection.weight);
+        if is_black(unoccluded) {
+            return None;
+        }
+        let visible = visibility(&tracer.scene, qs.point, dir, distance, qs.medium.as_ref(), path);
+        if is_black(visible) {
+            return None;
+        }
+        let sampled = Vertex::endpoint(
+            VertexKind::Camera,
+            connection.origin,
+            tracer.scene.atmosphere.clone(),
+            Vec3::new(1., 1., 1.),
+            1.,
+        );
+        Some(Splat {
+            x: connection.x,
+            y: connection.y,
+            radiance: unoccluded * visible * self.mis_weight(tracer, &light[..s], single(&sampled), s, 1),
+        })
+    }
+
+    /// Balance heuristic weight of building the path from `s` light and `t`
+    /// camera vertices, relative to every other split of the same path that
+    /// could have been sampled. `light` and `camera` hold exactly the
+    /// vertices used, with a freshly sampled endpoint in place of the
+    /// subpath's own for `s == 1` or `t == 1`.
+    fn mis_weight(&self, tracer: &RayTracer, light: &[Vertex], camera: &[Vertex], s: usize, t: usize) -> f32 {
+        if s + t == 2 {
+            return 1.;
+        }
+        let (light, camera) = (&light[..s], &camera[..t]);
+        // (forward density, reverse density, delta) per vertex; the
+        // connection changes the reverse densities around it
+        let densities = |v: &Vertex| (v.pdf_fwd, v.pdf_rev, v.is_delta());
+        let mut light_pdfs: Vec<(f32, f32, bool)> = light.iter().map(&densities).collect();
+        let mut camera_pdfs: Vec<(f32, f32, bool)> = camera.iter().map(&densities).collect();
+        let (pt, qs) = (&camera[t - 1], &light[s - 1]);
+        let pt_minus = if t > 1 { Some(&camera[t - 2]) } else { None };
+        let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+        camera_pdfs[t - 1].1 = qs.pdf(tracer, qs_minus, pt);
+        if let Some(pt_minus) = pt_minus {
+            camera_pdfs[t - 2].1 = pt.pdf(tracer, Some(qs), pt_minus);
+        }
+        light_pdfs[s - 1].1 = pt.pdf(tracer, pt_minus, qs);
+        if let Some(qs_minus) = qs_minus {
+            light_pdfs[s - 2].1 = qs.pdf(tracer, Some(pt), qs_minus);
+        }
+
+        // delta densities count as 1 in the ratios, those strategies are
+        // skipped anyway
+        let remap = |pdf: f32| if pdf != 0. { pdf } else { 1. };
+        let mut sum = 0.;
+        let mut ratio = 1.;
+        // the camera can't be hit, so no strategy ends at camera vertex 0
+        for i in (1..t).rev() {
+            ratio *= remap(camera_pdfs[i].1) / remap(camera_pdfs[i].0);
+            if !camera_pdfs[i].2 && !camera_pdfs[i - 1].2 {
+                sum += ratio;
+            }
+        }
+        ratio = 1.;
+        for i in (0..s).rev() {
+            ratio *= remap(light_pdfs[i].1) / remap(light_pdfs[i].0);
+            // nor can point lights, which rules out i == 0
+            if i > 0 && !light_pdfs[i].2 && !light_pdfs[i - 1].2 {
+                sum += ratio;
+            }
+        }
+        1. / (1. + sum)
+    }
+}
+
+/// A sampled endpoint standing in for a whole subpath.
+fn single(vertex: &Vertex) -> &[Vertex] {
+    ::std::slice::from_ref(vertex)
+}
+
+impl Integrator for BidirectionalPathTracer {
+    fn name(&self) -> &'static str {
+        "bdpt"
+    }
+
+    fn radiance(&self, tracer: &RayTracer, ray: Ray, path: &mut PathState) -> Vec3 {
+        let scene = &tracer.scene;
+        let origin = Vertex::endpoint(
+            VertexKind::Camera,
+            ray.origin,
+            scene.atmosphere.clone(),
+            Vec3::new(1., 1., 1.),
+            1.,
+        );
+        let mut camera = vec![origin];
+        let pdf = tracer.ray_pdf(ray.dir);
+        let escaped = self.walk(tracer, ray, Vec3::new(1., 1., 1.), pdf, &mut camera, path);
+        let light = self.light_subpath(tracer, path);
+
+        let max_depth = self.max_depth as usize;
+        let mut radiance = escaped;
+        for t in 2..camera.len() + 1 {
+            let mut direct = Vec3::zero();
+            for s in 1..(light.len() + 1).min(max_depth + 3 - t) {
+                direct = direct + self.connect(tracer, &light, &camera, s, t, path);
+            }
+            radiance = radiance + camera[t - 1].beta * direct;
+            if let (Some(segment), Some(ref mut vertices)) = (camera[t - 1].segment, path.vertices.as_mut()) {
+                match vertices[segment].event {
+                    PathEvent::Medium { direct: ref mut d, .. } |
+                    PathEvent::Surface { direct: ref mut d, .. } => *d = direct,
+                    PathEvent::Escaped { .. } => (),
+                }
+            }
+        }
+        for s in 2..(light.len() + 1).min(max_depth + 2) {
+            if let Some(splat) = self.splat(tracer, &light, s, path) {
+                path.splats.push(splat);
+            }
+        }
+        radiance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use core::scene::*;
+    use core::light::*;
+    use core::primitives::*;
+    use core::film::*;
+    use core::render::*;
+
+    // closed room of diffuse walls with a point light under the ceiling
+    fn room(integrator: Arc<dyn Integrator>) -> Scene {
+        let mut scene = Scene::new();
+        let walls = [
+            (Vec3::new(-2., 0., 0.), Vec3::new(1., 0., 0.)),
+            (Vec3::new(2., 0., 0.), Vec3::new(-1., 0., 0.)),
+            (Vec3::new(0., -2., 0.), Vec3::new(0., 1., 0.)),
+            (Vec3::new(0., 2., 0.), Vec3::new(0., -1., 0.)),
+            (Vec3::new(0., 0., -1.), Vec3::new(0., 0., 1.)),
+            (Vec3::new(0., 0., 4.), Vec3::new(0., 0., -1.)),
+        ];
+        for (i, &(origin, normal)) in walls.iter().enumerate() {
+            let albedo = if i == 0 { Vec3::new(0.7, 0.2, 0.2) } else { Vec3::new(0.5, 0.5, 0.5) };
+            scene.add(Box::new(Plane {
+                origin,
+                normal,
+                size: None,
+                material: Material::Lambertian(albedo),
+            }));
+        }
+        scene.lights.push(PointLight::new(Vec3::new(0.5, 1.5, 2.), Vec3::new(5., 5., 5.)));
+        scene.integrator = integrator;
+        scene
+    }
+
+    fn mean_luminance(integrator: Arc<dyn Integrator>) -> f32 {
+        let film = render_scene(room(integrator), (16, 12), 64, 4);
+        let pixels = film.pixels();
+        pixels.iter().map(|&c| luminance(c)).sum::<f32>() / pixels.len() as f32
+    }
+
+    #[test]
+    fn bdpt_convergence_test() {
+        let path = mean_luminance(Arc::new(PathTracer::new()));
+        let bdpt = mean_luminance(Arc::new(BidirectionalPathTracer::new()));
+        assert!(path > 0.);
+        assert!((bdpt / path - 1.).abs() < 0.03, "bdpt {} path {}", bdpt, path);
+    }
+
+    // a subpath through `points` on diffuse surfaces, with the densities
+    // `walk` would have recorded for it
+    fn subpath(tracer: &RayTracer, first: Vertex, first_pdf: f32, points: &[(Vec3, Vec3)]) -> Vec<Vertex> {
+        let mut vertices = vec![first];
+        let mut pdf = first_pdf;
+        for (i, &(point, normal)) in points.iter().enumerate() {
+            let prev = vertices.len() - 1;
+            let mut vertex = Vertex::endpoint(
+                VertexKind::Diffuse(Vec3::new(0.5, 0.5, 0.5)),
+                point,
+                None,
+                Vec3::new(1., 1., 1.),
+                0.,
+            );
+            vertex.normal = normal.normalize();
+            vertex.wo = direction(&vertex, &vertices[prev]);
+            vertex.pdf_fwd = area_density(pdf, vertices[prev].point, &vertex);
+            if let Some(&(next, _)) = points.get(i + 1) {
+                let dir = (next - point).normalize();
+                pdf = vertex.pdf_dir(tracer, vertex.wo, dir);
+                let rev = vertex.pdf_dir(tracer, dir, vertex.wo);
+                vertices[prev].pdf_rev = area_density(rev, point, &vertices[prev]);
+            }
+            vertices.push(vertex);
+        }
+        vertices
+    }
+
+    #[test]
+    fn mis_weight_test() {
+        // camera, two diffuse vertices and the light, built in each of the
+        // three ways that can be sampled
+        let mut scene = Scene::new();
+        let light_position = Vec3::new(2., 3., 1.);
+        scene.lights.push(PointLight::new(light_position, Vec3::new(1., 1., 1.)));
+        let tracer = RayTracer::new((16, 16), scene);
+        let x1 = (Vec3::new(0., 0., 3.), Vec3::new(0., 0., -1.));
+        let x2 = (Vec3::new(1., 1., 2.), Vec3::new(0., 1., 1.5));
+        let camera_endpoint = || Vertex::endpoint(VertexKind::Camera, Vec3::zero(), None, Vec3::new(1., 1., 1.), 1.);
+        let light_endpoint = || Vertex::endpoint(VertexKind::Light, light_position, None, Vec3::new(1., 1., 1.), 1.);
+        let camera_pdf = tracer.ray_pdf(x1.0.normalize());
+        let light_pdf = 1. / (4. * PI);
+
+        let bdpt = BidirectionalPathTracer::new();
+        let camera = subpath(&tracer, camera_endpoint(), camera_pdf, &[x1, x2]);
+        let light = subpath(&tracer, light_endpoint(), light_pdf, &[x2, x1]);
+        let weights = [
+            bdpt.mis_weight(&tracer, single(&light_endpoint()), &camera, 1, 3),
+            bdpt.mis_weight(&tracer, &light[..2], &camera[..2], 2, 2),
+            bdpt.mis_weight(&tracer, &light, single(&camera_endpoint()), 3, 1),
+        ];
+        assert!(weights.iter().all(|&w| w > 0.01));
+        let sum: f32 = weights.iter().sum();
+        assert!((sum - 1.).abs() < 1e-4, "{:?}", weights);
+    }
+}
@@ -0,0 +1,193 @@
+use std::cell::Cell;
+use std::f32;
+
+use math::*;
+use core::raytracer::*;
+
+const MAX_LEAF_SIZE: usize = 4;
+const SAH_BINS: usize = 12;
+
+// only counted inside `count_nodes_visited`
+thread_local!(static NODES_VISITED: Cell<Option<u64>> = const { Cell::new(None) });
+
+/// Runs `f` and counts the nodes the BVH traversals in it visit on this
+/// thread, nested mesh BVHs included. For tracing a single ray that is its
+/// traversal cost.
+pub fn count_nodes_visited<R, F: FnOnce() -> R>(f: F) -> (R, u64) {
+    let outer = NODES_VISITED.with(|n| n.replace(Some(0)));
+    let result = f();
+    let count = NODES_VISITED.with(|n| n.replace(outer)).unwrap_or(0);
+    (result, count)
+}
+
+struct BvhNode {
+    bounds: Aabb,
+    // leaf: first entry in `indices`; inner node: index of the right child,
+    // the left child always directly follows its parent
+    offset: usize,
+    // number of primitives, zero for inner nodes
+    count: usize,
+    axis: usize,
+}
+
+/// Bounding volume hierarchy over anything that has bounds. It only stores
+/// primitive indices; the owner keeps the primitives and intersects them in
+/// the callback passed to `intersect`. The same structure serves as the top
+/// level over scene objects and as the bottom level over mesh triangles.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+impl Bvh {
+    /// Builds the hierarchy with a binned surface area heuristic.
+    pub fn build(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            indices: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            let centroids: Vec<Vec3> = bounds.iter().map(|b| b.centroid()).collect();
+            bvh.build_node(bounds, &centroids, 0, bounds.len());
+        }
+        bvh
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |n| n.bounds)
+    }
+
+    fn build_node(&mut self, bounds: &[Aabb], centroids: &[Vec3], start: usize, end: usize) -> usize {
+        let node_index = self.nodes.len();
+        let count = end - start;
+
+        let mut node_bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for &i in &self.indices[start..end] {
+            node_bounds = node_bounds.union(bounds[i]);
+            centroid_bounds = centroid_bounds.grow(centroids[i]);
+        }
+        self.nodes.push(BvhNode {
+            bounds: node_bounds,
+            offset: start,
+            count,
+            axis: 0,
+        });
+
+        if count <= MAX_LEAF_SIZE {
+            return node_index;
+        }
+
+        let axis = centroid_bounds.largest_axis();
+        let lo = centroid_bounds.min.axis(axis);
+        let extent = centroid_bounds.max.axis(axis) - lo;
+
+        let bin_of = |c: Vec3| if extent > 0. {
+            (((c.axis(axis) - lo) / extent * SAH_BINS as f32) as usize).min(SAH_BINS - 1)
+        } else {
+            0
+        };
+
+        let mut bin_bounds = [Aabb::empty(); SAH_BINS];
+        let mut bin_counts = [0usize; SAH_BINS];
+        for &i in &self.indices[start..end] {
+            let b = bin_of(centroids[i]);
+            bin_counts[b] += 1;
+            bin_bounds[b] = bin_bounds[b].union(bounds[i]);
+        }
+
+        let mut best_cost = f32::INFINITY;
+        let mut best_split = 0;
+        for split in 1..SAH_BINS {
+            let (mut left, mut left_count) = (Aabb::empty(), 0);
+            let (mut right, mut right_count) = (Aabb::empty(), 0);
+            for b in 0..split {
+                left = left.union(bin_bounds[b]);
+                left_count += bin_counts[b];
+            }
+            for b in split..SAH_BINS {
+                right = right.union(bin_bounds[b]);
+                right_count += bin_counts[b];
+            }
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+            let cost = left.surface_area() * left_count as f32 +
+                right.surface_area() * right_count as f32;
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        let mid = if best_split > 0 {
+            let mut mid = start;
+            for i in start..end {
+                if bin_of(centroids[self.indices[i]]) < best_split {
+                    self.indices.swap(i, mid);
+                    mid += 1;
+                }
+            }
+            mid
+        } else {
+            // coincident centroids (e.g. stacked instances), split by count
+            start + count / 2
+        };
+
+        self.build_node(bounds, centroids, start, mid);
+        let right = self.build_node(bounds, centroids, mid, end);
+
+        let node = &mut self.nodes[node_index];
+        node.offset = right;
+        node.count = 0;
+        node.axis = axis;
+        node_index
+    }
+
+    /// Finds the closest hit in `(t_min, t_max)`. `hit` is called with a
+    /// primitive index and the current search range, and should return that
+    /// primitive's closest hit inside the range.
+    pub fn intersect<F>(&self, ray: &Ray, t_min: f32, t_max: f32, mut hit: F) -> Option<IntersectionResult>
+    where
+        F: FnMut(usize, f32, f32) -> Option<IntersectionResult>,
+    {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let inv_dir = ray.inv_dir();
+        let mut closest = t_max;
+        let mut best = None;
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+        let mut visited = 0;
+
+        while let Some(index) = stack.pop() {
+            visited += 1;
+            let node = &self.nodes[index];
+            if node.bounds.hit(ray, inv_dir, t_min, closest).is_none() {
+                continue;
+            }
+            if node.count > 0 {
+                for &i in &self.indices[node.offset..node.offset + node.count] {
+                    if let Some(result) = hit(i, t_min, closest) {
+                        closest = result.t;
+                        best = Some(result);
+                    }
+                }
+            } else if ray.dir.axis(node.axis) < 0. {
+                // push the far child first so the near one is visited first
+                stack.push(index + 1);
+                stack.push(node.offset);
+            } else {
+                stack.push(node.offset);
+                stack.push(index + 1);
+            }
+        }
+
+        NODES_VISITED.with(|n| if let Some(count) = n.get() {
+            n.set(Some(count + visited))
+        });
+        best
+    }
+}
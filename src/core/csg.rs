@@ -0,0 +1,208 @@
+use math::*;
+use core::raytracer::*;
+use core::scene::*;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    /// `left` with `right` carved out of it.
+    Difference,
+}
+
+impl CsgOp {
+    fn inside(&self, in_left: bool, in_right: bool) -> bool {
+        match *self {
+            CsgOp::Union => in_left || in_right,
+            CsgOp::Intersection => in_left && in_right,
+            CsgOp::Difference => in_left && !in_right,
+        }
+    }
+}
+
+/// Boolean combination of two solids. Both operands must report their
+/// `intervals`, so they have to be closed objects (or other `Csg` nodes).
+pub struct Csg {
+    pub op: CsgOp,
+    pub left: Box<dyn SceneObject>,
+    pub right: Box<dyn SceneObject>,
+}
+
+impl Csg {
+    pub fn new(op: CsgOp, left: Box<dyn SceneObject>, right: Box<dyn SceneObject>) -> Csg {
+        Csg {
+            op,
+            left,
+            right,
+        }
+    }
+
+    pub fn union(left: Box<dyn SceneObject>, right: Box<dyn SceneObject>) -> Csg {
+        Csg::new(CsgOp::Union, left, right)
+    }
+
+    pub fn intersection(left: Box<dyn SceneObject>, right: Box<dyn SceneObject>) -> Csg {
+        Csg::new(CsgOp::Intersection, left, right)
+    }
+
+    pub fn difference(left: Box<dyn SceneObject>, right: Box<dyn SceneObject>) -> Csg {
+        Csg::new(CsgOp::Difference, left, right)
+    }
+}
+
+impl SceneObject for Csg {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        for interval in self.intervals(ray) {
+            if interval.enter.t >= t_max {
+                break;
+            }
+            if interval.enter.t > t_min {
+                return Some(interval.enter);
+            }
+            if interval.exit.t > t_min && interval.exit.t < t_max {
+                return Some(interval.exit);
+            }
+        }
+        None
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        match self.op {
+            CsgOp::Union => match (self.left.bounds(), self.right.bounds()) {
+                (Some(a), Some(b)) => Some(a.union(b)),
+                _ => None,
+            },
+            CsgOp::Intersection => self.left.bounds().or_else(|| self.right.bounds()),
+            CsgOp::Difference => self.left.bounds(),
+        }
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        // walk the boundaries of both operands in order and record where the
+        // combined inside/outside state flips
+        let mut events: Vec<(IntersectionResult, bool)> = Vec::new();
+        for interval in self.left.intervals(ray) {
+            events.push((interval.enter, true));
+            events.push((interval.exit, true));
+        }
+        for interval in self.right.intervals(ray) {
+            events.push((interval.enter, false));
+            events.push((interval.exit, false));
+        }
+        events.sort_by(|a, b| a.0.t.partial_cmp(&b.0.t).unwrap());
+
+        let mut result = Vec::new();
+        let (mut in_left, mut in_right) = (false, false);
+        let mut inside = false;
+        let mut enter: Option<IntersectionResult> = None;
+        for (mut surface, is_left) in events {
+            if is_left {
+                in_left = !in_left;
+            } else {
+                in_right = !in_right;
+            }
+            let now_inside = self.op.inside(in_left, in_right);
+            if now_inside == inside {
+                continue;
+            }
+            inside = now_inside;
+            // the solid side of a subtracted surface is outside of the result
+            if !is_left && self.op == CsgOp::Difference {
+                surface.n = -surface.n;
+            }
+            if inside {
+                enter = Some(surface);
+            } else if let Some(enter) = enter.take() {
+                result.push(Interval {
+                    enter,
+                    exit: surface,
+                });
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::primitives::*;
+    use core::mesh::*;
+    use core::sdf::*;
+
+    fn grey() -> Material {
+        Material::Lambertian(Vec3::new(0.5, 0.5, 0.5))
+    }
+
+    fn sphere(x: f32, radius: f32) -> Box<dyn SceneObject> {
+        Box::new(Sphere {
+            origin: Vec3::new(x, 0., 0.),
+            radius,
+            material: grey(),
+        })
+    }
+
+    fn axis_box(min: f32, max: f32) -> Box<dyn SceneObject> {
+        Box::new(AxisBox {
+            min: Vec3::new(min, -1., -1.),
+            max: Vec3::new(max, 1., 1.),
+            material: grey(),
+        })
+    }
+
+    // spans along the x axis, from a ray starting at x = -5
+    fn spans(object: &dyn SceneObject, offset: f32) -> Vec<(f32, f32)> {
+        let ray = Ray::new(Vec3::new(-5., offset, offset), Vec3::new(1., 0., 0.));
+        object.intervals(&ray).iter().map(|i| (i.enter.t - 5., i.exit.t - 5.)).collect()
+    }
+
+    fn assert_spans(actual: Vec<(f32, f32)>, expected: &[(f32, f32)]) {
+        assert!(actual.len() == expected.len(), "{:?} != {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a.0 - e.0).abs() < 1e-3 && (a.1 - e.1).abs() < 1e-3, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn csg_test() {
+        let union = Csg::union(sphere(-0.5, 1.), sphere(0.5, 1.));
+        assert_spans(spans(&union, 0.), &[(-1.5, 1.5)]);
+        let intersection = Csg::intersection(sphere(-0.5, 1.), sphere(0.5, 1.));
+        assert_spans(spans(&intersection, 0.), &[(-0.5, 0.5)]);
+        let difference = Csg::difference(sphere(-0.5, 1.), sphere(0.5, 1.));
+        assert_spans(spans(&difference, 0.), &[(-1.5, -0.5)]);
+        // the carved out surface faces away from what is left
+        let hit = difference.intersect(&Ray::new(Vec3::new(5., 0., 0.), Vec3::new(-1., 0., 0.)), 0., 100.);
+        assert!(hit.unwrap().n.x > 0.99);
+
+        assert_spans(spans(&Csg::union(axis_box(-2., 0.), axis_box(1., 2.)), 0.), &[(-2., 0.), (1., 2.)]);
+        assert_spans(spans(&Csg::intersection(axis_box(-2., 1.), sphere(0., 1.5)), 0.), &[(-1.5, 1.)]);
+        assert_spans(spans(&Csg::difference(axis_box(-1., 1.), sphere(0., 0.5)), 0.), &[(-1., -0.5), (0.5, 1.)]);
+    }
+
+    #[test]
+    fn csg_mesh_sdf_test() {
+        // a cube whose x faces are split along y = z, right where the ray
+        // crosses them
+        let mut vertices = Vec::new();
+        for i in 0..8 {
+            let coord = |bit: usize| if i & bit != 0 { 1. } else { -1. };
+            vertices.push(Vec3::new(coord(1), coord(2), coord(4)));
+        }
+        let triangles = vec![
+            [0, 4, 6], [0, 6, 2], [1, 3, 7], [1, 7, 5], [0, 1, 5], [0, 5, 4],
+            [2, 6, 7], [2, 7, 3], [0, 2, 3], [0, 3, 1], [4, 5, 7], [4, 7, 6],
+        ];
+        let cube = Box::new(Mesh::new(vertices, triangles, grey()));
+        let hollow = Csg::difference(cube, sphere(0., 0.5));
+        let r = (0.25f32 - 0.08).sqrt();
+        assert_spans(spans(&hollow, 0.2), &[(-1., -r), (r, 1.)]);
+
+        let ball = Box::new(SdfObject::new(
+            Sdf::Sphere { radius: 1. },
+            Aabb::new(Vec3::new(-1.1, -1.1, -1.1), Vec3::new(1.1, 1.1, 1.1)),
+            grey(),
+        ));
+        assert_spans(spans(&Csg::intersection(ball, axis_box(0., 2.)), 0.), &[(0., 1.)]);
+    }
+}
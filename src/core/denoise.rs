@@ -0,0 +1,187 @@
+use math::*;
+use core::film::*;
+use core::aov::*;
+
+/// Edge-avoiding à-trous wavelet filter (Dammertz et al. 2010). Each
+/// iteration blurs with a 5x5 B3 spline kernel whose taps are spread twice
+/// as far apart as in the previous one, and every tap is weighted down when
+/// its color, normal or albedo differs from the center pixel. Texture detail
+/// is preserved by filtering illumination only: the color is divided by the
+/// albedo before filtering and multiplied back afterwards.
+#[derive(Clone, Copy, Debug)]
+pub struct Denoiser {
+    pub iterations: u32,
+    /// Color tolerance of the first iteration, halved in each following one.
+    pub sigma_color: f32,
+    pub sigma_normal: f32,
+    pub sigma_albedo: f32,
+}
+
+const KERNEL: [f32; 5] = [1. / 16., 1. / 4., 3. / 8., 1. / 4., 1. / 16.];
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser::new()
+    }
+}
+
+impl Denoiser {
+    pub fn new() -> Denoiser {
+        Denoiser {
+            iterations: 5,
+            sigma_color: 1.,
+            sigma_normal: 0.3,
+            sigma_albedo: 0.1,
+        }
+    }
+
+    /// Denoises the mean radiance of `film`, using the albedo and normal
+    /// buffers of `aovs` as guides.
+    pub fn denoise(&self, film: &Film, aovs: &AovBuffers) -> Film {
+        let pixels = self.apply(
+            film.width,
+            film.height,
+            &film.pixels(),
+            &aovs.pixels(Aov::Albedo),
+            &aovs.pixels(Aov::Normal),
+        );
+        Film::from_pixels((film.width, film.height), pixels)
+    }
+
+    /// Filters `color` with `albedo` and `normal` as guides, all rows from
+    /// the top. Pixels without a surface have zero albedo and normal.
+    pub fn apply(&self, width: u32, height: u32, color: &[Vec3], albedo: &[Vec3], normal: &[Vec3]) -> Vec<Vec3> {
+        let (w, h) = (width as i32, height as i32);
+        // the sky has no albedo to divide by
+        let albedo: Vec<Vec3> = albedo
+            .iter()
+            .map(|&a| if a.square_length() > 0. {
+                a.max(Vec3::new(1e-3, 1e-3, 1e-3))
+            } else {
+                Vec3::new(1., 1., 1.)
+            })
+            .collect();
+        let mut current: Vec<Vec3> = color.iter().zip(&albedo).map(|(&c, &a)| c / a).collect();
+        let mut next = vec![Vec3::zero(); current.len()];
+
+        // compare colors after a soft compression so fireflies don't make
+        // every neighbour look like an edge
+        let compress = |c: Vec3| c / (c + Vec3::new(1., 1., 1.));
+
+        let inv_normal = 1. / (self.sigma_normal * self.sigma_normal);
+        let inv_albedo = 1. / (self.sigma_albedo * self.sigma_albedo);
+        let mut sigma_color = self.sigma_color;
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let inv_color = 1. / (sigma_color * sigma_color);
+            let compressed: Vec<Vec3> = current.iter().map(|&c| compress(c)).collect();
+            for y in 0..h {
+                for x in 0..w {
+                    let i = (y * w + x) as usize;
+                    let c = compressed[i];
+                    let mut sum = Vec3::zero();
+                    let mut weight_sum = 0.;
+                    for (ky, &hy) in KERNEL.iter().enumerate() {
+                        let sy = y + (ky as i32 - 2) * step;
+                        if sy < 0 || sy >= h {
+                            continue;
+                        }
+                        for (kx, &hx) in KERNEL.iter().enumerate() {
+                            let sx = x + (kx as i32 - 2) * step;
+                            if sx < 0 || sx >= w {
+                                continue;
+                            }
+                            let j = (sy * w + sx) as usize;
+                            let dc = (compressed[j] - c).square_length();
+                            let dn = (normal[j] - normal[i]).square_length();
+                            let da = (albedo[j] - albedo[i]).square_length();
+                            let weight =
+                                hx * hy * (-dc * inv_color - dn * inv_normal - da * inv_albedo).exp();
+                            sum = sum + current[j] * weight;
+                            weight_sum += weight;
+                        }
+                    }
+                    // the center tap always has weight, so this never divides by 0
+                    next[i] = sum / weight_sum;
+                }
+            }
+            ::std::mem::swap(&mut current, &mut next);
+            sigma_color *= 0.5;
+        }
+
+        current.iter().zip(&albedo).map(|(&c, &a)| c * a).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 24;
+
+    fn grey(v: f32) -> Vec3 {
+        Vec3::new(v, v, v)
+    }
+
+    #[test]
+    fn constant_test() {
+        let count = (SIZE * SIZE) as usize;
+        let color = vec![Vec3::new(0.3, 0.5, 0.7); count];
+        let albedo = vec![grey(0.5); count];
+        let normal = vec![Vec3::new(0., 0., 1.); count];
+        let result = Denoiser::new().apply(SIZE, SIZE, &color, &albedo, &normal);
+        assert!(result.iter().all(|&c| (c - color[0]).length() < 1e-5));
+
+        // evenly lit texture survives, only the illumination is filtered
+        let albedo: Vec<Vec3> = (0..count)
+            .map(|i| if (i % 2 == 0) ^ (i as u32 / SIZE).is_multiple_of(2) { grey(0.9) } else { grey(0.1) })
+            .collect();
+        let color: Vec<Vec3> = albedo.iter().map(|&a| a * 2.).collect();
+        let result = Denoiser::new().apply(SIZE, SIZE, &color, &albedo, &normal);
+        for (c, expected) in result.iter().zip(&color) {
+            assert!((*c - *expected).length() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn edge_test() {
+        // two noisy walls meeting in the middle at a right angle, one dim
+        // and one bright, with the same albedo. The noise is a fixed hash of
+        // the pixel so the result doesn't vary between runs.
+        let count = (SIZE * SIZE) as usize;
+        let noise = |i: usize| ((i as f32 * 12.9898).sin() * 43758.547).fract().abs();
+        let left = |i: usize| (i as u32 % SIZE) < SIZE / 2;
+        let color: Vec<Vec3> = (0..count)
+            .map(|i| grey(if left(i) { 0.2 } else { 0.8 } * (0.5 + noise(i))))
+            .collect();
+        let albedo = vec![grey(0.5); count];
+        let normal: Vec<Vec3> = (0..count)
+            .map(|i| if left(i) { Vec3::new(0., 0., 1.) } else { Vec3::new(1., 0., 0.) })
+            .collect();
+        let flat = vec![Vec3::new(0., 0., 1.); count];
+
+        let error = |result: &[Vec3], columns: &[u32]| {
+            let mut sum = 0.;
+            let mut n = 0;
+            for y in 0..SIZE {
+                for &x in columns {
+                    let i = (y * SIZE + x) as usize;
+                    sum += (result[i].x - if left(i) { 0.2 } else { 0.8 }).abs();
+                    n += 1;
+                }
+            }
+            sum / n as f32
+        };
+        let edge = [SIZE / 2 - 1, SIZE / 2];
+        let inside: Vec<u32> = (0..SIZE).filter(|x| !edge.contains(x)).collect();
+
+        let denoiser = Denoiser::new();
+        let result = denoiser.apply(SIZE, SIZE, &color, &albedo, &normal);
+        // noise goes down on both sides, and the edge stays sharp
+        assert!(error(&result, &inside) < 0.3 * error(&color, &inside), "{} from {}", error(&result, &inside), error(&color, &inside));
+        assert!(error(&result, &edge) < 0.03, "edge off by {}", error(&result, &edge));
+        // without the normals to guide it, the edge smears
+        let smeared = denoiser.apply(SIZE, SIZE, &color, &albedo, &flat);
+        assert!(error(&smeared, &edge) > 2. * error(&result, &edge));
+    }
+}
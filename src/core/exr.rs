@@ -0,0 +1,230 @@
+//! Minimal OpenEXR writer: single part, scanline, uncompressed, with any
+//! number of named channels of half or float data.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExrPixelType {
+    Half,
+    Float,
+}
+
+impl ExrPixelType {
+    fn code(&self) -> i32 {
+        match *self {
+            ExrPixelType::Half => 1,
+            ExrPixelType::Float => 2,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            ExrPixelType::Half => 2,
+            ExrPixelType::Float => 4,
+        }
+    }
+}
+
+/// One image plane, `data` holds a value per pixel in rows from the top.
+/// Dotted names like `normal.X` group channels into layers.
+pub struct ExrChannel<'a> {
+    pub name: String,
+    pub data: &'a [f32],
+    pub pixel_type: ExrPixelType,
+}
+
+impl<'a> ExrChannel<'a> {
+    pub fn new(name: &str, data: &'a [f32], pixel_type: ExrPixelType) -> ExrChannel<'a> {
+        ExrChannel {
+            name: name.to_string(),
+            data,
+            pixel_type,
+        }
+    }
+}
+
+/// Rounds to the nearest IEEE half, overflowing to infinity.
+pub fn f32_to_f16(value: f32) -> u16 {
+    let x = value.to_bits();
+    let sign = ((x >> 16) & 0x8000) as u16;
+    let exponent = ((x >> 23) & 0xff) as i32;
+    let mantissa = x & 0x7f_ffff;
+
+    if exponent == 0xff {
+        // infinity stays infinity, NaN stays NaN
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let e = exponent - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        // subnormal half, shift in the implicit bit
+        let m = mantissa | 0x80_0000;
+        let shift = (14 - e) as u32;
+        let mut h = m >> shift;
+        let rest = m & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        if rest > halfway || (rest == halfway && h & 1 == 1) {
+            h += 1;
+        }
+        return sign | h as u16;
+    }
+    let mut h = ((e as u32) << 10) | (mantissa >> 13);
+    let rest = mantissa & 0x1fff;
+    // a carry out of the mantissa correctly bumps the exponent
+    if rest > 0x1000 || (rest == 0x1000 && h & 1 == 1) {
+        h += 1;
+    }
+    sign | h as u16
+}
+
+fn put_i32(out: &mut Vec<u8>, v: i32) {
+    let v = v as u32;
+    out.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+}
+
+fn put_u64(out: &mut Vec<u8>, v: u64) {
+    put_i32(out, v as u32 as i32);
+    put_i32(out, (v >> 32) as u32 as i32);
+}
+
+fn put_f32(out: &mut Vec<u8>, v: f32) {
+    put_i32(out, v.to_bits() as i32);
+}
+
+fn put_attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(kind.as_bytes());
+    out.push(0);
+    put_i32(out, value.len() as i32);
+    out.extend_from_slice(value);
+}
+
+pub fn write_exr<W: Write>(
+    w: &mut W,
+    width: u32,
+    height: u32,
+    channels: &[ExrChannel],
+) -> io::Result<()> {
+    let pixels = (width * height) as usize;
+    for c in channels {
+        if c.data.len() != pixels {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("channel {} has {} values for {} pixels", c.name, c.data.len(), pixels),
+            ));
+        }
+        if c.name.is_empty() || c.name.len() > 31 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("channel name '{}' must have 1 to 31 characters", c.name),
+            ));
+        }
+    }
+    // the format requires channels in alphabetical order, in the header and in the data
+    let mut sorted: Vec<&ExrChannel> = channels.iter().collect();
+    sorted.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut header = Vec::new();
+    // magic number and version 2, single part scanline
+    header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+    let mut chlist = Vec::new();
+    for c in &sorted {
+        chlist.extend_from_slice(c.name.as_bytes());
+        chlist.push(0);
+        put_i32(&mut chlist, c.pixel_type.code());
+        // pLinear and three reserved bytes
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        put_i32(&mut chlist, 1);
+        put_i32(&mut chlist, 1);
+    }
+    chlist.push(0);
+    put_attribute(&mut header, "channels", "chlist", &chlist);
+    put_attribute(&mut header, "compression", "compression", &[0]);
+
+    let mut window = Vec::new();
+    put_i32(&mut window, 0);
+    put_i32(&mut window, 0);
+    put_i32(&mut window, width as i32 - 1);
+    put_i32(&mut window, height as i32 - 1);
+    put_attribute(&mut header, "dataWindow", "box2i", &window);
+    put_attribute(&mut header, "displayWindow", "box2i", &window);
+    put_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+
+    let mut value = Vec::new();
+    put_f32(&mut value, 1.);
+    put_attribute(&mut header, "pixelAspectRatio", "float", &value);
+    let mut value = Vec::new();
+    put_f32(&mut value, 0.);
+    put_f32(&mut value, 0.);
+    put_attribute(&mut header, "screenWindowCenter", "v2f", &value);
+    let mut value = Vec::new();
+    put_f32(&mut value, 1.);
+    put_attribute(&mut header, "screenWindowWidth", "float", &value);
+    header.push(0);
+
+    // offset table, one chunk per scanline without compression
+    let line_size = width as usize * sorted.iter().map(|c| c.pixel_type.size()).sum::<usize>();
+    let chunk_size = 8 + line_size;
+    let table_end = header.len() + height as usize * 8;
+    for y in 0..height as usize {
+        put_u64(&mut header, (table_end + y * chunk_size) as u64);
+    }
+    w.write_all(&header)?;
+
+    let mut line = Vec::with_capacity(chunk_size);
+    for y in 0..height as usize {
+        line.clear();
+        put_i32(&mut line, y as i32);
+        put_i32(&mut line, line_size as i32);
+        for c in &sorted {
+            for &v in &c.data[y * width as usize..(y + 1) * width as usize] {
+                match c.pixel_type {
+                    ExrPixelType::Half => {
+                        let h = f32_to_f16(v);
+                        line.extend_from_slice(&[h as u8, (h >> 8) as u8]);
+                    }
+                    ExrPixelType::Float => put_f32(&mut line, v),
+                }
+            }
+        }
+        w.write_all(&line)?;
+    }
+    Ok(())
+}
+
+pub fn save_exr<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    channels: &[ExrChannel],
+) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write_exr(&mut w, width, height, channels)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_test() {
+        assert!(f32_to_f16(0.) == 0);
+        assert!(f32_to_f16(1.) == 0x3c00);
+        assert!(f32_to_f16(-2.) == 0xc000);
+        assert!(f32_to_f16(0.1) == 0x2e66);
+        assert!(f32_to_f16(65504.) == 0x7bff);
+        assert!(f32_to_f16(1e6) == 0x7c00);
+        assert!(f32_to_f16(2f32.powi(-24)) == 0x0001);
+        assert!(f32_to_f16(2f32.powi(-14)) == 0x0400);
+    }
+}
@@ -0,0 +1,224 @@
+use std::f32;
+use std::io;
+use std::path::Path;
+
+use math::*;
+use core::exr::*;
+use core::rgbe::*;
+use core::filter::*;
+
+/// Rec. 709 luminance of linear RGB.
+pub fn luminance(c: Vec3) -> f32 {
+    0.2126 * c.x + 0.7152 * c.y + 0.0722 * c.z
+}
+
+/// Floating point accumulation buffer for linear radiance. Each pixel keeps
+/// a weighted sum of its samples, so nothing is lost to 8-bit quantization
+/// between passes, plus enough statistics to estimate its error.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    sum: Vec<Vec3>,
+    weight: Vec<f32>,
+    // sums of luminance and its square and number of the samples taken
+    // inside the pixel, for `error`
+    sum_l: Vec<f32>,
+    sum_sq: Vec<f32>,
+    samples: Vec<u32>,
+    // light path contributions, see `add_light`
+    light: Vec<Vec3>,
+    light_paths: u64,
+}
+
+impl Film {
+    pub fn new(dimensions: (u32, u32)) -> Film {
+        let count = (dimensions.0 * dimensions.1) as usize;
+        Film {
+            width: dimensions.0,
+            height: dimensions.1,
+            sum: vec![Vec3::zero(); count],
+            weight: vec![0.; count],
+            sum_l: vec![0.; count],
+            sum_sq: vec![0.; count],
+            samples: vec![0; count],
+            light: vec![Vec3::zero(); count],
+            light_paths: 0,
+        }
+    }
+
+    /// Film holding `pixels` (rows from the top) as single samples, e.g.
+    /// the result of post-processing another film.
+    pub fn from_pixels(dimensions: (u32, u32), pixels: Vec<Vec3>) -> Film {
+        let count = pixels.len();
+        assert!(count == (dimensions.0 * dimensions.1) as usize);
+        Film {
+            width: dimensions.0,
+            height: dimensions.1,
+            sum_l: pixels.iter().map(|&c| luminance(c)).collect(),
+            sum_sq: pixels.iter().map(|&c| luminance(c) * luminance(c)).collect(),
+            sum: pixels,
+            weight: vec![1.; count],
+            samples: vec![1; count],
+            light: vec![Vec3::zero(); count],
+            light_paths: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for v in self.sum.iter_mut() {
+            *v = Vec3::zero();
+        }
+        for w in self.weight.iter_mut() {
+            *w = 0.;
+        }
+        for s in self.sum_l.iter_mut() {
+            *s = 0.;
+        }
+        for s in self.sum_sq.iter_mut() {
+            *s = 0.;
+        }
+        for n in self.samples.iter_mut() {
+            *n = 0;
+        }
+        for v in self.light.iter_mut() {
+            *v = Vec3::zero();
+        }
+        self.light_paths = 0;
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+
+    pub fn add_sample(&mut self, x: u32, y: u32, color: Vec3, weight: f32) {
+        let i = self.index(x, y);
+        self.sum[i] = self.sum[i] + color * weight;
+        self.weight[i] += weight;
+        self.count_sample(i, color);
+    }
+
+    fn count_sample(&mut self, i: usize, color: Vec3) {
+        let l = luminance(color);
+        self.sum_l[i] += l;
+        self.sum_sq[i] += l * l;
+        self.samples[i] += 1;
+    }
+
+    /// Adds a sample taken at continuous image position `(x, y)`, where
+    /// pixel `(i, j)` covers `[i, i + 1) x [j, j + 1)`, to every pixel within
+    /// the filter's radius. It only counts as a sample of the pixel it was
+    /// taken in, the others merely blend it in.
+    pub fn splat(&mut self, x: f32, y: f32, color: Vec3, filter: &Filter) {
+        let r = filter.radius();
+        let x0 = (x - 0.5 - r).ceil().max(0.) as u32;
+        let y0 = (y - 0.5 - r).ceil().max(0.) as u32;
+        let x1 = ((x - 0.5 + r).floor().max(-1.) as i64).min(self.width as i64 - 1);
+        let y1 = ((y - 0.5 + r).floor().max(-1.) as i64).min(self.height as i64 - 1);
+        for py in y0 as i64..y1 + 1 {
+            for px in x0 as i64..x1 + 1 {
+                let weight = filter.evaluate(px as f32 + 0.5 - x, py as f32 + 0.5 - y);
+                if weight != 0. {
+                    let i = self.index(px as u32, py as u32);
+                    self.sum[i] = self.sum[i] + color * weight;
+                    self.weight[i] += weight;
+                }
+            }
+        }
+        if x >= 0. && y >= 0. && x < self.width as f32 && y < self.height as f32 {
+            let i = self.index(x as u32, y as u32);
+            self.count_sample(i, color);
+        }
+    }
+
+    /// Adds radiance a light path carried to continuous image position
+    /// `(x, y)`. Unlike camera samples these aren't averaged per pixel but
+    /// summed and divided by the light paths per pixel of the whole image,
+    /// see `add_light_paths`; the reconstruction filter doesn't apply.
+    pub fn add_light(&mut self, x: f32, y: f32, color: Vec3) {
+        if x >= 0. && y >= 0. && x < self.width as f32 && y < self.height as f32 {
+            let i = self.index(x as u32, y as u32);
+            self.light[i] = self.light[i] + color;
+        }
+    }
+
+    /// Counts light paths traced, whether or not they reached the image.
+    pub fn add_light_paths(&mut self, count: u64) {
+        self.light_paths += count;
+    }
+
+    pub fn samples(&self, x: u32, y: u32) -> u32 {
+        self.samples[self.index(x, y)]
+    }
+
+    /// Estimated relative standard error of the pixel's mean luminance,
+    /// infinite with fewer than two samples. Luminance below 0.1 counts as
+    /// 0.1, so nearly black pixels aren't held to a relative precision
+    /// nobody could see.
+    pub fn error(&self, x: u32, y: u32) -> f32 {
+        let i = self.index(x, y);
+        let n = self.samples[i];
+        if n < 2 {
+            return f32::INFINITY;
+        }
+        let n = n as f32;
+        let mean = self.sum_l[i] / n;
+        let variance = (self.sum_sq[i] / n - mean * mean).max(0.);
+        // the unbiased sample variance divided by n, i.e. the variance of the mean
+        (variance / (n - 1.)).sqrt() / mean.max(0.1)
+    }
+
+    /// Weighted mean radiance of a pixel plus what light paths brought to
+    /// it, black before the first sample.
+    pub fn pixel(&self, x: u32, y: u32) -> Vec3 {
+        let i = self.index(x, y);
+        // negative filter lobes can leave tiny or negative weights early on
+        let camera = if self.weight[i] > 1e-4 {
+            self.sum[i] / self.weight[i]
+        } else {
+            Vec3::zero()
+        };
+        if self.light_paths == 0 {
+            return camera;
+        }
+        let paths_per_pixel = self.light_paths as f32 / (self.width * self.height) as f32;
+        camera + self.light[i] / paths_per_pixel
+    }
+
+    /// Mean radiance of every pixel, rows from the top.
+    pub fn pixels(&self) -> Vec<Vec3> {
+        let mut pixels = Vec::with_capacity(self.sum.len());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                pixels.push(self.pixel(x, y));
+            }
+        }
+        pixels
+    }
+
+    /// Red, green and blue planes of `pixels`, as stored in OpenEXR files.
+    pub fn planes(&self) -> [Vec<f32>; 3] {
+        let pixels = self.pixels();
+        [
+            pixels.iter().map(|c| c.x).collect(),
+            pixels.iter().map(|c| c.y).collect(),
+            pixels.iter().map(|c| c.z).collect(),
+        ]
+    }
+
+    /// Writes the linear radiance, before exposure and tone mapping, as an
+    /// OpenEXR image with R, G and B channels.
+    pub fn save_exr<P: AsRef<Path>>(&self, path: P, pixel_type: ExrPixelType) -> io::Result<()> {
+        let planes = self.planes();
+        let channels = [
+            ExrChannel::new("R", &planes[0], pixel_type),
+            ExrChannel::new("G", &planes[1], pixel_type),
+            ExrChannel::new("B", &planes[2], pixel_type),
+        ];
+        save_exr(path, self.width, self.height, &channels)
+    }
+
+    /// Writes the linear radiance as a Radiance `.hdr` image.
+    pub fn save_hdr<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        save_hdr(path, self.width, self.height, &self.pixels())
+    }
+}
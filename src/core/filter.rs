@@ -0,0 +1,96 @@
+/// Pixel reconstruction filter. Every sample is splatted into all pixels
+/// whose center lies within `radius` of it, weighted by the filter. Filters
+/// are separable, the weight is `f(dx) * f(dy)` in pixel units.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filter {
+    /// A radius of 0.5 keeps every sample in its own pixel.
+    Box { radius: f32 },
+    Tent { radius: f32 },
+    /// Gaussian shifted down so it reaches 0 at the radius.
+    Gaussian { radius: f32, alpha: f32 },
+    /// Mitchell-Netravali cubic. B = C = 1/3 is the recommended balance of
+    /// blur and ringing, and its negative lobes sharpen slightly.
+    Mitchell { radius: f32, b: f32, c: f32 },
+}
+
+impl Filter {
+    /// Filter with its usual radius and parameters.
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "box" => Some(Filter::Box { radius: 0.5 }),
+            "tent" => Some(Filter::Tent { radius: 1. }),
+            "gaussian" => Some(Filter::Gaussian { radius: 1.5, alpha: 2. }),
+            "mitchell" => Some(Filter::Mitchell { radius: 2., b: 1. / 3., c: 1. / 3. }),
+            _ => None,
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box { radius } |
+            Filter::Tent { radius } |
+            Filter::Gaussian { radius, .. } |
+            Filter::Mitchell { radius, .. } => radius,
+        }
+    }
+
+    pub fn with_radius(&self, radius: f32) -> Filter {
+        match *self {
+            Filter::Box { .. } => Filter::Box { radius },
+            Filter::Tent { .. } => Filter::Tent { radius },
+            Filter::Gaussian { alpha, .. } => Filter::Gaussian { radius, alpha },
+            Filter::Mitchell { b, c, .. } => Filter::Mitchell { radius, b, c },
+        }
+    }
+
+    /// One dimensional filter value at offset `x` from the center.
+    pub fn evaluate_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        match *self {
+            Filter::Box { radius } => if x < radius { 1. } else { 0. },
+            Filter::Tent { radius } => (radius - x).max(0.),
+            Filter::Gaussian { radius, alpha } => {
+                ((-alpha * x * x).exp() - (-alpha * radius * radius).exp()).max(0.)
+            }
+            Filter::Mitchell { radius, b, c } => {
+                // the cubic is defined on [-2, 2]
+                let x = 2. * x / radius;
+                let x2 = x * x;
+                let x3 = x2 * x;
+                if x < 1. {
+                    ((12. - 9. * b - 6. * c) * x3 + (-18. + 12. * b + 6. * c) * x2 + (6. - 2. * b)) / 6.
+                } else if x < 2. {
+                    ((-b - 6. * c) * x3 + (6. * b + 30. * c) * x2 + (-12. * b - 48. * c) * x +
+                        (8. * b + 24. * c)) / 6.
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+
+    pub fn evaluate(&self, dx: f32, dy: f32) -> f32 {
+        self.evaluate_1d(dx) * self.evaluate_1d(dy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_test() {
+        let mitchell = Filter::from_name("mitchell").unwrap();
+        // with B + 2C = 1 and unit spacing, shifted copies sum to one
+        for &x in &[0., 0.25, 0.5, 0.8] {
+            let sum: f32 = (-2..3).map(|k| mitchell.evaluate_1d(x + k as f32)).sum();
+            assert!((sum - 1.).abs() < 1e-5);
+        }
+        assert!(mitchell.evaluate_1d(1.5) < 0.);
+
+        let tent = Filter::Tent { radius: 1. };
+        assert!(tent.evaluate(0.5, 0.) == 0.5);
+        assert!(Filter::Box { radius: 0.5 }.evaluate(0.5, 0.) == 0.);
+        assert!(Filter::from_name("gaussian").unwrap().evaluate_1d(1.5) == 0.);
+    }
+}
@@ -0,0 +1,272 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use math::*;
+
+const BRICK_SIZE: usize = 8;
+const BRICK_VOXELS: usize = BRICK_SIZE * BRICK_SIZE * BRICK_SIZE;
+
+enum GridData {
+    Dense(Vec<f32>),
+    /// 8^3 bricks, `None` for bricks that are entirely empty.
+    Sparse(Vec<Option<Box<[f32; BRICK_VOXELS]>>>),
+}
+
+/// 3D grid of density values spanning the unit cube, with voxel centers at
+/// `(i + 0.5) / dims`. Storage is either dense or a sparse set of bricks,
+/// which saves memory for mostly empty volumes like clouds.
+pub struct DensityGrid {
+    pub dims: (usize, usize, usize),
+    data: GridData,
+}
+
+fn brick_dims(dims: (usize, usize, usize)) -> (usize, usize, usize) {
+    let up = |n: usize| n.div_ceil(BRICK_SIZE);
+    (up(dims.0), up(dims.1), up(dims.2))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn write_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]);
+}
+
+impl DensityGrid {
+    pub fn dense(dims: (usize, usize, usize), values: Vec<f32>) -> DensityGrid {
+        assert!(dims.0 > 0 && dims.1 > 0 && dims.2 > 0, "empty grid");
+        assert_eq!(values.len(), dims.0 * dims.1 * dims.2);
+        DensityGrid {
+            dims,
+            data: GridData::Dense(values),
+        }
+    }
+
+    /// Empty sparse grid, fill it with `set`.
+    pub fn sparse(dims: (usize, usize, usize)) -> DensityGrid {
+        assert!(dims.0 > 0 && dims.1 > 0 && dims.2 > 0, "empty grid");
+        let b = brick_dims(dims);
+        DensityGrid {
+            dims,
+            data: GridData::Sparse((0..b.0 * b.1 * b.2).map(|_| None).collect()),
+        }
+    }
+
+    /// Loads a raw grid: three little-endian u32 dimensions followed by the
+    /// little-endian f32 densities with x varying fastest, then y, then z.
+    /// Voxels that are zero are dropped into a sparse grid when `sparse` is set.
+    pub fn load<P: AsRef<Path>>(path: P, sparse: bool) -> io::Result<DensityGrid> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if bytes.len() < 12 {
+            return Err(invalid("grid header is truncated"));
+        }
+        let dims = (
+            read_u32(&bytes[0..4]) as usize,
+            read_u32(&bytes[4..8]) as usize,
+            read_u32(&bytes[8..12]) as usize,
+        );
+        if dims.0 == 0 || dims.1 == 0 || dims.2 == 0 {
+            return Err(invalid("grid has no voxels"));
+        }
+        let size = dims.0
+            .checked_mul(dims.1)
+            .and_then(|n| n.checked_mul(dims.2))
+            .and_then(|n| n.checked_mul(4))
+            .and_then(|n| n.checked_add(12));
+        if size != Some(bytes.len()) {
+            return Err(invalid("grid size does not match its dimensions"));
+        }
+        let values: Vec<f32> = bytes[12..]
+            .chunks(4)
+            .map(|b| f32::from_bits(read_u32(b)))
+            .collect();
+
+        if !sparse {
+            return Ok(DensityGrid::dense(dims, values));
+        }
+        let mut grid = DensityGrid::sparse(dims);
+        for z in 0..dims.2 {
+            for y in 0..dims.1 {
+                for x in 0..dims.0 {
+                    let v = values[(z * dims.1 + y) * dims.0 + x];
+                    if v != 0. {
+                        grid.set(x, y, z, v);
+                    }
+                }
+            }
+        }
+        Ok(grid)
+    }
+
+    /// Writes the grid in the format read by `load`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = Vec::with_capacity(12 + self.dims.0 * self.dims.1 * self.dims.2 * 4);
+        write_u32(&mut out, self.dims.0 as u32);
+        write_u32(&mut out, self.dims.1 as u32);
+        write_u32(&mut out, self.dims.2 as u32);
+        for z in 0..self.dims.2 {
+            for y in 0..self.dims.1 {
+                for x in 0..self.dims.0 {
+                    write_u32(&mut out, self.voxel(x, y, z).to_bits());
+                }
+            }
+        }
+        File::create(path)?.write_all(&out)
+    }
+
+    fn brick_index(&self, x: usize, y: usize, z: usize) -> (usize, usize) {
+        let b = brick_dims(self.dims);
+        let brick = ((z / BRICK_SIZE) * b.1 + y / BRICK_SIZE) * b.0 + x / BRICK_SIZE;
+        let (lx, ly, lz) = (x % BRICK_SIZE, y % BRICK_SIZE, z % BRICK_SIZE);
+        (brick, (lz * BRICK_SIZE + ly) * BRICK_SIZE + lx)
+    }
+
+    pub fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        match self.data {
+            GridData::Dense(ref values) => values[(z * self.dims.1 + y) * self.dims.0 + x],
+            GridData::Sparse(ref bricks) => {
+                let (brick, i) = self.brick_index(x, y, z);
+                bricks[brick].as_ref().map_or(0., |b| b[i])
+            }
+        }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, v: f32) {
+        let (brick, i) = self.brick_index(x, y, z);
+        let index = (z * self.dims.1 + y) * self.dims.0 + x;
+        match self.data {
+            GridData::Dense(ref mut values) => values[index] = v,
+            GridData::Sparse(ref mut bricks) => {
+                if bricks[brick].is_none() {
+                    if v == 0. {
+                        return;
+                    }
+                    bricks[brick] = Some(Box::new([0.; BRICK_VOXELS]));
+                }
+                if let Some(ref mut b) = bricks[brick] {
+                    b[i] = v;
+                }
+            }
+        }
+    }
+
+    /// Trilinearly interpolated density at `p` in the unit cube, zero outside.
+    pub fn density(&self, p: Vec3) -> f32 {
+        if p.x < 0. || p.y < 0. || p.z < 0. || p.x > 1. || p.y > 1. || p.z > 1. {
+            return 0.;
+        }
+        let g = Vec3::new(
+            p.x * self.dims.0 as f32 - 0.5,
+            p.y * self.dims.1 as f32 - 0.5,
+            p.z * self.dims.2 as f32 - 0.5,
+        );
+        let (fx, fy, fz) = (g.x.floor(), g.y.floor(), g.z.floor());
+        let (tx, ty, tz) = (g.x - fx, g.y - fy, g.z - fz);
+        let clamp = |i: f32, n: usize| (i.max(0.) as usize).min(n - 1);
+        let (x0, y0, z0) = (clamp(fx, self.dims.0), clamp(fy, self.dims.1), clamp(fz, self.dims.2));
+        let (x1, y1, z1) = (
+            clamp(fx + 1., self.dims.0),
+            clamp(fy + 1., self.dims.1),
+            clamp(fz + 1., self.dims.2),
+        );
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let c00 = lerp(self.voxel(x0, y0, z0), self.voxel(x1, y0, z0), tx);
+        let c10 = lerp(self.voxel(x0, y1, z0), self.voxel(x1, y1, z0), tx);
+        let c01 = lerp(self.voxel(x0, y0, z1), self.voxel(x1, y0, z1), tx);
+        let c11 = lerp(self.voxel(x0, y1, z1), self.voxel(x1, y1, z1), tx);
+        lerp(lerp(c00, c10, ty), lerp(c01, c11, ty), tz)
+    }
+}
+
+/// Coarse grid of density upper bounds over a `DensityGrid`, one cell per
+/// 8^3 voxels. Lets tracking take long steps through thin regions.
+pub struct MajorantGrid {
+    pub dims: (usize, usize, usize),
+    values: Vec<f32>,
+}
+
+impl MajorantGrid {
+    pub fn new(grid: &DensityGrid) -> MajorantGrid {
+        let dims = brick_dims(grid.dims);
+        let mut values = vec![0.; dims.0 * dims.1 * dims.2];
+        for cz in 0..dims.2 {
+            for cy in 0..dims.1 {
+                for cx in 0..dims.0 {
+                    // trilinear lookups reach one voxel past the cell
+                    let range = |c: usize, n: usize| {
+                        (c * BRICK_SIZE).saturating_sub(1)..((c + 1) * BRICK_SIZE + 1).min(n)
+                    };
+                    let mut max: f32 = 0.;
+                    for z in range(cz, grid.dims.2) {
+                        for y in range(cy, grid.dims.1) {
+                            for x in range(cx, grid.dims.0) {
+                                max = max.max(grid.voxel(x, y, z));
+                            }
+                        }
+                    }
+                    values[(cz * dims.1 + cy) * dims.0 + cx] = max;
+                }
+            }
+        }
+        MajorantGrid {
+            dims,
+            values,
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.values[(z * self.dims.1 + y) * self.dims.0 + x]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn grid_file_test() {
+        let path = env::temp_dir().join("rusty-ray-grid-test.raw");
+        let mut grid = DensityGrid::sparse((9, 2, 3));
+        grid.set(8, 1, 2, 0.75);
+        grid.set(0, 0, 0, 2.);
+        grid.save(&path).unwrap();
+        for &sparse in &[false, true] {
+            let loaded = DensityGrid::load(&path, sparse).unwrap();
+            assert!(loaded.dims == (9, 2, 3));
+            assert!(loaded.voxel(8, 1, 2) == 0.75 && loaded.voxel(0, 0, 0) == 2. && loaded.voxel(1, 0, 0) == 0.);
+        }
+
+        let mut header = Vec::new();
+        write_u32(&mut header, 2);
+        write_u32(&mut header, 0);
+        write_u32(&mut header, 2);
+        let malformed = vec![
+            // truncated header
+            header[..8].to_vec(),
+            // a zero dimension
+            header.clone(),
+            // dimensions whose size overflows
+            vec![0xff; 12],
+            // a value missing
+            {
+                let mut bytes = Vec::new();
+                for _ in 0..3 {
+                    write_u32(&mut bytes, 1);
+                }
+                bytes
+            },
+        ];
+        for bytes in malformed {
+            File::create(&path).unwrap().write_all(&bytes).unwrap();
+            let error = DensityGrid::load(&path, false).err().expect("malformed grid loaded");
+            assert!(error.kind() == io::ErrorKind::InvalidData);
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,119 @@
+use std::sync::Arc;
+
+use math::*;
+use core::raytracer::*;
+use core::scene::*;
+
+/// Places a shared object in the scene with its own transform. The wrapped
+/// object is intersected in its local space, so one object can be reused by
+/// any number of instances without copying its geometry.
+pub struct Instance {
+    pub object: Arc<dyn SceneObject>,
+    pub transform: Transform,
+}
+
+impl Instance {
+    pub fn new(object: Arc<dyn SceneObject>, transform: Transform) -> Instance {
+        Instance {
+            object,
+            transform,
+        }
+    }
+
+    // objects expect unit directions, so t is scaled between the two spaces
+    fn local_ray(&self, ray: &Ray) -> (Ray, f32) {
+        let local = self.transform.inverse().ray(ray);
+        let scale = local.dir.length();
+        (Ray::new(local.origin, local.dir / scale), scale)
+    }
+
+    fn to_world(&self, mut result: IntersectionResult, scale: f32) -> IntersectionResult {
+        result.t /= scale;
+        result.n = self.transform.normal(result.n).normalize();
+        result
+    }
+}
+
+impl SceneObject for Instance {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        let (local, scale) = self.local_ray(ray);
+        self.object
+            .intersect(&local, t_min * scale, t_max * scale)
+            .map(|result| self.to_world(result, scale))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.object.bounds().map(|b| b.transform(&self.transform))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let (local, scale) = self.local_ray(ray);
+        self.object
+            .intervals(&local)
+            .into_iter()
+            .map(|interval| Interval {
+                enter: self.to_world(interval.enter, scale),
+                exit: self.to_world(interval.exit, scale),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-4
+    }
+
+    #[test]
+    fn instance_test() {
+        // a unit sphere stretched into an ellipsoid with semi-axes 2, 1 and
+        // 0.5, centered at z = 10
+        let sphere = Arc::new(Sphere {
+            origin: Vec3::zero(),
+            radius: 1.,
+            material: Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+        });
+        let instance = Instance::new(
+            sphere,
+            Transform::translate(Vec3::new(0., 0., 10.)) * Transform::scale(Vec3::new(2., 1., 0.5)),
+        );
+
+        let bounds = instance.bounds().unwrap();
+        assert!(close(bounds.min, Vec3::new(-2., -1., 9.5)));
+        assert!(close(bounds.max, Vec3::new(2., 1., 10.5)));
+
+        let hit = instance.intersect(&Ray::new(Vec3::zero(), Vec3::new(0., 0., 1.)), 0., f32::INFINITY).unwrap();
+        assert!((hit.t - 9.5).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(0., 0., -1.)));
+
+        let hit = instance.intersect(&Ray::new(Vec3::new(-5., 0., 10.), Vec3::new(1., 0., 0.)), 0., f32::INFINITY).unwrap();
+        assert!((hit.t - 3.).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(-1., 0., 0.)));
+
+        // away from the axes the normal follows the gradient of
+        // x^2 / 4 + y^2 + 4 z^2, not the direction from the center; rays
+        // come in along the normal from 3 units out
+        for i in 0..16 {
+            let (a, b) = (i as f32 * 0.4, i as f32 * 0.7 - 2.);
+            let local = Vec3::new(b.cos() * a.cos(), b.cos() * a.sin(), b.sin());
+            let p = Vec3::new(2. * local.x, local.y, 10. + 0.5 * local.z);
+            let n = Vec3::new(local.x / 2., local.y, 2. * local.z).normalize();
+            let hit = instance.intersect(&Ray::new(p + n * 3., -n), 0., f32::INFINITY).unwrap();
+            assert!((hit.t - 3.).abs() < 1e-3, "t {}", hit.t);
+            assert!(close(hit.n, n), "normal {} instead of {}", hit.n, n);
+            // the interval runs from there to the far side
+            let intervals = instance.intervals(&Ray::new(p + n * 3., -n));
+            assert_eq!(intervals.len(), 1);
+            assert!((intervals[0].enter.t - 3.).abs() < 1e-3);
+            assert!(close(intervals[0].enter.n, n));
+        }
+
+        // t_max is in world units
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0., 0., 1.));
+        assert!(instance.intersect(&ray, 0., 9.4).is_none());
+        assert!(instance.intersect(&ray, 0., 9.6).is_some());
+    }
+}
@@ -0,0 +1,614 @@
+use std::borrow::Cow;
+use std::f32;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use math::*;
+use core::util::*;
+use core::scene::*;
+use core::medium::*;
+use core::raytracer::*;
+use core::path::*;
+use core::bdpt::*;
+use core::photon::*;
+use core::mlt::*;
+use core::spectral::*;
+use core::bvh;
+
+/// Offset that keeps secondary rays from hitting the surface they start on.
+pub const EPSILON: f32 = 0.001;
+
+/// Radiance that lands elsewhere on the image than the camera sample that
+/// produced it, e.g. a light path seen by the camera. See `Film::add_light`.
+#[derive(Clone, Copy, Debug)]
+pub struct Splat {
+    /// Continuous image position.
+    pub x: f32,
+    pub y: f32,
+    pub radiance: Vec3,
+}
+
+/// Bookkeeping along one camera path.
+pub struct PathState {
+    /// Rays traced so far, shadow rays included.
+    pub rays: u32,
+    /// Product of the weights so far.
+    pub throughput: Vec3,
+    /// Every segment of the path, when it is being recorded.
+    pub vertices: Option<Vec<PathVertex>>,
+    /// Light paths traced along with the camera path and what they added
+    /// to the image.
+    pub light_paths: u32,
+    pub splats: Vec<Splat>,
+    /// Set when the path is traced spectrally; its colors are then samples
+    /// at these wavelengths.
+    pub wavelengths: Option<Wavelengths>,
+}
+
+impl PathState {
+    pub fn new(record: bool) -> PathState {
+        PathState {
+            rays: 0,
+            throughput: Vec3::new(1., 1., 1.),
+            vertices: if record { Some(Vec::new()) } else { None },
+            light_paths: 0,
+            splats: Vec::new(),
+            wavelengths: None,
+        }
+    }
+
+    /// `rgb` as the path carries colors.
+    pub fn color(&self, rgb: Vec3) -> Vec3 {
+        match self.wavelengths {
+            Some(ref wavelengths) => wavelengths.spectrum(rgb),
+            None => rgb,
+        }
+    }
+
+    /// `medium` with its colors as the path carries them.
+    pub fn medium<'a>(&self, medium: &'a Medium) -> Cow<'a, Medium> {
+        match self.wavelengths {
+            Some(ref wavelengths) => Cow::Owned(wavelengths.medium(medium)),
+            None => Cow::Borrowed(medium),
+        }
+    }
+
+    /// `material.scatter` for the colors the path carries. Spectral paths
+    /// refract at their hero wavelength, and only it goes on through glass
+    /// that disperses light.
+    pub fn scatter(&mut self, ray: &Ray, res: &IntersectionResult) -> Option<(Ray, Vec3)> {
+        match self.wavelengths {
+            Some(ref mut wavelengths) => match res.material {
+                Material::Dielectric(ref ior) if ior.is_dispersive() => {
+                    let weight = wavelengths.terminate_secondary();
+                    res.material.scatter_at(ray, res, wavelengths.lambda[0]).map(|(r, w)| (r, w * weight))
+                }
+                _ => res.material.scatter(ray, res).map(|(r, w)| (r, wavelengths.spectrum(w))),
+            },
+            None => res.material.scatter(ray, res),
+        }
+    }
+
+    /// Adds a segment if the path is being recorded. The event is only
+    /// built then.
+    pub fn record<F: FnOnce() -> PathEvent>(&mut self, depth: u8, ray: Ray, transmittance: Vec3, event: F) {
+        if let Some(ref mut vertices) = self.vertices {
+            vertices.push(PathVertex {
+                depth,
+                origin: ray.origin,
+                direction: ray.dir,
+                throughput: self.throughput,
+                transmittance,
+                event: event(),
+            });
+        }
+    }
+}
+
+/// Light transport algorithm, turns camera rays into radiance. Render
+/// threads share one instance, so it must not keep per-ray state; state
+/// carried from one sample to the next has to sit behind a lock.
+pub trait Integrator: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Radiance arriving at `tracer`'s camera along `ray`. Every traced ray
+    /// is counted in `path`; integrators that follow a single path also
+    /// record its bounces there when asked to, and those that trace light
+    /// paths leave their contributions there as splats.
+    fn radiance(&self, tracer: &RayTracer, ray: Ray, path: &mut PathState) -> Vec3;
+
+    /// Called when a `RayTracer` is set up with the integrator, and again
+    /// whenever its view changes, for work that has to happen before
+    /// rendering. Returns the integrator to render with instead, if any.
+    fn prepare(&self, _tracer: &RayTracer) -> Option<Arc<dyn Integrator>> {
+        None
+    }
+
+    /// True if `radiance` always returns zero and the image is made of
+    /// splats alone. Per-pixel error estimates mean nothing then, so
+    /// adaptive sampling doesn't apply.
+    fn splats_only(&self) -> bool {
+        false
+    }
+}
+
+/// Names accepted by `integrator_from_name`.
+pub const INTEGRATOR_NAMES: [&str; 12] =
+    ["path", "bdpt", "photon", "mlt", "spectral", "whitted", "ao", "normals", "uv", "depth", "hits", "bvh"];
+
+/// Integrator with its default settings.
+pub fn integrator_from_name(name: &str) -> Option<Arc<dyn Integrator>> {
+    let integrator: Arc<dyn Integrator> = match name {
+        "path" => Arc::new(PathTracer::new()),
+        "bdpt" => Arc::new(BidirectionalPathTracer::new()),
+        "photon" => Arc::new(PhotonMapper::new()),
+        "mlt" => Arc::new(MetropolisTracer::new()),
+        "spectral" => Arc::new(PathTracer {
+            spectral: true,
+            ..PathTracer::new()
+        }),
+        "whitted" => Arc::new(Whitted::new()),
+        "ao" => Arc::new(AmbientOcclusion::new()),
+        "normals" => Arc::new(DebugIntegrator::Normals),
+        "uv" => Arc::new(DebugIntegrator::Uv),
+        "depth" => Arc::new(DebugIntegrator::Depth { max: 20. }),
+        "hits" => Arc::new(DebugIntegrator::HitCount { max: 8 }),
+        "bvh" => Arc::new(DebugIntegrator::BvhCost { max: 64 }),
+        _ => return None,
+    };
+    Some(integrator)
+}
+
+/// First hit along `ray` beyond `min_t` that isn't an invisible volume
+/// boundary.
+pub fn first_surface(scene: &Scene, ray: Ray, min_t: f32) -> Option<IntersectionResult> {
+    let mut min_t = min_t;
+    loop {
+        match scene.intersect(ray, min_t) {
+            Some(IntersectionResult { material: Material::Volume(_), t, .. }) => min_t = t + EPSILON,
+            result => return result,
+        }
+    }
+}
+
+/// Fraction of light that gets from `origin` to the point `distance` away
+/// along `dir`: zero if a surface is in the way, otherwise the
+/// transmittance of the media passed on the way. `medium` is the one around
+/// `origin`.
+pub fn visibility(
+    scene: &Scene,
+    origin: Vec3,
+    dir: Vec3,
+    distance: f32,
+    medium: Option<&Medium>,
+    path: &mut PathState,
+) -> Vec3 {
+    let mut origin = origin;
+    let mut distance = distance;
+    let mut medium = medium.cloned();
+    let mut transmittance = Vec3::new(1., 1., 1.);
+    loop {
+        let ray = Ray::new(origin, dir);
+        path.rays += 1;
+        let hit = match scene.intersect(ray, EPSILON) {
+            Some(hit) => if hit.t < distance - EPSILON { Some(hit) } else { None },
+            None => None,
+        };
+        let t = hit.as_ref().map_or(distance, |hit| hit.t);
+        if let Some(ref medium) = medium {
+            transmittance = transmittance * path.medium(medium).transmittance(&ray, t);
+        }
+        match hit {
+            Some(IntersectionResult { material: Material::Volume(m), n, .. }) => {
+                medium = if dir.dot(n) < 0. { Some(m) } else { scene.atmosphere.clone() };
+                origin = ray.point(t);
+                distance -= t;
+            }
+            Some(_) => return Vec3::zero(),
+            None => return transmittance,
+        }
+    }
+}
+
+/// Light reflected towards the viewer by a diffuse surface at `p` with
+/// normal `n`, coming straight from the scene's point lights.
+pub fn direct_diffuse(
+    scene: &Scene,
+    p: Vec3,
+    n: Vec3,
+    albedo: Vec3,
+    medium: Option<&Medium>,
+    path: &mut PathState,
+) -> Vec3 {
+    let mut sum = Vec3::zero();
+    for light in &scene.lights {
+        let (dir, distance, radiance) = light.illuminate(p);
+        let cos = dir.dot(n);
+        if cos > 0. {
+            let radiance = path.color(radiance);
+            sum = sum + radiance * visibility(scene, p, dir, distance, medium, path) * cos;
+        }
+    }
+    sum * albedo / PI
+}
+
+/// Light scattered towards `-dir` at `p` inside `medium`, coming straight
+/// from the scene's point lights.
+fn direct_medium(scene: &Scene, p: Vec3, dir: Vec3, medium: &Medium, path: &mut PathState) -> Vec3 {
+    let mut sum = Vec3::zero();
+    for light in &scene.lights {
+        let (to_light, distance, radiance) = light.illuminate(p);
+        let visible = visibility(scene, p, to_light, distance, Some(medium), path);
+        sum = sum + path.color(radiance) * visible * medium.phase(dir, to_light);
+    }
+    sum
+}
+
+/// Unidirectional path tracer. Diffuse bounces and medium scattering
+/// events also connect to the point lights directly.
+#[derive(Clone)]
+pub struct PathTracer {
+    pub max_depth: u8,
+    /// Light focused onto diffuse surfaces by mirrors and glass, which
+    /// the paths can't find themselves. See `PhotonMapper`.
+    pub caustics: Option<Arc<Caustics>>,
+    /// Traces every path at a few sampled wavelengths instead of in RGB,
+    /// see `Wavelengths`. Colors are turned into spectra on the way and the
+    /// result back into RGB.
+    pub spectral: bool,
+}
+
+impl Default for PathTracer {
+    fn default() -> PathTracer {
+        PathTracer::new()
+    }
+}
+
+impl PathTracer {
+    pub fn new() -> PathTracer {
+        PathTracer {
+            max_depth: 40,
+            caustics: None,
+            spectral: false,
+        }
+    }
+
+    /// Radiance along `ray`, which starts out travelling through `medium`.
+    fn trace(&self, scene: &Scene, ray: Ray, depth: u8, medium: Option<&Medium>, path: &mut PathState) -> Vec3 {
+        path.rays += 1;
+        let min_t = if depth > 0 { EPSILON } else { 0. };
+        let intersection = scene.intersect(ray, min_t);
+
+        let mut weight = Vec3::new(1., 1., 1.);
+        if let Some(medium) = medium {
+            let t_max = intersection.as_ref().map_or(f32::INFINITY, |r| r.t);
+            match path.medium(medium).sample(&ray, t_max) {
+                MediumEvent::Scatter { point, weight } => {
+                    let direct = direct_medium(scene, point, ray.dir, medium, path);
+                    let scattered = if depth < self.max_depth {
+                        Some(Ray::new(point, medium.sample_phase(ray.dir)))
+                    } else {
+                        None
+                    };
+                    path.record(depth, ray, Vec3::new(1., 1., 1.), || PathEvent::Medium {
+                        point,
+                        weight,
+                        direct,
+                        direction: scattered.map(|r| r.dir),
+                    });
+                    let indirect = match scattered {
+                        Some(scattered) => {
+                            path.throughput = path.throughput * weight;
+                            self.trace(scene, scattered, depth + 1, Some(medium), path)
+                        }
+                        None => Vec3::zero(),
+                    };
+                    return (direct + indirect) * weight;
+                }
+                MediumEvent::Pass { weight: w } => weight = w,
+            }
+        }
+
+        if let Some(ref result) = intersection {
+            // crossing a volume boundary switches media: entering its
+            // medium, or leaving back into the atmosphere
+            let next_medium = match result.material {
+                Material::Volume(ref m) if ray.dir.dot(result.n) < 0. => Some(m),
+                Material::Volume(_) => scene.atmosphere.as_ref(),
+                _ => medium,
+            };
+            let point = ray.point(result.t);
+            let direct = match result.material {
+                Material::Lambertian(albedo) => {
+                    let caustic = match self.caustics {
+                        Some(ref caustics) => path.color(caustics.radiance(point, result.n, albedo)),
+                        None => Vec3::zero(),
+                    };
+                    let albedo = path.color(albedo);
+                    direct_diffuse(scene, point, result.n, albedo, medium, path) + caustic
+                }
+                _ => Vec3::zero(),
+            };
+            let scatter = if depth < self.max_depth {
+                path.scatter(&ray, result)
+            } else {
+                None
+            };
+            path.record(depth, ray, weight, || PathEvent::Surface {
+                t: result.t,
+                point,
+                normal: result.n,
+                uv: result.uv,
+                object: result.object,
+                material: result.material.clone(),
+                direct,
+                sample: scatter.as_ref().map(|&(r, attenuation)| BsdfSample {
+                    direction: r.dir,
+                    weight: attenuation,
+                    pdf: result.material.pdf(result, r.dir),
+                }),
+            });
+            let indirect = match scatter {
+                Some((r, attenuation)) => {
+                    path.throughput = path.throughput * attenuation * weight;
+                    self.trace(scene, r, depth + 1, next_medium, path) * attenuation
+                }
+                None => Vec3::zero(),
+            };
+            (direct + indirect) * weight
+        } else {
+            let sky = path.color(scene.background);
+            path.record(depth, ray, weight, || PathEvent::Escaped { radiance: sky });
+            sky * weight
+        }
+    }
+}
+
+impl Integrator for PathTracer {
+    fn name(&self) -> &'static str {
+        if self.spectral { "spectral" } else { "path" }
+    }
+
+    fn radiance(&self, tracer: &RayTracer, ray: Ray, path: &mut PathState) -> Vec3 {
+        let scene = &tracer.scene;
+        if !self.spectral {
+            return self.trace(scene, ray, 0, scene.atmosphere.as_ref(), path);
+        }
+        path.wavelengths = Some(Wavelengths::sample(rnd()));
+        let radiance = self.trace(scene, ray, 0, scene.atmosphere.as_ref(), path);
+        path.wavelengths.map_or(radiance, |wavelengths| wavelengths.to_rgb(radiance))
+    }
+}
+
+/// Whitted style ray tracer: point lights with hard shadows and a constant
+/// ambient term on diffuse surfaces, perfect reflection on metals and one of
+/// reflection or refraction, picked at random, on dielectrics. Camera
+/// and reflection rays ignore participating media, shadow rays are still
+/// dimmed by them.
+pub struct Whitted {
+    pub max_depth: u8,
+    /// Fraction of the background that lights diffuse surfaces everywhere.
+    pub ambient: f32,
+}
+
+impl Default for Whitted {
+    fn default() -> Whitted {
+        Whitted::new()
+    }
+}
+
+impl Whitted {
+    pub fn new() -> Whitted {
+        Whitted {
+            max_depth: 8,
+            ambient: 0.2,
+        }
+    }
+
+    fn trace(&self, scene: &Scene, ray: Ray, depth: u8, path: &mut PathState) -> Vec3 {
+        path.rays += 1;
+        let min_t = if depth > 0 { EPSILON } else { 0. };
+        let result = match first_surface(scene, ray, min_t) {
+            Some(result) => result,
+            None => return scene.background,
+        };
+        let point = ray.point(result.t);
+        match result.material {
+            Material::Lambertian(albedo) => {
+                let ambient = scene.background * albedo * self.ambient;
+                ambient + direct_diffuse(scene, point, result.n, albedo, scene.atmosphere.as_ref(), path)
+            }
+            Material::Metal(_) | Material::Dielectric(_) if depth < self.max_depth => {
+                match result.material.scatter(&ray, &result) {
+                    Some((scattered, attenuation)) => self.trace(scene, scattered, depth + 1, path) * attenuation,
+                    None => Vec3::zero(),
+                }
+            }
+            _ => Vec3::zero(),
+        }
+    }
+}
+
+impl Integrator for Whitted {
+    fn name(&self) -> &'static str {
+        "whitted"
+    }
+
+    fn radiance(&self, tracer: &RayTracer, ray: Ray, path: &mut PathState) -> Vec3 {
+        self.trace(&tracer.scene, ray, 0, path)
+    }
+}
+
+/// Ambient occlusion: the fraction of cosine weighted directions above the
+/// first surface that are free of geometry up to `distance`. The
+/// background counts as unoccluded.
+pub struct AmbientOcclusion {
+    /// Occlusion rays per camera ray.
+    pub samples: u32,
+    pub distance: f32,
+}
+
+impl Default for AmbientOcclusion {
+    fn default() -> AmbientOcclusion {
+        AmbientOcclusion::new()
+    }
+}
+
+impl AmbientOcclusion {
+    pub fn new() -> AmbientOcclusion {
+        AmbientOcclusion {
+            samples: 4,
+            distance: 2.,
+        }
+    }
+}
+
+impl Integrator for AmbientOcclusion {
+    fn name(&self) -> &'static str {
+        "ao"
+    }
+
+    fn radiance(&self, tracer: &RayTracer, ray: Ray, path: &mut PathState) -> Vec3 {
+        let scene = &tracer.scene;
+        path.rays += 1;
+        let result = match first_surface(scene, ray, 0.) {
+            Some(result) => result,
+            None => return Vec3::new(1., 1., 1.),
+        };
+        let point = ray.point(result.t);
+        let n = if ray.dir.dot(result.n) > 0. { result.n * -1. } else { result.n };
+        let mut open = 0;
+        for _ in 0..self.samples {
+            let dir = (n + rnd_unit_vector()).normalize();
+            path.rays += 1;
+            match first_surface(scene, Ray::new(point, dir), EPSILON) {
+                Some(ref hit) if hit.t < self.distance => (),
+                _ => open += 1,
+            }
+        }
+        let ao = open as f32 / self.samples.max(1) as f32;
+        Vec3::new(ao, ao, ao)
+    }
+}
+
+/// Shows a property of the scene instead of light transport. Values are
+/// mapped to [0, 1], counts become a heatmap. Misses are black.
+#[derive(Clone, Copy, Debug)]
+pub enum DebugIntegrator {
+    /// Surface normal mapped from [-1, 1] to [0, 1].
+    Normals,
+    /// Surface coordinates in red and green.
+    Uv,
+    /// Distance to the first surface, white at `max` and beyond.
+    Depth { max: f32 },
+    /// Surfaces the camera ray passes through, volume boundaries included,
+    /// red at `max` and beyond.
+    HitCount { max: u32 },
+    /// BVH nodes visited to find the first hit, red at `max` and beyond.
+    /// Objects without bounds aren't in the BVH and cost nothing.
+    BvhCost { max: u32 },
+}
+
+/// Blue through green to red for `x` going from 0 to 1.
+fn heat(x: f32) -> Vec3 {
+    let x = x.clamp(0., 1.);
+    Vec3::new((2. * x - 1.).max(0.), 1. - (2. * x - 1.).abs(), (1. - 2. * x).max(0.))
+}
+
+impl Integrator for DebugIntegrator {
+    fn name(&self) -> &'static str {
+        match *self {
+            DebugIntegrator::Normals => "normals",
+            DebugIntegrator::Uv => "uv",
+            DebugIntegrator::Depth { .. } => "depth",
+            DebugIntegrator::HitCount { .. } => "hits",
+            DebugIntegrator::BvhCost { .. } => "bvh",
+        }
+    }
+
+    fn radiance(&self, tracer: &RayTracer, ray: Ray, path: &mut PathState) -> Vec3 {
+        let scene = &tracer.scene;
+        path.rays += 1;
+        match *self {
+            DebugIntegrator::HitCount { max } => {
+                let mut count = 0;
+                let mut min_t = 0.;
+                while let Some(hit) = scene.intersect(ray, min_t) {
+                    count += 1;
+                    min_t = hit.t + EPSILON;
+                    if count >= max {
+                        break;
+                    }
+                    path.rays += 1;
+                }
+                heat(count as f32 / max as f32)
+            }
+            DebugIntegrator::BvhCost { max } => {
+                let (_, visited) = bvh::count_nodes_visited(|| scene.intersect(ray, 0.));
+                heat(visited as f32 / max as f32)
+            }
+            _ => {
+                let result = match first_surface(scene, ray, 0.) {
+                    Some(result) => result,
+                    None => return Vec3::zero(),
+                };
+                match *self {
+                    DebugIntegrator::Normals => (result.n + Vec3::new(1., 1., 1.)) * 0.5,
+                    DebugIntegrator::Uv => Vec3::new(result.uv.0, result.uv.1, 0.),
+                    DebugIntegrator::Depth { max } => {
+                        let d = (result.t / max).min(1.);
+                        Vec3::new(d, d, d)
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::light::*;
+
+    #[test]
+    fn integrator_names_test() {
+        for &name in INTEGRATOR_NAMES.iter() {
+            assert!(integrator_from_name(name).map(|i| i.name()) == Some(name));
+        }
+        assert!(integrator_from_name("none").is_none());
+    }
+
+    #[test]
+    fn direct_lighting_test() {
+        // one diffuse sphere under a point light and a black sky: all light
+        // is direct, so path tracing and Whitted see the same
+        let mut scene = Scene::new();
+        scene.background = Vec3::zero();
+        scene.lights.push(PointLight::new(Vec3::new(-2., 3., 0.), Vec3::new(10., 10., 10.)));
+        scene.add(Box::new(Sphere {
+            origin: Vec3::new(0., 0., 4.),
+            radius: 1.,
+            material: Material::Lambertian(Vec3::new(0.8, 0.5, 0.2)),
+        }));
+        let tracer = RayTracer::new((16, 16), scene);
+        let (path_tracer, whitted) = (PathTracer::new(), Whitted::new());
+        let mut lit = 0;
+        for y in 0..16 {
+            for x in 0..16 {
+                let ray = tracer.ray_at(x as f32 + 0.5, y as f32 + 0.5);
+                let expected = whitted.radiance(&tracer, ray, &mut PathState::new(false));
+                let mut sum = Vec3::zero();
+                for _ in 0..16 {
+                    sum = sum + path_tracer.radiance(&tracer, ray, &mut PathState::new(false));
+                }
+                let error = sum / 16. - expected;
+                assert!(error.x.abs() < 1e-4 && error.y.abs() < 1e-4 && error.z.abs() < 1e-4);
+                if expected.x > 0. {
+                    lit += 1;
+                }
+            }
+        }
+        assert!(lit > 10);
+    }
+}
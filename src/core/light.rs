@@ -0,0 +1,28 @@
+use math::*;
+
+/// Light emitted equally in all directions from a single point. Rays can't
+/// hit it, integrators have to connect to it explicitly.
+#[derive(Clone, Copy, Debug)]
+pub struct PointLight {
+    pub position: Vec3,
+    /// Radiant intensity, irradiance at distance `d` facing the light is
+    /// `intensity / d^2`.
+    pub intensity: Vec3,
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, intensity: Vec3) -> PointLight {
+        PointLight {
+            position,
+            intensity,
+        }
+    }
+
+    /// Unit direction from `p` to the light, the distance to it and the
+    /// radiance arriving at `p` from that direction, before shadowing.
+    pub fn illuminate(&self, p: Vec3) -> (Vec3, f32, Vec3) {
+        let d = self.position - p;
+        let distance = d.length();
+        (d / distance, distance, self.intensity / (distance * distance))
+    }
+}
@@ -0,0 +1,468 @@
+use std::f32;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use math::*;
+use core::util::*;
+use core::grid::*;
+
+/// Henyey-Greenstein phase function value for the cosine between the
+/// incoming propagation direction and the scattered direction.
+pub fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let denom = 1. + g * g - 2. * g * cos_theta;
+    (1. - g * g) / (4. * PI * denom * denom.sqrt())
+}
+
+/// Samples a scattered direction for a ray travelling along `dir`, with
+/// density proportional to `henyey_greenstein`. Positive `g` scatters forward.
+pub fn sample_henyey_greenstein(dir: Vec3, g: f32) -> Vec3 {
+    let (u1, u2) = (rnd(), rnd());
+    let cos_theta = if g.abs() < 1e-3 {
+        1. - 2. * u1
+    } else {
+        let s = (1. - g * g) / (1. - g + 2. * g * u1);
+        (1. + g * g - s * s) / (2. * g)
+    };
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    let phi = 2. * PI * u2;
+    let (tu, tv) = dir.orthonormal_basis();
+    (tu * (sin_theta * phi.cos()) + tv * (sin_theta * phi.sin()) + dir * cos_theta).normalize()
+}
+
+/// Medium with constant coefficients. Coefficients are per unit of distance
+/// and per color channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HomogeneousMedium {
+    pub sigma_a: Vec3,
+    pub sigma_s: Vec3,
+    /// Henyey-Greenstein asymmetry, -1 (backward) to 1 (forward).
+    pub g: f32,
+}
+
+impl HomogeneousMedium {
+    pub fn sigma_t(&self) -> Vec3 {
+        self.sigma_a + self.sigma_s
+    }
+
+    pub fn transmittance(&self, distance: f32) -> Vec3 {
+        let tau = self.sigma_t() * distance;
+        Vec3::new((-tau.x).exp(), (-tau.y).exp(), (-tau.z).exp())
+    }
+
+    fn sample(&self, ray: &Ray, t_max: f32) -> MediumEvent {
+        // distances are sampled with the channel average; the per-channel
+        // difference goes into the weight
+        let sigma_t = self.sigma_t();
+        let density = (sigma_t.x + sigma_t.y + sigma_t.z) / 3.;
+        if density <= 0. {
+            return MediumEvent::Pass { weight: Vec3::new(1., 1., 1.) };
+        }
+        let u = rnd();
+        let t = -(1. - u).ln() / density;
+        if t < t_max {
+            let pdf = density * (-density * t).exp();
+            MediumEvent::Scatter {
+                point: ray.point(t),
+                weight: self.transmittance(t) * self.sigma_s / pdf,
+            }
+        } else {
+            MediumEvent::Pass { weight: self.transmittance(t_max) / (-density * t_max).exp() }
+        }
+    }
+}
+
+/// Medium whose density comes from a voxel grid stretched over `bounds`.
+/// Extinction is `sigma_t` times the density and is the same for every
+/// channel, which keeps delta tracking unbiased; color comes from the single
+/// scattering `albedo`. Attach it with `Material::Volume` to an object that
+/// encloses `bounds`, typically an `AxisBox` with the same extent.
+#[derive(Clone)]
+pub struct GridMedium {
+    pub grid: Arc<DensityGrid>,
+    majorants: Arc<MajorantGrid>,
+    pub bounds: Aabb,
+    pub sigma_t: f32,
+    pub albedo: Vec3,
+    pub g: f32,
+}
+
+impl GridMedium {
+    pub fn new(grid: Arc<DensityGrid>, bounds: Aabb, sigma_t: f32, albedo: Vec3, g: f32) -> GridMedium {
+        GridMedium {
+            majorants: Arc::new(MajorantGrid::new(&grid)),
+            grid,
+            bounds,
+            sigma_t,
+            albedo,
+            g,
+        }
+    }
+
+    fn extinction(&self, p: Vec3) -> f32 {
+        let e = self.bounds.extent();
+        let local = p - self.bounds.min;
+        self.sigma_t * self.grid.density(Vec3::new(local.x / e.x, local.y / e.y, local.z / e.z))
+    }
+
+    /// Walks the majorant cells along the ray up to `t_max` with a 3D DDA.
+    /// `segment` gets the range of t inside each cell and the cell's
+    /// extinction bound, and returns false to stop the walk.
+    fn traverse<F>(&self, ray: &Ray, t_max: f32, mut segment: F)
+    where
+        F: FnMut(f32, f32, f32) -> bool,
+    {
+        let (t_enter, t_exit) = match self.bounds.hit(ray, ray.inv_dir(), 0., t_max) {
+            Some(range) => range,
+            None => return,
+        };
+
+        // work in majorant cell units, where every cell is a unit cube
+        let dims = [self.majorants.dims.0, self.majorants.dims.1, self.majorants.dims.2];
+        let voxels = [self.grid.dims.0, self.grid.dims.1, self.grid.dims.2];
+        let extent = self.bounds.extent();
+        let mut origin = [0.; 3];
+        let mut dir = [0.; 3];
+        let mut cell = [0usize; 3];
+        let mut t_next = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+        for axis in 0..3 {
+            // cells are 8 voxels wide, the last one may stick out of the bounds
+            let scale = voxels[axis] as f32 / 8. / extent.axis(axis);
+            origin[axis] = (ray.origin.axis(axis) - self.bounds.min.axis(axis)) * scale;
+            dir[axis] = ray.dir.axis(axis) * scale;
+            let p = origin[axis] + dir[axis] * t_enter;
+            cell[axis] = (p.max(0.) as usize).min(dims[axis] - 1);
+            if dir[axis] > 0. {
+                t_next[axis] = ((cell[axis] + 1) as f32 - origin[axis]) / dir[axis];
+                t_delta[axis] = 1. / dir[axis];
+            } else if dir[axis] < 0. {
+                t_next[axis] = (cell[axis] as f32 - origin[axis]) / dir[axis];
+                t_delta[axis] = -1. / dir[axis];
+            }
+        }
+
+        let mut t = t_enter;
+        loop {
+            let axis = if t_next[0] < t_next[1] && t_next[0] < t_next[2] {
+                0
+            } else if t_next[1] < t_next[2] {
+                1
+            } else {
+                2
+            };
+            let t_end = t_next[axis].min(t_exit);
+            let majorant = self.sigma_t * self.majorants.get(cell[0], cell[1], cell[2]);
+            if t_end > t && !segment(t, t_end, majorant) {
+                return;
+            }
+            if t_end >= t_exit {
+                return;
+            }
+            t = t_end;
+            t_next[axis] += t_delta[axis];
+            if dir[axis] > 0. {
+                cell[axis] += 1;
+                if cell[axis] >= dims[axis] {
+                    return;
+                }
+            } else {
+                if cell[axis] == 0 {
+                    return;
+                }
+                cell[axis] -= 1;
+            }
+        }
+    }
+
+    /// Delta tracking: tentative collisions against the majorant, accepted
+    /// with probability of the real to majorant extinction ratio.
+    fn sample(&self, ray: &Ray, t_max: f32) -> MediumEvent {
+        let mut event = MediumEvent::Pass { weight: Vec3::new(1., 1., 1.) };
+        self.traverse(ray, t_max, |t_start, t_end, majorant| {
+            if majorant <= 0. {
+                return true;
+            }
+            let mut t = t_start;
+            loop {
+                t -= (1. - rnd()).ln() / majorant;
+                if t >= t_end {
+                    return true;
+                }
+                let point = ray.point(t);
+                if rnd() * majorant < self.extinction(point) {
+                    event = MediumEvent::Scatter {
+                        point,
+                        weight: self.albedo,
+                    };
+                    return false;
+                }
+            }
+        });
+        event
+    }
+
+    /// Ratio tracking estimate of the transmittance up to `t_max`.
+    fn transmittance(&self, ray: &Ray, t_max: f32) -> f32 {
+        let mut transmittance = 1.;
+        self.traverse(ray, t_max, |t_start, t_end, majorant| {
+            if majorant <= 0. {
+                return true;
+            }
+            let mut t = t_start;
+            loop {
+                t -= (1. - rnd()).ln() / majorant;
+                if t >= t_end {
+                    return true;
+                }
+                transmittance *= 1. - self.extinction(ray.point(t)) / majorant;
+                // terminate negligible paths, Russian roulette keeps it unbiased
+                if transmittance < 0.1 {
+                    if rnd() < 0.5 {
+                        transmittance = 0.;
+                        return false;
+                    }
+                    transmittance *= 2.;
+                }
+            }
+        });
+        transmittance
+    }
+}
+
+impl PartialEq for GridMedium {
+    /// Grids are compared by identity, they are too large to compare by value.
+    fn eq(&self, other: &GridMedium) -> bool {
+        Arc::ptr_eq(&self.grid, &other.grid) && self.bounds == other.bounds &&
+            self.sigma_t == other.sigma_t && self.albedo == other.albedo && self.g == other.g
+    }
+}
+
+/// Result of free-flight sampling along a ray segment.
+pub enum MediumEvent {
+    /// Scattered inside the medium before reaching the end of the segment.
+    Scatter { point: Vec3, weight: Vec3 },
+    /// Made it through; `weight` accounts for the transmittance.
+    Pass { weight: Vec3 },
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Medium {
+    Homogeneous(HomogeneousMedium),
+    Grid(GridMedium),
+}
+
+impl Medium {
+    /// Samples a free-flight distance along `ray`, which travels inside the
+    /// medium up to `t_max` (the next surface, or infinity).
+    pub fn sample(&self, ray: &Ray, t_max: f32) -> MediumEvent {
+        match *self {
+            Medium::Homogeneous(ref m) => m.sample(ray, t_max),
+            Medium::Grid(ref m) => m.sample(ray, t_max),
+        }
+    }
+
+    /// Fraction of light that makes it through the medium along `ray` up to
+    /// `t_max`. Exact for homogeneous media, an unbiased estimate for grids.
+    pub fn transmittance(&self, ray: &Ray, t_max: f32) -> Vec3 {
+        match *self {
+            Medium::Homogeneous(ref m) => m.transmittance(t_max),
+            Medium::Grid(ref m) => {
+                let t = m.transmittance(ray, t_max);
+                Vec3::new(t, t, t)
+            }
+        }
+    }
+
+    /// Phase function value for scattering from travelling along `dir` to
+    /// travelling along `scattered`, both unit vectors.
+    pub fn phase(&self, dir: Vec3, scattered: Vec3) -> f32 {
+        let g = match *self {
+            Medium::Homogeneous(ref m) => m.g,
+            Medium::Grid(ref m) => m.g,
+        };
+        henyey_greenstein(dir.dot(scattered), g)
+    }
+
+    /// Samples the direction a ray travelling along `dir` scatters to.
+    pub fn sample_phase(&self, dir: Vec3) -> Vec3 {
+        match *self {
+            Medium::Homogeneous(ref m) => sample_henyey_greenstein(dir, m.g),
+            Medium::Grid(ref m) => sample_henyey_greenstein(dir, m.g),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: usize = 100000;
+
+    fn close(a: Vec3, b: Vec3, tolerance: f32) -> bool {
+        (a - b).length() < tolerance
+    }
+
+    fn ray() -> Ray {
+        Ray::new(Vec3::zero(), Vec3::new(0., 0., 1.))
+    }
+
+    #[test]
+    fn transmittance_test() {
+        let medium = HomogeneousMedium {
+            sigma_a: Vec3::new(0.1, 0.5, 1.),
+            sigma_s: Vec3::new(0.4, 0.5, 1.),
+            g: 0.,
+        };
+        for &d in &[0f32, 0.5, 2., 10.] {
+            let expected = Vec3::new((-0.5 * d).exp(), (-d).exp(), (-2. * d).exp());
+            assert!(close(medium.transmittance(d), expected, 1e-6));
+            assert!(close(Medium::Homogeneous(medium.clone()).transmittance(&ray(), d), expected, 1e-6));
+        }
+    }
+
+    #[test]
+    fn free_flight_test() {
+        // grey: the mean distance to a collision is the mean free path, and
+        // a collision scatters with the albedo as weight
+        let medium = HomogeneousMedium {
+            sigma_a: Vec3::new(0.5, 0.5, 0.5),
+            sigma_s: Vec3::new(1.5, 1.5, 1.5),
+            g: 0.,
+        };
+        let mut sum = 0.;
+        for _ in 0..SAMPLES {
+            match medium.sample(&ray(), f32::INFINITY) {
+                MediumEvent::Scatter { point, weight } => {
+                    sum += point.z;
+                    assert!(close(weight, Vec3::new(0.75, 0.75, 0.75), 1e-4));
+                }
+                MediumEvent::Pass { .. } => panic!("an infinite medium let a ray through"),
+            }
+        }
+        let mean = sum / SAMPLES as f32;
+        assert!((mean - 0.5).abs() < 0.01, "mean free path {}", mean);
+
+        // coloured and cut short by a surface: the pass weights average to
+        // the transmittance, the scatter weights to the single scattered
+        // fraction sigma_s / sigma_t (1 - T)
+        let medium = HomogeneousMedium {
+            sigma_a: Vec3::new(0.1, 0.5, 1.),
+            sigma_s: Vec3::new(0.4, 0.5, 1.),
+            g: 0.,
+        };
+        let t_max = 1.;
+        let (mut passed, mut scattered) = (Vec3::zero(), Vec3::zero());
+        for _ in 0..SAMPLES {
+            match medium.sample(&ray(), t_max) {
+                MediumEvent::Scatter { point, weight } => {
+                    assert!(point.z < t_max);
+                    scattered = scattered + weight;
+                }
+                MediumEvent::Pass { weight } => passed = passed + weight,
+            }
+        }
+        let n = SAMPLES as f32;
+        let t = medium.transmittance(t_max);
+        assert!(close(passed / n, t, 0.01), "{} instead of {}", passed / n, t);
+        let sigma_t = medium.sigma_t();
+        let expected = Vec3::new(
+            medium.sigma_s.x / sigma_t.x * (1. - t.x),
+            medium.sigma_s.y / sigma_t.y * (1. - t.y),
+            medium.sigma_s.z / sigma_t.z * (1. - t.z),
+        );
+        assert!(close(scattered / n, expected, 0.01), "{} instead of {}", scattered / n, expected);
+    }
+
+    #[test]
+    fn phase_test() {
+        let dir = Vec3::new(1., 2., -0.5).normalize();
+        for &g in &[-0.7, 0., 0.3, 0.9] {
+            let medium = Medium::Homogeneous(HomogeneousMedium {
+                sigma_a: Vec3::zero(),
+                sigma_s: Vec3::new(1., 1., 1.),
+                g,
+            });
+            // the mean cosine of Henyey-Greenstein is g
+            let mut sum = 0.;
+            for _ in 0..SAMPLES {
+                let scattered = medium.sample_phase(dir);
+                assert!((scattered.length() - 1.).abs() < 1e-4);
+                sum += scattered.dot(dir);
+            }
+            let mean = sum / SAMPLES as f32;
+            assert!((mean - g).abs() < 0.01, "mean cosine {} for g {}", mean, g);
+
+            // and it integrates to one over the sphere
+            let steps = 100000;
+            let integral: f32 = (0..steps)
+                .map(|i| {
+                    let cos_theta = -1. + (i as f32 + 0.5) * 2. / steps as f32;
+                    henyey_greenstein(cos_theta, g) * 2. * PI * 2. / steps as f32
+                })
+                .sum();
+            assert!((integral - 1.).abs() < 1e-3, "integral {} for g {}", integral, g);
+        }
+    }
+
+    #[test]
+    fn grid_tracking_test() {
+        // a constant density over 20x16x24 voxels of 0.1, so 3x2x3 majorant
+        // cells. The spike only touches the corner voxel but raises the
+        // majorant of the whole first cell, where ratio tracking then gets
+        // fractional weights. Emptying the voxels below x = 17 zeroes the
+        // majorants of the first two cells in x, which the walk has to skip
+        // without skipping the density beyond them.
+        let dims = (20, 16, 24);
+        let bounds = Aabb::new(Vec3::new(1., -2., 3.), Vec3::new(3., -0.4, 5.4));
+        let density = 0.5;
+        let sigma_t = 1.5;
+        for case in 0..3 {
+            let mut grid = DensityGrid::dense(dims, vec![density; dims.0 * dims.1 * dims.2]);
+            let mut empty_below = bounds.min.x;
+            if case == 1 {
+                grid.set(0, 0, 0, 5.);
+            }
+            if case == 2 {
+                for z in 0..dims.2 {
+                    for y in 0..dims.1 {
+                        for x in 0..17 {
+                            grid.set(x, y, z, 0.);
+                        }
+                    }
+                }
+                // interpolation ramps up between the voxel centers around
+                // x = 17, which integrates like a step right at it
+                empty_below = bounds.min.x + 1.7;
+            }
+            let medium = GridMedium::new(Arc::new(grid), bounds, sigma_t, Vec3::new(0.8, 0.8, 0.8), 0.);
+            let wrapped = Medium::Grid(medium.clone());
+
+            // diagonally across cells in every axis, from outside the bounds,
+            // away from the spike
+            let ray = Ray::new(Vec3::new(0., -1.7, 3.3), Vec3::new(1., 0.3, 0.5).normalize());
+            let (t_enter, t_exit) = bounds.hit(&ray, ray.inv_dir(), 0., f32::INFINITY).unwrap();
+            let t_dense = (empty_below - ray.origin.x) / ray.dir.x;
+            for &t_max in &[f32::INFINITY, (t_enter + t_exit) / 2., t_dense + 0.3] {
+                let length = (t_exit.min(t_max) - t_enter.max(t_dense)).max(0.);
+                let expected = (-sigma_t * density * length).exp();
+
+                let mut sum = 0.;
+                let mut passed = 0;
+                for _ in 0..SAMPLES {
+                    sum += wrapped.transmittance(&ray, t_max).x;
+                    match wrapped.sample(&ray, t_max) {
+                        MediumEvent::Scatter { point, weight } => {
+                            assert!(bounds.contains(point) && point.x >= empty_below - 0.1);
+                            assert!(close(weight, medium.albedo, 1e-6));
+                        }
+                        MediumEvent::Pass { .. } => passed += 1,
+                    }
+                }
+                let ratio = sum / SAMPLES as f32;
+                let delta = passed as f32 / SAMPLES as f32;
+                assert!((ratio - expected).abs() < 0.01, "ratio tracking {} instead of {}", ratio, expected);
+                assert!((delta - expected).abs() < 0.01, "delta tracking {} instead of {}", delta, expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::f32;
+
+use math::*;
+use core::raytracer::*;
+use core::scene::*;
+use core::bvh::*;
+
+/// Indexed triangle mesh with its own BVH over the triangles. Wrap it in an
+/// `Arc` and reference it from `Instance`s to place it many times; the
+/// triangles and their hierarchy are stored once.
+pub struct Mesh {
+    pub vertices: Vec<Vec3>,
+    /// Per-vertex normals for smooth shading, empty for flat shading. Flat
+    /// normals are `(p1 - p0) x (p2 - p0)`.
+    pub normals: Vec<Vec3>,
+    pub triangles: Vec<[usize; 3]>,
+    pub material: Material,
+    bvh: Bvh,
+}
+
+impl Mesh {
+    pub fn new(vertices: Vec<Vec3>, triangles: Vec<[usize; 3]>, material: Material) -> Mesh {
+        Mesh::with_normals(vertices, Vec::new(), triangles, material)
+    }
+
+    pub fn with_normals(
+        vertices: Vec<Vec3>,
+        normals: Vec<Vec3>,
+        triangles: Vec<[usize; 3]>,
+        material: Material,
+    ) -> Mesh {
+        let bounds: Vec<Aabb> = triangles
+            .iter()
+            .map(|tri| {
+                Aabb::empty()
+                    .grow(vertices[tri[0]])
+                    .grow(vertices[tri[1]])
+                    .grow(vertices[tri[2]])
+            })
+            .collect();
+        Mesh {
+            bvh: Bvh::build(&bounds),
+            vertices,
+            normals,
+            triangles,
+            material,
+        }
+    }
+
+    // Moller-Trumbore
+    fn intersect_triangle(&self, index: usize, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        let tri = self.triangles[index];
+        let (p0, p1, p2) = (self.vertices[tri[0]], self.vertices[tri[1]], self.vertices[tri[2]]);
+        let e1 = p1 - p0;
+        let e2 = p2 - p0;
+        let pvec = ray.dir.cross(e2);
+        let det = e1.dot(pvec);
+        if det.abs() < 1e-8 {
+            return None;
+        }
+        let inv_det = 1. / det;
+        let tvec = ray.origin - p0;
+        let u = tvec.dot(pvec) * inv_det;
+        if !(0. ..=1.).contains(&u) {
+            return None;
+        }
+        let qvec = tvec.cross(e1);
+        let v = ray.dir.dot(qvec) * inv_det;
+        if v < 0. || u + v > 1. {
+            return None;
+        }
+        let t = e2.dot(qvec) * inv_det;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+
+        let normal = if self.normals.is_empty() {
+            e1.cross(e2).normalize()
+        } else {
+            (self.normals[tri[0]] * (1. - u - v) + self.normals[tri[1]] * u + self.normals[tri[2]] * v)
+                .normalize()
+        };
+
+        Some(IntersectionResult {
+            t,
+            n: normal,
+            // meshes have no texture coordinates, report barycentrics instead
+            uv: (u, v),
+            material: self.material.clone(),
+            object: 0,
+        })
+    }
+}
+
+impl SceneObject for Mesh {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        self.bvh.intersect(ray, t_min, t_max, |i, t0, t1| {
+            self.intersect_triangle(i, ray, t0, t1)
+        })
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.bvh.bounds())
+    }
+
+    /// Assumes the mesh is closed, and consistently wound if it is flat
+    /// shaded.
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        // every hit along the line, by never narrowing the search
+        let mut crossings = Vec::new();
+        self.bvh.intersect(ray, f32::NEG_INFINITY, f32::INFINITY, |i, t0, t1| {
+            if let Some(result) = self.intersect_triangle(i, ray, t0, t1) {
+                crossings.push(result);
+            }
+            None
+        });
+        Interval::from_crossings(crossings)
+    }
+}
@@ -0,0 +1,231 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use math::*;
+use core::util::*;
+use core::sampler::*;
+use core::film::*;
+use core::raytracer::*;
+use core::integrator::*;
+
+/// Where one Markov chain is: the camera path its samples trace.
+struct Chain {
+    samples: PrimarySamples,
+    x: f32,
+    y: f32,
+    radiance: Vec3,
+}
+
+/// Markov chains seeded for one view, and the image brightness their
+/// splats are normalised by: the average luminance of all paths.
+struct Chains {
+    brightness: f32,
+    running: Vec<Mutex<Option<Chain>>>,
+}
+
+/// Metropolis light transport in primary sample space (Kelemen et al.).
+/// Markov chains wander through the random numbers of the path tracer,
+/// mutating them a little or, now and then, all anew, and keep a mutation
+/// with a probability that makes them visit paths in proportion to their
+/// brightness. Once a chain found light that gets in through a narrow gap
+/// it stays around and explores it, where independent paths would hardly
+/// ever hit it again.
+///
+/// Every camera sample advances one chain by a mutation and splats the
+/// result wherever on the image the path lands. The chains and the image
+/// brightness they are normalised by come from uniformly sampled paths,
+/// traced by the first sample rendered for a view. Only splats make up the
+/// image, so adaptive sampling has nothing to go by.
+pub struct MetropolisTracer {
+    /// Uniform paths traced to seed the chains.
+    pub bootstrap: usize,
+    pub chains: usize,
+    /// Chance that a mutation picks entirely new samples.
+    pub large_step_probability: f32,
+    /// Standard deviation of the small steps.
+    pub sigma: f32,
+    /// Turns the samples into camera paths.
+    pub paths: PathTracer,
+    /// Set up once, on whichever thread renders first.
+    seeded: Mutex<Option<Arc<Chains>>>,
+    next: AtomicUsize,
+}
+
+impl Default for MetropolisTracer {
+    fn default() -> MetropolisTracer {
+        MetropolisTracer::new()
+    }
+}
+
+impl MetropolisTracer {
+    pub fn new() -> MetropolisTracer {
+        MetropolisTracer {
+            bootstrap: 20000,
+            chains: 64,
+            large_step_probability: 0.3,
+            sigma: 0.01,
+            paths: PathTracer::new(),
+            seeded: Mutex::new(None),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Traces the camera path `samples` stand for.
+    fn evaluate(&self, tracer: &RayTracer, samples: PrimarySamples, path: &mut PathState) -> Chain {
+        let (width, height) = (tracer.dimensions.0 as f32, tracer.dimensions.1 as f32);
+        let ((x, y, radiance, rays), samples) = with_primary_samples(samples, || {
+            let (x, y) = (rnd() * width, rnd() * height);
+            let mut camera_path = PathState::new(false);
+            let radiance = self.paths.radiance(tracer, tracer.ray_at(x, y), &mut camera_path);
+            (x, y, radiance, camera_path.rays)
+        });
+        path.rays += rays;
+        Chain {
+            samples,
+            x,
+            y,
+            radiance,
+        }
+    }
+
+    /// Traces the uniform paths and starts the chains at some of them.
+    fn seed(&self, tracer: &RayTracer, path: &mut PathState) -> Chains {
+        let samples = |seed: usize| PrimarySamples::new(seed as u32, self.large_step_probability, self.sigma);
+        let mut cdf = Vec::with_capacity(self.bootstrap);
+        let mut sum = 0.;
+        for seed in 0..self.bootstrap {
+            sum += importance(self.evaluate(tracer, samples(seed), path).radiance);
+            cdf.push(sum);
+        }
+        let mut running = Vec::new();
+        if sum > 0. {
+            // start the chains at bootstrap paths picked by their
+            // luminance, which replay from the same seed
+            for _ in 0..self.chains {
+                let u = rnd() * sum;
+                let seed = cdf.iter().position(|&c| c > u).unwrap_or(self.bootstrap - 1);
+                running.push(Mutex::new(Some(self.evaluate(tracer, samples(seed), path))));
+            }
+        }
+        Chains {
+            brightness: if self.bootstrap > 0 { sum / self.bootstrap as f32 } else { 0. },
+            running,
+        }
+    }
+
+    /// The chains, seeded first if this is the first sample. Other threads
+    /// wait for that meanwhile.
+    fn seeded_chains(&self, tracer: &RayTracer, path: &mut PathState) -> Arc<Chains> {
+        let mut seeded = self.seeded.lock().unwrap();
+        if seeded.is_none() {
+            *seeded = Some(Arc::new(self.seed(tracer, path)));
+        }
+        seeded.as_ref().unwrap().clone()
+    }
+}
+
+/// What a chain's path is sampled by, its luminance.
+fn importance(radiance: Vec3) -> f32 {
+    let y = luminance(radiance);
+    if y.is_finite() && y > 0. { y } else { 0. }
+}
+
+impl Integrator for MetropolisTracer {
+    fn name(&self) -> &'static str {
+        "mlt"
+    }
+
+    fn splats_only(&self) -> bool {
+        true
+    }
+
+    fn prepare(&self, _tracer: &RayTracer) -> Option<Arc<dyn Integrator>> {
+        // a new view needs new chains, seeded once rendering starts
+        Some(Arc::new(MetropolisTracer {
+            bootstrap: self.bootstrap,
+            chains: self.chains,
+            large_step_probability: self.large_step_probability,
+            sigma: self.sigma,
+            paths: self.paths.clone(),
+            seeded: Mutex::new(None),
+            next: AtomicUsize::new(0),
+        }))
+    }
+
+    fn radiance(&self, tracer: &RayTracer, _ray: Ray, path: &mut PathState) -> Vec3 {
+        // the mutation counts as a light path, so the splats are averaged
+        // over all of them
+        path.light_paths += 1;
+        let chains = self.seeded_chains(tracer, path);
+        if chains.running.is_empty() {
+            return Vec3::zero();
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % chains.running.len();
+        let mut slot = chains.running[index].lock().unwrap();
+        let mut chain = match slot.take() {
+            Some(chain) => chain,
+            None => return Vec3::zero(),
+        };
+        chain.samples.mutate();
+        let mut proposed = self.evaluate(tracer, chain.samples, path);
+
+        let current_importance = importance(chain.radiance);
+        let proposed_importance = importance(proposed.radiance);
+        let accept = if current_importance > 0. {
+            (proposed_importance / current_importance).min(1.)
+        } else {
+            1.
+        };
+        // both paths contribute, weighted by how likely the chain moves
+        // on, which beats splatting only where it ends up
+        if accept > 0. && proposed_importance > 0. {
+            path.splats.push(Splat {
+                x: proposed.x,
+                y: proposed.y,
+                radiance: proposed.radiance * (accept * chains.brightness / proposed_importance),
+            });
+        }
+        if accept < 1. {
+            path.splats.push(Splat {
+                x: chain.x,
+                y: chain.y,
+                radiance: chain.radiance * ((1. - accept) * chains.brightness / current_importance),
+            });
+        }
+        *slot = Some(if rnd() < accept {
+            proposed.samples.accept();
+            proposed
+        } else {
+            proposed.samples.reject();
+            Chain {
+                samples: proposed.samples,
+                ..chain
+            }
+        });
+        Vec3::zero()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::scene::*;
+
+    #[test]
+    fn mlt_replay_test() {
+        let mut scene = Scene::new();
+        scene.add(Box::new(Sphere {
+            origin: Vec3::new(0., 0., 3.),
+            radius: 1.,
+            material: Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+        }));
+        let tracer = RayTracer::new((16, 16), scene);
+        let mlt = MetropolisTracer::new();
+        let mut path = PathState::new(false);
+        for seed in 0..16 {
+            let a = mlt.evaluate(&tracer, PrimarySamples::new(seed, 0.3, 0.01), &mut path);
+            let b = mlt.evaluate(&tracer, PrimarySamples::new(seed, 0.3, 0.01), &mut path);
+            assert!(a.x == b.x && a.y == b.y && a.radiance == b.radiance);
+        }
+    }
+}
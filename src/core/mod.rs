@@ -1,6 +1,53 @@
 pub mod util;
+pub mod sampler;
 pub mod raytracer;
 pub mod scene;
+pub mod instance;
+pub mod bvh;
+pub mod mesh;
+pub mod primitives;
+pub mod csg;
+pub mod sdf;
+pub mod medium;
+pub mod grid;
+pub mod film;
+pub mod tonemap;
+pub mod exr;
+pub mod rgbe;
+pub mod aov;
+pub mod denoise;
+pub mod filter;
+pub mod render;
+pub mod path;
+pub mod light;
+pub mod integrator;
+pub mod bdpt;
+pub mod photon;
+pub mod mlt;
+pub mod spectral;
 
+pub use self::sampler::*;
 pub use self::raytracer::*;
-pub use self::scene::*;
\ No newline at end of file
+pub use self::scene::*;
+pub use self::instance::*;
+pub use self::mesh::*;
+pub use self::primitives::*;
+pub use self::csg::*;
+pub use self::sdf::*;
+pub use self::medium::*;
+pub use self::grid::*;
+pub use self::film::*;
+pub use self::tonemap::*;
+pub use self::exr::*;
+pub use self::rgbe::*;
+pub use self::aov::*;
+pub use self::denoise::*;
+pub use self::filter::*;
+pub use self::render::*;
+pub use self::path::*;
+pub use self::light::*;
+pub use self::integrator::*;
+pub use self::bdpt::*;
+pub use self::photon::*;
+pub use self::mlt::*;
+pub use self::spectral::*;
@@ -0,0 +1,288 @@
+extern crate image;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use math::*;
+use core::raytracer::*;
+
+/// Direction picked by a material, see `Material::scatter`.
+#[derive(Clone, Debug)]
+pub struct BsdfSample {
+    pub direction: Vec3,
+    /// BSDF times cosine over pdf, what the throughput gets multiplied by.
+    pub weight: Vec3,
+    /// Solid angle density of `direction`, `None` for delta distributions
+    /// like mirrors.
+    pub pdf: Option<f32>,
+}
+
+#[derive(Clone)]
+pub enum PathEvent {
+    /// Scattered inside a participating medium. `direction` is `None` when
+    /// the path was cut off at the maximum depth.
+    Medium {
+        point: Vec3,
+        weight: Vec3,
+        /// Light scattered straight from the light sources.
+        direct: Vec3,
+        direction: Option<Vec3>,
+    },
+    /// Hit a surface. `sample` is `None` if the material absorbed the ray or
+    /// the path was cut off at the maximum depth.
+    Surface {
+        t: f32,
+        point: Vec3,
+        normal: Vec3,
+        uv: (f32, f32),
+        object: usize,
+        material: Material,
+        /// Light reflected straight from the light sources.
+        direct: Vec3,
+        sample: Option<BsdfSample>,
+    },
+    /// Left the scene and picked up the sky.
+    Escaped { radiance: Vec3 },
+}
+
+/// One segment of a recorded path and what happened at its end.
+#[derive(Clone)]
+pub struct PathVertex {
+    pub depth: u8,
+    pub origin: Vec3,
+    pub direction: Vec3,
+    /// Product of the weights of all earlier bounces.
+    pub throughput: Vec3,
+    /// Medium transmittance along the segment.
+    pub transmittance: Vec3,
+    pub event: PathEvent,
+}
+
+impl PathVertex {
+    /// Where the segment ends, `None` if it goes on forever.
+    pub fn end(&self) -> Option<Vec3> {
+        match self.event {
+            PathEvent::Medium { point, .. } | PathEvent::Surface { point, .. } => Some(point),
+            PathEvent::Escaped { .. } => None,
+        }
+    }
+}
+
+/// A single camera sample traced with every bounce recorded, see
+/// `RayTracer::trace_path`.
+#[derive(Clone)]
+pub struct RecordedPath {
+    pub pixel: (u32, u32),
+    /// Continuous image position of the sample.
+    pub x: f32,
+    pub y: f32,
+    pub radiance: Vec3,
+    pub vertices: Vec<PathVertex>,
+}
+
+/// Segment colors by depth.
+const PALETTE: [[u8; 3]; 6] = [
+    [255, 255, 0],
+    [0, 255, 255],
+    [255, 0, 255],
+    [255, 128, 0],
+    [0, 255, 0],
+    [255, 255, 255],
+];
+
+/// How far escaping segments are drawn.
+const ESCAPE_LENGTH: f32 = 1000.;
+
+fn json_f32(x: f32) -> String {
+    // JSON has no infinities or NaNs
+    if x.is_finite() { format!("{}", x) } else { "null".to_string() }
+}
+
+fn json_vec3(v: Vec3) -> String {
+    format!("[{}, {}, {}]", json_f32(v.x), json_f32(v.y), json_f32(v.z))
+}
+
+fn json_material(material: &Material) -> String {
+    match *material {
+        Material::Volume(_) => "{\"type\": \"volume\"}".to_string(),
+        _ => format!(
+            "{{\"type\": \"{}\", \"albedo\": {}}}",
+            material.name(),
+            json_vec3(material.albedo())
+        ),
+    }
+}
+
+impl RecordedPath {
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"pixel\": [{}, {}],", self.pixel.0, self.pixel.1)?;
+        writeln!(w, "  \"position\": [{}, {}],", json_f32(self.x), json_f32(self.y))?;
+        writeln!(w, "  \"radiance\": {},", json_vec3(self.radiance))?;
+        writeln!(w, "  \"vertices\": [")?;
+        for (i, v) in self.vertices.iter().enumerate() {
+            let event = match v.event {
+                PathEvent::Medium { point, weight, direct, direction } => format!(
+                    "\"event\": \"medium\", \"point\": {}, \"weight\": {}, \"direct\": {}, \"scattered\": {}",
+                    json_vec3(point),
+                    json_vec3(weight),
+                    json_vec3(direct),
+                    direction.map_or("null".to_string(), json_vec3)
+                ),
+                PathEvent::Surface { t, point, normal, uv, object, ref material, direct, ref sample } => {
+                    let sample = match *sample {
+                        Some(ref s) => format!(
+                            "{{\"direction\": {}, \"weight\": {}, \"pdf\": {}}}",
+                            json_vec3(s.direction),
+                            json_vec3(s.weight),
+                            s.pdf.map_or("null".to_string(), json_f32)
+                        ),
+                        None => "null".to_string(),
+                    };
+                    format!(
+                        "\"event\": \"surface\", \"t\": {}, \"point\": {}, \"normal\": {}, \
+                         \"uv\": [{}, {}], \"object\": {}, \"material\": {}, \"direct\": {}, \"bsdf_sample\": {}",
+                        json_f32(t),
+                        json_vec3(point),
+                        json_vec3(normal),
+                        json_f32(uv.0),
+                        json_f32(uv.1),
+                        object,
+                        json_material(material),
+                        json_vec3(direct),
+                        sample
+                    )
+                }
+                PathEvent::Escaped { radiance } => {
+                    format!("\"event\": \"escaped\", \"radiance\": {}", json_vec3(radiance))
+                }
+            };
+            writeln!(
+                w,
+                "    {{\"depth\": {}, \"origin\": {}, \"direction\": {}, \"throughput\": {}, \
+                 \"transmittance\": {}, {}}}{}",
+                v.depth,
+                json_vec3(v.origin),
+                json_vec3(v.direction),
+                json_vec3(v.throughput),
+                json_vec3(v.transmittance),
+                event,
+                if i + 1 < self.vertices.len() { "," } else { "" }
+            )?;
+        }
+        writeln!(w, "  ]")?;
+        writeln!(w, "}}")
+    }
+
+    pub fn save_json<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_json(&mut w)
+    }
+
+    /// Draws the path segments into `image` as seen by `tracer`'s camera,
+    /// which must match the image size.
+    pub fn draw(&self, image: &mut image::RgbImage, tracer: &RayTracer) {
+        for v in &self.vertices {
+            let color = image::Rgb(PALETTE[v.depth as usize % PALETTE.len()]);
+            let end = v.end().unwrap_or(v.origin + v.direction * ESCAPE_LENGTH);
+            if let Some((a, b)) = tracer.project_segment(v.origin, end) {
+                draw_line(image, a, b, color);
+                if v.end().is_some() {
+                    draw_marker(image, b, color);
+                }
+            }
+        }
+    }
+}
+
+fn put(image: &mut image::RgbImage, x: i64, y: i64, color: image::Rgb<u8>) {
+    if x >= 0 && y >= 0 && x < image.width() as i64 && y < image.height() as i64 {
+        image.put_pixel(x as u32, y as u32, color);
+    }
+}
+
+fn draw_line(image: &mut image::RgbImage, a: (f32, f32), b: (f32, f32), color: image::Rgb<u8>) {
+    // clamp far off points so the step count stays sane
+    let limit = 4. * (image.width() + image.height()) as f32;
+    let clamp = |p: (f32, f32)| (p.0.max(-limit).min(limit), p.1.max(-limit).min(limit));
+    let (a, b) = (clamp(a), clamp(b));
+    let steps = (b.0 - a.0).abs().max((b.1 - a.1).abs()).ceil().max(1.) as u32;
+    for i in 0..steps + 1 {
+        let t = i as f32 / steps as f32;
+        let x = a.0 + (b.0 - a.0) * t;
+        let y = a.1 + (b.1 - a.1) * t;
+        put(image, x.floor() as i64, y.floor() as i64, color);
+    }
+}
+
+fn draw_marker(image: &mut image::RgbImage, p: (f32, f32), color: image::Rgb<u8>) {
+    let (x, y) = (p.0.floor() as i64, p.1.floor() as i64);
+    for dy in -2..3 {
+        for dx in -2..3 {
+            put(image, x + dx, y + dy, color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32;
+
+    #[test]
+    fn json_test() {
+        let one = Vec3::new(1., 1., 1.);
+        let grey = Vec3::new(0.5, 0.5, 0.5);
+        let path = RecordedPath {
+            pixel: (3, 4),
+            x: 3.5,
+            y: 4.25,
+            radiance: Vec3::new(f32::NAN, 1., f32::INFINITY),
+            vertices: vec![
+                PathVertex {
+                    depth: 0,
+                    origin: Vec3::zero(),
+                    direction: Vec3::new(0., 0., 1.),
+                    throughput: one,
+                    transmittance: one,
+                    event: PathEvent::Surface {
+                        t: 2.,
+                        point: Vec3::new(0., 0., 2.),
+                        normal: Vec3::new(0., 0., -1.),
+                        uv: (0.5, 0.25),
+                        object: 1,
+                        material: Material::Metal(grey),
+                        direct: Vec3::zero(),
+                        sample: Some(BsdfSample {
+                            direction: Vec3::new(0., 0., -1.),
+                            weight: grey,
+                            pdf: None,
+                        }),
+                    },
+                },
+                PathVertex {
+                    depth: 1,
+                    origin: Vec3::new(0., 0., 2.),
+                    direction: Vec3::new(0., 0., -1.),
+                    throughput: grey,
+                    transmittance: one,
+                    event: PathEvent::Escaped { radiance: one },
+                },
+            ],
+        };
+        let mut out = Vec::new();
+        path.write_json(&mut out).unwrap();
+        let expected = r#"{
+  "pixel": [3, 4],
+  "position": [3.5, 4.25],
+  "radiance": [null, 1, null],
+  "vertices": [
+    {"depth": 0, "origin": [0, 0, 0], "direction": [0, 0, 1], "throughput": [1, 1, 1], "transmittance": [1, 1, 1], "event": "surface", "t": 2, "point": [0, 0, 2], "normal": [0, 0, -1], "uv": [0.5, 0.25], "object": 1, "material": {"type": "metal", "albedo": [0.5, 0.5, 0.5]}, "direct": [0, 0, 0], "bsdf_sample": {"direction": [0, 0, -1], "weight": [0.5, 0.5, 0.5], "pdf": null}},
+    {"depth": 1, "origin": [0, 0, 2], "direction": [0, 0, -1], "throughput": [0.5, 0.5, 0.5], "transmittance": [1, 1, 1], "event": "escaped", "radiance": [1, 1, 1]}
+  ]
+}
+"#;
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
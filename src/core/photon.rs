@@ -0,0 +1,325 @@
+use std::f32;
+use std::f32::consts::PI;
+use std::sync::Arc;
+use std::thread;
+
+use math::*;
+use core::util::*;
+use core::scene::*;
+use core::medium::*;
+use core::raytracer::*;
+use core::integrator::*;
+
+/// Light that arrived at a surface.
+#[derive(Clone, Copy, Debug)]
+pub struct Photon {
+    pub position: Vec3,
+    /// Direction the photon was travelling in.
+    pub direction: Vec3,
+    /// Normal of the surface it landed on.
+    pub normal: Vec3,
+    /// Flux carried, the share of the light's power the photon stands for.
+    pub power: Vec3,
+}
+
+/// Photons in a balanced kd-tree for nearest neighbour lookups. The tree is
+/// implicit: the median of every range of `photons` splits it along the
+/// axis stored for it, the halves on either side are its subtrees.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> PhotonMap {
+        let mut axes = vec![0; photons.len()];
+        let len = photons.len();
+        build(&mut photons, &mut axes, 0, len);
+        PhotonMap {
+            photons,
+            axes,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Up to `count` photons closest to `p`, not further away than
+    /// `max_distance`, nearest first, with their squared distances.
+    pub fn nearest(&self, p: Vec3, count: usize, max_distance: f32) -> Vec<(f32, &Photon)> {
+        let mut found = Vec::with_capacity(count + 1);
+        let mut max_sq = max_distance * max_distance;
+        self.search(0, self.photons.len(), p, count, &mut found, &mut max_sq);
+        found.into_iter().map(|(d, i)| (d, &self.photons[i])).collect()
+    }
+
+    fn search(&self, lo: usize, hi: usize, p: Vec3, count: usize, found: &mut Vec<(f32, usize)>, max_sq: &mut f32) {
+        if lo >= hi || count == 0 {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        let photon = &self.photons[mid];
+        let axis = self.axes[mid] as usize;
+        let delta = p.axis(axis) - photon.position.axis(axis);
+        let (near, far) = if delta < 0. { ((lo, mid), (mid + 1, hi)) } else { ((mid + 1, hi), (lo, mid)) };
+
+        self.search(near.0, near.1, p, count, found, max_sq);
+        let d = (photon.position - p).square_length();
+        if d < *max_sq {
+            let at = found.iter().position(|&(other, _)| other > d).unwrap_or(found.len());
+            found.insert(at, (d, mid));
+            if found.len() > count {
+                found.pop();
+            }
+            if found.len() == count {
+                // only closer photons matter from now on
+                *max_sq = found[count - 1].0;
+            }
+        }
+        if delta * delta < *max_sq {
+            self.search(far.0, far.1, p, count, found, max_sq);
+        }
+    }
+}
+
+/// Puts the median of `photons[lo..hi]` along its widest axis in the
+/// middle, smaller ones before and larger ones after, and recurses.
+fn build(photons: &mut [Photon], axes: &mut [u8], lo: usize, hi: usize) {
+    if hi - lo <= 1 {
+        return;
+    }
+    let mut bounds = Aabb::empty();
+    for photon in &photons[lo..hi] {
+        bounds = bounds.grow(photon.position);
+    }
+    let axis = bounds.largest_axis();
+    photons[lo..hi].sort_by(|a, b| {
+        a.position.axis(axis).partial_cmp(&b.position.axis(axis)).unwrap_or(::std::cmp::Ordering::Equal)
+    });
+    let mid = (lo + hi) / 2;
+    axes[mid] = axis as u8;
+    build(photons, axes, lo, mid);
+    build(photons, axes, mid + 1, hi);
+}
+
+/// Threads the photons are traced on.
+const PHOTON_THREADS: usize = 4;
+
+/// Shoots `count` photons from the scene's point lights and keeps those
+/// that reach a diffuse surface after one or more mirror or glass bounces,
+/// the light paths a camera path tracer can't find. The work is spread
+/// over several threads.
+pub fn trace_caustic_photons(scene: &Arc<Scene>, count: usize, max_depth: u8) -> PhotonMap {
+    if scene.lights.is_empty() || count == 0 {
+        return PhotonMap::new(Vec::new());
+    }
+    // a point light's power is 4 pi times its intensity
+    let share = 4. * PI * scene.lights.len() as f32 / count as f32;
+    let workers: Vec<_> = (0..PHOTON_THREADS)
+        .map(|i| {
+            let scene = scene.clone();
+            let n = count / PHOTON_THREADS + if i < count % PHOTON_THREADS { 1 } else { 0 };
+            thread::spawn(move || trace_photons(&scene, n, share, max_depth))
+        })
+        .collect();
+    let mut photons = Vec::new();
+    for worker in workers {
+        photons.extend(worker.join().expect("photon thread panicked"));
+    }
+    PhotonMap::new(photons)
+}
+
+/// Caustic photons of `count` light paths, each carrying `share` of a
+/// light's intensity.
+fn trace_photons(scene: &Scene, count: usize, share: f32, max_depth: u8) -> Vec<Photon> {
+    let mut photons = Vec::new();
+    for _ in 0..count {
+        let light = scene.lights[rnd_index(scene.lights.len())];
+        let mut ray = Ray::new(light.position, rnd_unit_vector());
+        let mut power = light.intensity * share;
+        let mut medium = scene.atmosphere.clone();
+        let mut specular = false;
+        let mut min_t = 0.;
+        for _ in 0..max_depth {
+            let result = scene.intersect(ray, min_t);
+            min_t = EPSILON;
+            if let Some(ref m) = medium {
+                let t_max = result.as_ref().map_or(f32::INFINITY, |r| r.t);
+                match m.sample(&ray, t_max) {
+                    // scattered light is found from the camera side
+                    MediumEvent::Scatter { .. } => break,
+                    MediumEvent::Pass { weight } => power = power * weight,
+                }
+            }
+            let result = match result {
+                Some(result) => result,
+                None => break,
+            };
+            let point = ray.point(result.t);
+            match result.material {
+                Material::Lambertian(_) => {
+                    if specular {
+                        photons.push(Photon {
+                            position: point,
+                            direction: ray.dir,
+                            normal: result.n,
+                            power,
+                        });
+                    }
+                    break;
+                }
+                Material::Volume(ref m) => {
+                    medium = if ray.dir.dot(result.n) < 0. { Some(m.clone()) } else { scene.atmosphere.clone() };
+                    ray = Ray::new(point, ray.dir);
+                }
+                _ => match result.material.scatter(&ray, &result) {
+                    Some((scattered, attenuation)) => {
+                        power = power * attenuation;
+                        specular = true;
+                        ray = scattered;
+                    }
+                    None => break,
+                },
+            }
+        }
+    }
+    photons
+}
+
+/// Caustic photon map and how to estimate radiance from it.
+pub struct Caustics {
+    pub map: PhotonMap,
+    /// Photons per estimate.
+    pub gather: usize,
+    /// Largest distance searched for them.
+    pub radius: f32,
+}
+
+impl Caustics {
+    /// Caustic light a diffuse surface at `p` reflects to the side `n`
+    /// points to.
+    pub fn radiance(&self, p: Vec3, n: Vec3, albedo: Vec3) -> Vec3 {
+        let found = self.map.nearest(p, self.gather, self.radius);
+        if found.is_empty() {
+            return Vec3::zero();
+        }
+        // the disk holding the photons, all of the search radius if it
+        // wasn't filled up
+        let r_sq = if found.len() == self.gather { found[found.len() - 1].0 } else { self.radius * self.radius };
+        let mut flux = Vec3::zero();
+        for &(_, photon) in &found {
+            // skip photons from behind and those on other surfaces close by
+            if photon.direction.dot(n) < 0. && photon.normal.dot(n) > 0.9 {
+                flux = flux + photon.power;
+            }
+        }
+        flux * albedo / (PI * PI * r_sq)
+    }
+}
+
+/// Path tracer plus a caustic photon map, for light focused onto diffuse
+/// surfaces by mirrors and glass. The photons are shot once, when a
+/// `RayTracer` is first set up with it, so the caustics stay as blurry as the
+/// photon count makes them however long the image refines.
+pub struct PhotonMapper {
+    /// Photons shot from the lights; only caustic ones are kept.
+    pub photons: usize,
+    pub gather: usize,
+    pub radius: f32,
+    /// Traces the camera paths, with the caustics filled in once prepared.
+    pub paths: PathTracer,
+}
+
+impl Default for PhotonMapper {
+    fn default() -> PhotonMapper {
+        PhotonMapper::new()
+    }
+}
+
+impl PhotonMapper {
+    pub fn new() -> PhotonMapper {
+        PhotonMapper {
+            photons: 2000000,
+            gather: 64,
+            radius: 0.25,
+            paths: PathTracer::new(),
+        }
+    }
+}
+
+impl Integrator for PhotonMapper {
+    fn name(&self) -> &'static str {
+        "photon"
+    }
+
+    fn prepare(&self, tracer: &RayTracer) -> Option<Arc<dyn Integrator>> {
+        // the photons don't depend on the view
+        if self.paths.caustics.is_some() {
+            return None;
+        }
+        let caustics = Caustics {
+            map: trace_caustic_photons(&tracer.scene, self.photons, self.paths.max_depth),
+            gather: self.gather,
+            radius: self.radius,
+        };
+        Some(Arc::new(PhotonMapper {
+            photons: self.photons,
+            gather: self.gather,
+            radius: self.radius,
+            paths: PathTracer {
+                caustics: Some(Arc::new(caustics)),
+                ..self.paths.clone()
+            },
+        }))
+    }
+
+    fn radiance(&self, tracer: &RayTracer, ray: Ray, path: &mut PathState) -> Vec3 {
+        self.paths.radiance(tracer, ray, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rnd_point() -> Vec3 {
+        Vec3::new(rnd(), rnd(), rnd())
+    }
+
+    #[test]
+    fn photon_map_test() {
+        let photons: Vec<Photon> = (0..1000)
+            .map(|_| Photon {
+                position: rnd_point(),
+                direction: Vec3::new(0., -1., 0.),
+                normal: Vec3::new(0., 1., 0.),
+                power: Vec3::new(1., 1., 1.),
+            })
+            .collect();
+        let map = PhotonMap::new(photons.clone());
+        assert!(map.len() == photons.len());
+        for _ in 0..100 {
+            let p = rnd_point();
+            for &(count, max_distance) in &[(1, f32::INFINITY), (16, f32::INFINITY), (16, 0.1), (2000, 0.3)] {
+                let mut expected: Vec<f32> = photons
+                    .iter()
+                    .map(|photon| (photon.position - p).square_length())
+                    .filter(|&d| d < max_distance * max_distance)
+                    .collect();
+                expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                expected.truncate(count);
+                let found = map.nearest(p, count, max_distance);
+                let distances: Vec<f32> = found.iter().map(|&(d, _)| d).collect();
+                assert!(distances == expected);
+                for &(d, photon) in &found {
+                    assert!((photon.position - p).square_length() == d);
+                }
+            }
+        }
+    }
+}
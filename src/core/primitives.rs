@@ -0,0 +1,710 @@
+//! Analytic primitives besides `Sphere`. Shapes with an axis are aligned
+//! with +y around `origin`; wrap them in an `Instance` for any other
+//! orientation.
+
+use std::f32;
+use std::f32::consts::PI;
+
+use math::*;
+use math::poly;
+use core::raytracer::*;
+use core::scene::*;
+
+fn in_range(t: f32, t_min: f32, t_max: f32) -> bool {
+    t > t_min && t < t_max
+}
+
+fn hit(t: f32, n: Vec3, uv: (f32, f32), material: &Material) -> Option<IntersectionResult> {
+    Some(IntersectionResult {
+        t,
+        n,
+        uv,
+        material: material.clone(),
+        object: 0,
+    })
+}
+
+fn crossing_intervals(crossings: Vec<(f32, Vec3, (f32, f32))>, material: &Material) -> Vec<Interval> {
+    Interval::from_crossings(
+        crossings
+            .into_iter()
+            .map(|(t, n, uv)| IntersectionResult {
+                t,
+                n,
+                uv,
+                material: material.clone(),
+                object: 0,
+            })
+            .collect(),
+    )
+}
+
+// rays parallel to a cap or cone side can yield NaN or infinite t, which
+// are no crossings at all and must not reach the sort
+fn sorted_crossings(mut crossings: Vec<(f32, Vec3, (f32, f32))>) -> Vec<(f32, Vec3, (f32, f32))> {
+    crossings.retain(|c| c.0.is_finite());
+    crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+    crossings
+}
+
+// thin surfaces have no inside, so their normal is turned towards the ray
+fn facing(n: Vec3, ray: &Ray) -> Vec3 {
+    if n.dot(ray.dir) > 0. { -n } else { n }
+}
+
+// flat shapes get zero-thickness boxes, pad them so slab tests stay robust
+fn padded(b: Aabb) -> Aabb {
+    let pad = Vec3::new(1e-4, 1e-4, 1e-4);
+    Aabb::new(b.min - pad, b.max + pad)
+}
+
+/// Plane through `origin`. Infinite when `size` is `None`, otherwise a
+/// `(width, height)` rectangle centered on `origin` and spanned by the
+/// tangents from `normal.orthonormal_basis()`. UVs of infinite planes are
+/// the tangent coordinates, finite ones map the rectangle to [0, 1].
+pub struct Plane {
+    pub origin: Vec3,
+    pub normal: Vec3,
+    pub size: Option<(f32, f32)>,
+    pub material: Material,
+}
+
+impl SceneObject for Plane {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        let n = self.normal.normalize();
+        let denom = n.dot(ray.dir);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+        let t = (self.origin - ray.origin).dot(n) / denom;
+        if !in_range(t, t_min, t_max) {
+            return None;
+        }
+        let (tu, tv) = n.orthonormal_basis();
+        let local = ray.point(t) - self.origin;
+        let (x, y) = (local.dot(tu), local.dot(tv));
+        let uv = match self.size {
+            Some((w, h)) => {
+                if x.abs() > w * 0.5 || y.abs() > h * 0.5 {
+                    return None;
+                }
+                (x / w + 0.5, y / h + 0.5)
+            }
+            None => (x, y),
+        };
+        hit(t, facing(n, ray), uv, &self.material)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        self.size.map(|(w, h)| {
+            let (tu, tv) = self.normal.normalize().orthonormal_basis();
+            let (du, dv) = (tu * (w * 0.5), tv * (h * 0.5));
+            padded(
+                Aabb::empty()
+                    .grow(self.origin + du + dv)
+                    .grow(self.origin + du - dv)
+                    .grow(self.origin - du + dv)
+                    .grow(self.origin - du - dv),
+            )
+        })
+    }
+}
+
+/// Flat disk. UVs are polar: angle and radius, both in [0, 1].
+pub struct Disk {
+    pub origin: Vec3,
+    pub normal: Vec3,
+    pub radius: f32,
+    pub material: Material,
+}
+
+impl SceneObject for Disk {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        let n = self.normal.normalize();
+        let denom = n.dot(ray.dir);
+        if denom.abs() < 1e-8 {
+            return None;
+        }
+        let t = (self.origin - ray.origin).dot(n) / denom;
+        if !in_range(t, t_min, t_max) {
+            return None;
+        }
+        let local = ray.point(t) - self.origin;
+        let r = local.length();
+        if r > self.radius {
+            return None;
+        }
+        let (tu, tv) = n.orthonormal_basis();
+        let angle = local.dot(tv).atan2(local.dot(tu));
+        let uv = (angle / (2. * PI) + 0.5, r / self.radius);
+        hit(t, facing(n, ray), uv, &self.material)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let n = self.normal.normalize();
+        // extent of a circle along each axis
+        let e = Vec3::new(
+            (1. - n.x * n.x).max(0.).sqrt(),
+            (1. - n.y * n.y).max(0.).sqrt(),
+            (1. - n.z * n.z).max(0.).sqrt(),
+        ) * self.radius;
+        Some(padded(Aabb::new(self.origin - e, self.origin + e)))
+    }
+}
+
+/// Axis-aligned box, intersected with the slab test. UVs span each face.
+pub struct AxisBox {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub material: Material,
+}
+
+impl AxisBox {
+    pub fn aabb(&self) -> Aabb {
+        Aabb::new(self.min, self.max)
+    }
+
+    /// Outward normal and face UVs at a point on the surface.
+    pub fn surface(&self, p: Vec3) -> (Vec3, (f32, f32)) {
+        let b = self.aabb();
+        let half = b.extent() * 0.5;
+        let local = p - b.centroid();
+        // the face is on the axis where the point is relatively farthest out
+        let rel = Vec3::new(local.x / half.x, local.y / half.y, local.z / half.z);
+        let axis = if rel.x.abs() > rel.y.abs() && rel.x.abs() > rel.z.abs() {
+            0
+        } else if rel.y.abs() > rel.z.abs() {
+            1
+        } else {
+            2
+        };
+        let sign = if rel.axis(axis) > 0. { 1. } else { -1. };
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let uv = (rel.axis(u) * 0.5 + 0.5, rel.axis(v) * 0.5 + 0.5);
+        let n = match axis {
+            0 => Vec3::new(sign, 0., 0.),
+            1 => Vec3::new(0., sign, 0.),
+            _ => Vec3::new(0., 0., sign),
+        };
+        (n, uv)
+    }
+}
+
+impl SceneObject for AxisBox {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        let (t0, t1) = self.aabb().hit(ray, ray.inv_dir(), f32::NEG_INFINITY, f32::INFINITY)?;
+        let t = if in_range(t0, t_min, t_max) {
+            t0
+        } else if in_range(t1, t_min, t_max) {
+            t1
+        } else {
+            return None;
+        };
+        let (n, uv) = self.surface(ray.point(t));
+        hit(t, n, uv, &self.material)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.aabb())
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        match self.aabb().hit(ray, ray.inv_dir(), f32::NEG_INFINITY, f32::INFINITY) {
+            Some((t0, t1)) => {
+                let surface = |t: f32| {
+                    let (n, uv) = self.surface(ray.point(t));
+                    IntersectionResult {
+                        t,
+                        n,
+                        uv,
+                        material: self.material.clone(),
+                        object: 0,
+                    }
+                };
+                vec![Interval {
+                    enter: surface(t0),
+                    exit: surface(t1),
+                }]
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Capped cylinder along +y with its base at `origin`. Side UVs are angle
+/// and height, cap UVs are planar.
+pub struct Cylinder {
+    pub origin: Vec3,
+    pub radius: f32,
+    pub height: f32,
+    pub material: Material,
+}
+
+impl Cylinder {
+    /// All surface crossings of the ray, ascending, with normals and UVs.
+    pub fn crossings(&self, ray: &Ray) -> Vec<(f32, Vec3, (f32, f32))> {
+        let o = ray.origin - self.origin;
+        let d = ray.dir;
+        let mut result = Vec::with_capacity(4);
+
+        let a = d.x * d.x + d.z * d.z;
+        let b = 2. * (o.x * d.x + o.z * d.z);
+        let c = o.x * o.x + o.z * o.z - self.radius * self.radius;
+        for t in poly::solve_quadratic(a as f64, b as f64, c as f64) {
+            let t = t as f32;
+            let p = o + d * t;
+            if p.y >= 0. && p.y <= self.height {
+                let n = Vec3::new(p.x, 0., p.z) / self.radius;
+                let uv = (p.z.atan2(p.x) / (2. * PI) + 0.5, p.y / self.height);
+                result.push((t, n, uv));
+            }
+        }
+
+        if d.y.abs() > 1e-8 {
+            for &(y, sign) in &[(0., -1.), (self.height, 1.)] {
+                let t = (y - o.y) / d.y;
+                let p = o + d * t;
+                if p.x * p.x + p.z * p.z <= self.radius * self.radius {
+                    let uv = (p.x / (2. * self.radius) + 0.5, p.z / (2. * self.radius) + 0.5);
+                    result.push((t, Vec3::new(0., sign, 0.), uv));
+                }
+            }
+        }
+
+        sorted_crossings(result)
+    }
+}
+
+impl SceneObject for Cylinder {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        self.crossings(ray)
+            .into_iter()
+            .find(|c| in_range(c.0, t_min, t_max))
+            .and_then(|(t, n, uv)| hit(t, n, uv, &self.material))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let r = self.radius;
+        Some(Aabb::new(
+            self.origin - Vec3::new(r, 0., r),
+            self.origin + Vec3::new(r, self.height, r),
+        ))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        crossing_intervals(self.crossings(ray), &self.material)
+    }
+}
+
+/// Cone along +y with its base disk at `origin` and apex `height` above it.
+/// Side UVs are angle and height, base UVs are planar.
+pub struct Cone {
+    pub origin: Vec3,
+    pub radius: f32,
+    pub height: f32,
+    pub material: Material,
+}
+
+impl Cone {
+    /// All surface crossings of the ray, ascending, with normals and UVs.
+    pub fn crossings(&self, ray: &Ray) -> Vec<(f32, Vec3, (f32, f32))> {
+        let o = ray.origin - self.origin;
+        let d = ray.dir;
+        let k = self.radius / self.height;
+        let k2 = k * k;
+        let mut result = Vec::with_capacity(3);
+
+        // x^2 + z^2 = k^2 (height - y)^2
+        let h = self.height - o.y;
+        let a = d.x * d.x + d.z * d.z - k2 * d.y * d.y;
+        let b = 2. * (o.x * d.x + o.z * d.z + k2 * h * d.y);
+        let c = o.x * o.x + o.z * o.z - k2 * h * h;
+        for t in poly::solve_quadratic(a as f64, b as f64, c as f64) {
+            let t = t as f32;
+            let p = o + d * t;
+            if p.y >= 0. && p.y <= self.height {
+                let n = Vec3::new(p.x, k2 * (self.height - p.y), p.z).normalize();
+                let uv = (p.z.atan2(p.x) / (2. * PI) + 0.5, p.y / self.height);
+                result.push((t, n, uv));
+            }
+        }
+
+        if d.y.abs() > 1e-8 {
+            let t = -o.y / d.y;
+            let p = o + d * t;
+            if p.x * p.x + p.z * p.z <= self.radius * self.radius {
+                let uv = (p.x / (2. * self.radius) + 0.5, p.z / (2. * self.radius) + 0.5);
+                result.push((t, Vec3::new(0., -1., 0.), uv));
+            }
+        }
+
+        sorted_crossings(result)
+    }
+}
+
+impl SceneObject for Cone {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        self.crossings(ray)
+            .into_iter()
+            .find(|c| in_range(c.0, t_min, t_max))
+            .and_then(|(t, n, uv)| hit(t, n, uv, &self.material))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let r = self.radius;
+        Some(Aabb::new(
+            self.origin - Vec3::new(r, 0., r),
+            self.origin + Vec3::new(r, self.height, r),
+        ))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        crossing_intervals(self.crossings(ray), &self.material)
+    }
+}
+
+/// Torus around the y axis through `origin`. `major_radius` is the distance
+/// from the center to the middle of the tube, `minor_radius` the tube
+/// radius. UVs are the angle around the axis and the angle around the tube.
+pub struct Torus {
+    pub origin: Vec3,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    pub material: Material,
+}
+
+/// Newton steps on the implicit torus along a ray. The expanded quartic
+/// loses digits for grazing rays, the implicit form doesn't. Like in
+/// `poly::solve_quartic` only steps that improve the residual are kept.
+fn polish(o: (f64, f64, f64), d: (f64, f64, f64), big_r2: f64, small_r2: f64, mut t: f64) -> f64 {
+    // (|p|^2 + R^2 - r^2)^2 - 4 R^2 (x^2 + z^2) and its derivative in t
+    let f = |t: f64| {
+        let (x, y, z) = (o.0 + d.0 * t, o.1 + d.1 * t, o.2 + d.2 * t);
+        let s = x * x + y * y + z * z + big_r2 - small_r2;
+        let f = s * s - 4. * big_r2 * (x * x + z * z);
+        let df = 4. * s * (x * d.0 + y * d.1 + z * d.2) - 8. * big_r2 * (x * d.0 + z * d.2);
+        (f, df)
+    };
+    for _ in 0..3 {
+        let (ft, df) = f(t);
+        let next = t - ft / df;
+        if f(next).0.abs() < ft.abs() {
+            t = next;
+        }
+    }
+    t
+}
+
+impl Torus {
+    /// All surface crossings of the ray, ascending, with normals and UVs.
+    pub fn crossings(&self, ray: &Ray) -> Vec<(f32, Vec3, (f32, f32))> {
+        let bounds = Aabb::new(Vec3::zero() - self.extent(), self.extent());
+        let o = ray.origin - self.origin;
+        // start the ray at the bounding box: the quartic is far better
+        // conditioned for small t
+        let t_start = match bounds.hit(&Ray::new(o, ray.dir), ray.inv_dir(), f32::NEG_INFINITY, f32::INFINITY) {
+            Some((t0, _)) => t0.max(0.),
+            None => return vec![],
+        };
+        let o = o + ray.dir * t_start;
+
+        // solve for a torus of outer radius one along a unit direction, the
+        // solver's tolerances are absolute
+        let scale = 1. / (self.major_radius + self.minor_radius) as f64;
+        let length = ray.dir.length() as f64;
+        let (ox, oy, oz) = (o.x as f64 * scale, o.y as f64 * scale, o.z as f64 * scale);
+        let (dx, dy, dz) = (ray.dir.x as f64 / length, ray.dir.y as f64 / length, ray.dir.z as f64 / length);
+        let big_r2 = (self.major_radius as f64 * scale).powi(2);
+        let small_r2 = (self.minor_radius as f64 * scale).powi(2);
+
+        let e = ox * ox + oy * oy + oz * oz - big_r2 - small_r2;
+        let f = ox * dx + oy * dy + oz * dz;
+        let four_r2 = 4. * big_r2;
+
+        let roots = poly::solve_quartic(
+            1.,
+            4. * f,
+            2. * e + 4. * f * f + four_r2 * dy * dy,
+            4. * f * e + 2. * four_r2 * oy * dy,
+            e * e - four_r2 * (small_r2 - oy * oy),
+        );
+
+        let crossings = roots
+            .into_iter()
+            .map(|t| {
+                let t = polish((ox, oy, oz), (dx, dy, dz), big_r2, small_r2, t);
+                let t = (t / (scale * length)) as f32;
+                let p = o + ray.dir * t;
+                (t + t_start, self.normal(p), self.uv(p))
+            })
+            .collect();
+        sorted_crossings(crossings)
+    }
+
+    fn extent(&self) -> Vec3 {
+        let r = self.major_radius + self.minor_radius;
+        Vec3::new(r, self.minor_radius, r)
+    }
+
+    fn normal(&self, p: Vec3) -> Vec3 {
+        let s = p.square_length() - self.major_radius * self.major_radius -
+            self.minor_radius * self.minor_radius;
+        Vec3::new(
+            p.x * s,
+            p.y * (s + 2. * self.major_radius * self.major_radius),
+            p.z * s,
+        ).normalize()
+    }
+
+    fn uv(&self, p: Vec3) -> (f32, f32) {
+        let u = p.z.atan2(p.x);
+        let ring = (p.x * p.x + p.z * p.z).sqrt() - self.major_radius;
+        let v = p.y.atan2(ring);
+        (u / (2. * PI) + 0.5, v / (2. * PI) + 0.5)
+    }
+}
+
+impl SceneObject for Torus {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        self.crossings(ray)
+            .into_iter()
+            .find(|c| in_range(c.0, t_min, t_max))
+            .and_then(|(t, n, uv)| hit(t, n, uv, &self.material))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(Aabb::new(self.origin - self.extent(), self.origin + self.extent()))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        crossing_intervals(self.crossings(ray), &self.material)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-4
+    }
+
+    fn close_uv(a: (f32, f32), b: (f32, f32)) -> bool {
+        (a.0 - b.0).abs() < 1e-4 && (a.1 - b.1).abs() < 1e-4
+    }
+
+    fn material() -> Material {
+        Material::Lambertian(Vec3::new(0.5, 0.5, 0.5))
+    }
+
+    fn first_hit(object: &dyn SceneObject, origin: Vec3, dir: Vec3) -> IntersectionResult {
+        object.intersect(&Ray::new(origin, dir), 0., f32::INFINITY).unwrap()
+    }
+
+    #[test]
+    fn plane_test() {
+        let plane = Plane {
+            origin: Vec3::new(0., 0., 5.),
+            normal: Vec3::new(0., 0., -1.),
+            size: Some((2., 4.)),
+            material: material(),
+        };
+        let (tu, tv) = Vec3::new(0., 0., -1.).orthonormal_basis();
+
+        let hit = first_hit(&plane, Vec3::zero(), Vec3::new(0., 0., 1.));
+        assert!((hit.t - 5.).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(0., 0., -1.)));
+        assert!(close_uv(hit.uv, (0.5, 0.5)));
+        let hit = first_hit(&plane, tu * 0.5 + tv * -1., Vec3::new(0., 0., 1.));
+        assert!(close_uv(hit.uv, (0.75, 0.25)));
+        // the normal faces rays from behind
+        let hit = first_hit(&plane, Vec3::new(0., 0., 10.), Vec3::new(0., 0., -1.));
+        assert!(close(hit.n, Vec3::new(0., 0., 1.)));
+        // outside the rectangle
+        assert!(plane.intersect(&Ray::new(tu * 1.1, Vec3::new(0., 0., 1.)), 0., f32::INFINITY).is_none());
+        assert!(plane.intersect(&Ray::new(tv * 2.1, Vec3::new(0., 0., 1.)), 0., f32::INFINITY).is_none());
+
+        let bounds = plane.bounds().unwrap();
+        for &(u, v) in &[(1., 2.), (1., -2.), (-1., 2.), (-1., -2.)] {
+            assert!(bounds.contains(Vec3::new(0., 0., 5.) + tu * u + tv * v));
+        }
+        assert!(bounds.extent().z < 1e-3);
+
+        let infinite = Plane { size: None, ..plane };
+        assert!(infinite.bounds().is_none());
+        let hit = first_hit(&infinite, tu * 100. + tv * -3., Vec3::new(0., 0., 1.));
+        assert!(close_uv(hit.uv, (100., -3.)));
+    }
+
+    #[test]
+    fn disk_test() {
+        let disk = Disk {
+            origin: Vec3::zero(),
+            normal: Vec3::new(0., 1., 0.),
+            radius: 2.,
+            material: material(),
+        };
+        let hit = first_hit(&disk, Vec3::new(1., 5., 0.), Vec3::new(0., -1., 0.));
+        assert!((hit.t - 5.).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(0., 1., 0.)));
+        assert!((hit.uv.1 - 0.5).abs() < 1e-4);
+        let hit = first_hit(&disk, Vec3::new(1., -5., 0.), Vec3::new(0., 1., 0.));
+        assert!(close(hit.n, Vec3::new(0., -1., 0.)));
+        assert!(disk.intersect(&Ray::new(Vec3::new(2.1, 5., 0.), Vec3::new(0., -1., 0.)), 0., f32::INFINITY).is_none());
+
+        let bounds = disk.bounds().unwrap();
+        assert!(close(bounds.extent(), Vec3::new(4., 0., 4.) + Vec3::new(2e-4, 2e-4, 2e-4)));
+    }
+
+    #[test]
+    fn box_test() {
+        let b = AxisBox {
+            min: Vec3::new(-1., -2., -3.),
+            max: Vec3::new(1., 2., 3.),
+            material: material(),
+        };
+        let hit = first_hit(&b, Vec3::new(-5., 0., 0.), Vec3::new(1., 0., 0.));
+        assert!((hit.t - 4.).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(-1., 0., 0.)));
+        assert!(close_uv(hit.uv, (0.5, 0.5)));
+        let hit = first_hit(&b, Vec3::new(-5., 1., 1.5), Vec3::new(1., 0., 0.));
+        assert!(close_uv(hit.uv, (0.75, 0.75)));
+        let hit = first_hit(&b, Vec3::new(0., 0., 10.), Vec3::new(0., 0., -1.));
+        assert!((hit.t - 7.).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(0., 0., 1.)));
+        // from inside the exit is hit, with the outward normal
+        let hit = first_hit(&b, Vec3::zero(), Vec3::new(1., 0., 0.));
+        assert!((hit.t - 1.).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(1., 0., 0.)));
+
+        let bounds = b.bounds().unwrap();
+        assert!(close(bounds.min, b.min) && close(bounds.max, b.max));
+    }
+
+    #[test]
+    fn cylinder_test() {
+        let cylinder = Cylinder {
+            origin: Vec3::zero(),
+            radius: 1.,
+            height: 2.,
+            material: material(),
+        };
+        let hit = first_hit(&cylinder, Vec3::new(0., 1., -5.), Vec3::new(0., 0., 1.));
+        assert!((hit.t - 4.).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(0., 0., -1.)));
+        assert!(close_uv(hit.uv, (0.25, 0.5)));
+        let hit = first_hit(&cylinder, Vec3::new(0.5, 5., 0.), Vec3::new(0., -1., 0.));
+        assert!((hit.t - 3.).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(0., 1., 0.)));
+        assert!(close_uv(hit.uv, (0.75, 0.5)));
+        let hit = first_hit(&cylinder, Vec3::new(0.5, -5., 0.), Vec3::new(0., 1., 0.));
+        assert!((hit.t - 5.).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(0., -1., 0.)));
+        // above the top cap
+        assert!(cylinder.intersect(&Ray::new(Vec3::new(0., 2.1, -5.), Vec3::new(0., 0., 1.)), 0., f32::INFINITY).is_none());
+
+        let intervals = cylinder.intervals(&Ray::new(Vec3::new(0., 1., -5.), Vec3::new(0., 0., 1.)));
+        assert_eq!(intervals.len(), 1);
+        assert!((intervals[0].exit.t - 6.).abs() < 1e-4);
+
+        let bounds = cylinder.bounds().unwrap();
+        assert!(close(bounds.min, Vec3::new(-1., 0., -1.)) && close(bounds.max, Vec3::new(1., 2., 1.)));
+    }
+
+    #[test]
+    fn cone_test() {
+        let cone = Cone {
+            origin: Vec3::zero(),
+            radius: 1.,
+            height: 2.,
+            material: material(),
+        };
+        // the radius is 0.5 halfway up
+        let hit = first_hit(&cone, Vec3::new(0., 1., -5.), Vec3::new(0., 0., 1.));
+        assert!((hit.t - 4.5).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(0., 0.5, -1.).normalize()));
+        assert!(close_uv(hit.uv, (0.25, 0.5)));
+        let hit = first_hit(&cone, Vec3::new(0.5, -5., 0.), Vec3::new(0., 1., 0.));
+        assert!((hit.t - 5.).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(0., -1., 0.)));
+        assert!(close_uv(hit.uv, (0.75, 0.5)));
+        // straight down onto the side next to the apex
+        let hit = first_hit(&cone, Vec3::new(0.25, 5., 0.), Vec3::new(0., -1., 0.));
+        assert!((hit.t - 3.5).abs() < 1e-4);
+
+        let bounds = cone.bounds().unwrap();
+        assert!(close(bounds.min, Vec3::new(-1., 0., -1.)) && close(bounds.max, Vec3::new(1., 2., 1.)));
+    }
+
+    #[test]
+    fn torus_test() {
+        let torus = Torus {
+            origin: Vec3::zero(),
+            major_radius: 2.,
+            minor_radius: 0.5,
+            material: material(),
+        };
+        let ray = Ray::new(Vec3::new(0., 0., -5.), Vec3::new(0., 0., 1.));
+        let crossings = torus.crossings(&ray);
+        assert_eq!(crossings.len(), 4);
+        for (c, &t) in crossings.iter().zip(&[2.5, 3.5, 6.5, 7.5]) {
+            assert!((c.0 - t).abs() < 1e-4, "t {} instead of {}", c.0, t);
+        }
+        let hit = torus.intersect(&ray, 0., f32::INFINITY).unwrap();
+        assert!(close(hit.n, Vec3::new(0., 0., -1.)));
+        assert!(close_uv(hit.uv, (0.25, 0.5)));
+        let hit = first_hit(&torus, Vec3::new(2., 5., 0.), Vec3::new(0., -1., 0.));
+        assert!((hit.t - 4.5).abs() < 1e-4);
+        assert!(close(hit.n, Vec3::new(0., 1., 0.)));
+        assert!(close_uv(hit.uv, (0.5, 0.75)));
+        // through the hole
+        assert!(torus.intersect(&Ray::new(Vec3::new(0., 5., 0.), Vec3::new(0., -1., 0.)), 0., f32::INFINITY).is_none());
+
+        let bounds = torus.bounds().unwrap();
+        assert!(close(bounds.min, Vec3::new(-2.5, -0.5, -2.5)) && close(bounds.max, Vec3::new(2.5, 0.5, 2.5)));
+    }
+
+    #[test]
+    fn torus_grazing_test() {
+        // rays tangent to the outer half of the tube, moved just inside, must
+        // hit and land on the surface, whatever the size of the torus
+        for &size in &[0.02, 2., 200.] {
+            let (big, small) = (size, size / 4.);
+            let torus = Torus {
+                origin: Vec3::new(1., 2., 3.) * size,
+                major_radius: big,
+                minor_radius: small,
+                material: material(),
+            };
+            for i in 0..500 {
+                let (u, v) = (i as f32 * 0.37, (i as f32 * 0.61).sin() * 1.4);
+                let n = Vec3::new(v.cos() * u.cos(), v.sin(), v.cos() * u.sin());
+                let p = Vec3::new(u.cos(), 0., u.sin()) * big + n * small;
+                let w = Vec3::new((i as f32 * 1.3).cos(), (i as f32 * 0.9).sin(), 0.5);
+                let dir = (w - n * w.dot(n)).normalize();
+                let ray = Ray::new(torus.origin + p - n * (small * 1e-3) - dir * (3. * size), dir);
+                let hit = torus.intersect(&ray, 0., f32::INFINITY);
+                assert!(hit.is_some(), "size {} missed at {}", size, p);
+                let q = ray.point(hit.unwrap().t) - torus.origin;
+                let ring = (q.x * q.x + q.z * q.z).sqrt() - big;
+                let distance = (ring * ring + q.y * q.y).sqrt() - small;
+                assert!(distance.abs() < 1e-3 * size, "size {}: {} off the surface at {}", size, distance, p);
+            }
+        }
+    }
+
+    #[test]
+    fn non_finite_test() {
+        let dir = Vec3::new(f32::NAN, 1., 0.);
+        let origin = Vec3::new(0., -5., 0.);
+        let cylinder = Cylinder { origin: Vec3::zero(), radius: 1., height: 2., material: material() };
+        let cone = Cone { origin: Vec3::zero(), radius: 1., height: 2., material: material() };
+        let torus = Torus { origin: Vec3::zero(), major_radius: 2., minor_radius: 0.5, material: material() };
+        for object in &[&cylinder as &dyn SceneObject, &cone, &torus] {
+            assert!(object.intersect(&Ray::new(origin, dir), 0., f32::INFINITY).is_none());
+        }
+        assert!(cylinder.crossings(&Ray::new(origin, dir)).is_empty());
+        assert!(cone.crossings(&Ray::new(origin, dir)).is_empty());
+        assert!(torus.crossings(&Ray::new(origin, dir)).is_empty());
+    }
+}
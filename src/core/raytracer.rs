@@ -1,20 +1,26 @@
-extern crate image;
-extern crate rand;
-
-use image::GenericImage;
-use rand::Rng;
+use std::f32::consts::PI;
+use std::sync::Arc;
 
 use math::*;
-use util::*;
-use scene::*;
-
-const SAMPLES_PER_PIXEL: u32 = 1;
-const MAX_DEPTH: u8 = 40;
+use core::util::*;
+use core::scene::*;
+use core::medium::*;
+use core::spectral::*;
+use core::path::*;
+use core::integrator::*;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum Material {
     Lambertian(Vec3),
     Metal(Vec3),
+    /// Clear glass-like material with the given refractive index. Rays are
+    /// reflected or refracted, picked by the Fresnel reflectance. Spectral
+    /// rendering refracts each wavelength by its own index, so dispersive
+    /// glass splits white light up into its colors.
+    Dielectric(Ior),
+    /// Invisible boundary of a closed object filled with a participating
+    /// medium. Rays pass through the surface unchanged.
+    Volume(Medium),
 }
 
 impl Material {
@@ -24,8 +30,10 @@ impl Material {
         res: &IntersectionResult,
         albedo: Vec3,
     ) -> Option<(Ray, Vec3)> {
+        // a point on the unit sphere touching the surface gives a cosine
+        // distributed direction, so the weight is just the albedo
         let p = ray.point(res.t);
-        let target = p + res.n + rnd_in_unit_sphere();
+        let target = p + res.n + rnd_unit_vector();
         Some((Ray::new(p, (target - p).normalize()), albedo))
     }
 
@@ -43,90 +51,406 @@ impl Material {
         }
     }
 
+    fn scatter_dielectric(
+        &self,
+        ray: &Ray,
+        res: &IntersectionResult,
+        ior: f32,
+    ) -> Option<(Ray, Vec3)> {
+        // leaving the object if the ray is on the inside of the normal
+        let (n, eta) = if ray.dir.dot(res.n) < 0. { (res.n, 1. / ior) } else { (-res.n, ior) };
+        let refracted = match ray.dir.refract(n, eta) {
+            Some(dir) => {
+                // Schlick's approximation of the Fresnel reflectance, with
+                // the angle on the less dense side
+                let cos = if eta > 1. { -dir.normalize().dot(n) } else { -ray.dir.dot(n) };
+                let r0 = ((1. - ior) / (1. + ior)).powi(2);
+                let reflectance = r0 + (1. - r0) * (1. - cos).powi(5);
+                if rnd() >= reflectance { dir } else { ray.dir.reflect(n) }
+            }
+            None => ray.dir.reflect(n),
+        };
+        Some((Ray::new(ray.point(res.t), refracted.normalize()), Vec3::new(1., 1., 1.)))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Material::Lambertian(_) => "lambertian",
+            Material::Metal(_) => "metal",
+            Material::Dielectric(_) => "dielectric",
+            Material::Volume(_) => "volume",
+        }
+    }
+
+    /// Density with which `scatter` picks `dir` at `res`, `None` for
+    /// materials that only ever scatter into one direction.
+    pub fn pdf(&self, res: &IntersectionResult, dir: Vec3) -> Option<f32> {
+        match *self {
+            Material::Lambertian(_) => Some(dir.dot(res.n).max(0.) / PI),
+            Material::Metal(_) | Material::Dielectric(_) | Material::Volume(_) => None,
+        }
+    }
+
+    /// Base color, as used by the albedo output variable.
+    pub fn albedo(&self) -> Vec3 {
+        match *self {
+            Material::Lambertian(albedo) | Material::Metal(albedo) => albedo,
+            Material::Dielectric(_) | Material::Volume(_) => Vec3::new(1., 1., 1.),
+        }
+    }
+
     pub fn scatter(&self, ray: &Ray, res: &IntersectionResult) -> Option<(Ray, Vec3)> {
         match *self {
             Material::Lambertian(albedo) => self.scatter_lambertian(ray, res, albedo),
             Material::Metal(albedo) => self.scatter_metal(ray, res, albedo),
+            Material::Dielectric(ref ior) => self.scatter_dielectric(ray, res, ior.nd()),
+            Material::Volume(_) => Some((Ray::new(ray.point(res.t), ray.dir), Vec3::new(1., 1., 1.))),
+        }
+    }
+
+    /// Like `scatter`, with the refractive index at `lambda` nanometres.
+    pub fn scatter_at(&self, ray: &Ray, res: &IntersectionResult, lambda: f32) -> Option<(Ray, Vec3)> {
+        match *self {
+            Material::Dielectric(ref ior) => self.scatter_dielectric(ray, res, ior.at(lambda)),
+            _ => self.scatter(ray, res),
         }
     }
 }
 
+#[derive(Clone)]
 pub struct IntersectionResult {
     pub t: f32,
     pub n: Vec3,
+    pub uv: (f32, f32),
     pub material: Material,
+    /// Index into `Scene::objects` of the object that was hit. Objects
+    /// leave it at 0, `Scene::intersect` fills it in.
+    pub object: usize,
 }
 
+/// One camera sample: where on the image it was taken and what it saw.
+pub struct Sample {
+    /// Continuous image position, pixel `(i, j)` covers `[i, i + 1) x [j, j + 1)`.
+    pub x: f32,
+    pub y: f32,
+    pub ray: Ray,
+    pub color: Vec3,
+    /// First visible surface, only looked up when asked for.
+    pub hit: Option<IntersectionResult>,
+    /// Rays traced for this sample, bounces included.
+    pub rays: u32,
+    /// Light paths traced along with it and their contributions, see
+    /// `Film::add_light`.
+    pub light_paths: u32,
+    pub splats: Vec<Splat>,
+}
+
+/// A point connected to the camera, see `RayTracer::connect_camera`.
+#[derive(Clone, Copy, Debug)]
+pub struct CameraConnection {
+    /// Point on the lens.
+    pub origin: Vec3,
+    /// Continuous image position the point shows up at.
+    pub x: f32,
+    pub y: f32,
+    /// Camera importance over the density of picking `origin`, times the
+    /// geometry term of the connection. Radiance leaving the point towards
+    /// `origin` times this is what it adds to the image, before shadowing.
+    pub weight: f32,
+}
+
+/// Traces camera samples through a scene. It never changes once created,
+/// so render threads share it behind an `Arc`; a different view means a
+/// new `RayTracer` over the same scene, see `with_camera`.
+#[derive(Clone)]
 pub struct RayTracer {
-    pub scene: Scene,
-    pub image: image::DynamicImage,
+    pub scene: Arc<Scene>,
+    pub camera: Camera,
+    pub integrator: Arc<dyn Integrator>,
     pub dimensions: (u32, u32),
-    fov: f32,
-    iters: u32
 }
 
 impl RayTracer {
-    pub fn new(dimensions: (u32, u32)) -> RayTracer {
+    /// Builds the scene's acceleration structure unless that's done
+    /// already and prepares its integrator, see `Integrator::prepare`.
+    pub fn new(dimensions: (u32, u32), mut scene: Scene) -> RayTracer {
+        if !scene.is_built() {
+            scene.build();
+        }
         RayTracer {
-            image: image::DynamicImage::new_rgb8(dimensions.0, dimensions.1),
-            dimensions: dimensions,
-            fov: 3.14 / 3.,
-            scene: Scene::new(),
-            iters: 0
+            camera: scene.camera,
+            integrator: scene.integrator.clone(),
+            scene: Arc::new(scene),
+            dimensions,
+        }.prepared()
+    }
+
+    /// The same scene seen through `camera`.
+    pub fn with_camera(&self, camera: Camera) -> RayTracer {
+        RayTracer {
+            camera,
+            ..self.clone()
+        }.prepared()
+    }
+
+    /// The same view rendered at another resolution.
+    pub fn with_dimensions(&self, dimensions: (u32, u32)) -> RayTracer {
+        RayTracer {
+            dimensions,
+            ..self.clone()
+        }.prepared()
+    }
+
+    /// The same view rendered with another light transport algorithm.
+    pub fn with_integrator(&self, integrator: Arc<dyn Integrator>) -> RayTracer {
+        RayTracer {
+            integrator,
+            ..self.clone()
+        }.prepared()
+    }
+
+    /// Lets the integrator get ready for this scene and view.
+    fn prepared(mut self) -> RayTracer {
+        if let Some(integrator) = self.integrator.prepare(&self) {
+            self.integrator = integrator;
         }
+        self
     }
 
-    pub fn update(&mut self) {
-        // send ray:
-        let fov_tan = (self.fov * 0.5).tan();
+    /// Area of the image plane at unit distance in front of the camera.
+    fn image_plane_area(&self) -> f32 {
+        let fov_tan = (self.camera.fov * 0.5).tan();
         let aspect = (self.dimensions.0 as f32) / (self.dimensions.1 as f32);
-        let mut rng = rand::thread_rng();
-
-        for px in 0..self.dimensions.0 {
-            for py in 0..self.dimensions.1 {
-                let mut color =  rgb_to_vec3(self.image.get_pixel(px, py));
-                color = igamma(color);
-
-                let k = self.iters as f32;
-                color = color * k / (k + 1.);
-                let weight = 1. / ((k + 1.) * (SAMPLES_PER_PIXEL as f32));
-
-                for _ in 0..SAMPLES_PER_PIXEL {
-                    let xr = (px as f32) + rng.gen_range(0., 1.);
-                    let yr = (py as f32) + rng.gen_range(0., 1.);
-                    let x = (2. * xr) / (self.dimensions.0 as f32) - 1.;
-                    let y = (2. * yr) / (self.dimensions.1 as f32) - 1.;
-
-                    let origin = self.scene.camera.position;
-                    let dir = Vec3::new(x * fov_tan * aspect, y * fov_tan, 1.).normalize();
-                    let ray = Ray::new(origin, dir);
-                    // TODO: orientation
-
-                    let result_color = self.render(ray, 0);
-                    color = color + result_color * weight;
-                }
-
-                color = gamma(color);
-                self.image.put_pixel(px, py, vec3_to_rgb(color));
-            }
+        4. * fov_tan * fov_tan * aspect
+    }
+
+    /// Density per unit solid angle with which `sample` shoots camera rays
+    /// into `dir` from a given point on the lens.
+    pub fn ray_pdf(&self, dir: Vec3) -> f32 {
+        let (_, _, forward) = self.camera.basis();
+        let cos = dir.dot(forward);
+        if cos <= 0. {
+            return 0.;
         }
+        1. / (self.image_plane_area() * cos * cos * cos)
+    }
 
-        self.iters = self.iters + 1;
+    /// Picks a point on the lens like `sample` does and finds where on the
+    /// image `p` is seen from there, `None` if outside the image.
+    pub fn connect_camera(&self, p: Vec3) -> Option<CameraConnection> {
+        let (right, up, forward) = self.camera.basis();
+        let origin = if self.camera.aperture > 0. {
+            let lens = rnd_in_unit_disk();
+            self.camera.position + (right * lens.0 + up * lens.1) * self.camera.aperture
+        } else {
+            self.camera.position
+        };
+        let d = p - origin;
+        let distance_sq = d.square_length();
+        let dir = d / distance_sq.sqrt();
+        let cos = dir.dot(forward);
+        if cos <= 0. {
+            return None;
+        }
+        // rays through the lens meet on the focus plane, where the pinhole
+        // projection tells the image position
+        let target = if self.camera.aperture > 0. {
+            origin + dir * (self.camera.focus_distance / cos)
+        } else {
+            p
+        };
+        let (x, y) = self.project(target)?;
+        if x < 0. || y < 0. || x >= self.dimensions.0 as f32 || y >= self.dimensions.1 as f32 {
+            return None;
+        }
+        // importance 1 / (A cos^4) per lens area, the lens area cancels
+        // against the density of `origin`
+        Some(CameraConnection {
+            origin,
+            x,
+            y,
+            weight: 1. / (self.image_plane_area() * cos * cos * cos * distance_sq),
+        })
     }
 
-    fn render(&self, ray: Ray, depth: u8) -> Vec3 {
-        let min_t = if depth > 0 { 0.001 } else { 0. };
-        let intersection = self.scene.intersect(ray, min_t);
-        if let Some(ref result) = intersection {
-            let scatter = result.material.scatter(&ray, &result);
-            if depth < MAX_DEPTH {
-                if let Some((r, attennuation)) = scatter {
-                    return self.render(r, depth + 1) * attennuation
-                }
-            }
-            Vec3::new(0., 0., 0.)
+    /// Camera ray through the continuous image position `(xr, yr)`, leaving
+    /// the lens at `lens`, a point of the unit disk.
+    fn camera_ray(&self, xr: f32, yr: f32, lens: (f32, f32)) -> Ray {
+        let fov_tan = (self.camera.fov * 0.5).tan();
+        let aspect = (self.dimensions.0 as f32) / (self.dimensions.1 as f32);
+        let x = (2. * xr) / (self.dimensions.0 as f32) - 1.;
+        // image rows go top to bottom
+        let y = 1. - (2. * yr) / (self.dimensions.1 as f32);
+
+        let (right, up, forward) = self.camera.basis();
+        let dir = right * (x * fov_tan * aspect) + up * (y * fov_tan) + forward;
+        if self.camera.aperture <= 0. {
+            return Ray::new(self.camera.position, dir.normalize());
+        }
+        // all rays through the lens meet again on the focus plane
+        let focus = self.camera.position + dir * self.camera.focus_distance;
+        let origin = self.camera.position + (right * lens.0 + up * lens.1) * self.camera.aperture;
+        Ray::new(origin, (focus - origin).normalize())
+    }
+
+    /// Camera ray through the continuous image position `(xr, yr)`, from a
+    /// random point on the lens.
+    pub fn ray_at(&self, xr: f32, yr: f32) -> Ray {
+        let lens = if self.camera.aperture > 0. { rnd_in_unit_disk() } else { (0., 0.) };
+        self.camera_ray(xr, yr, lens)
+    }
+
+    /// Jittered camera ray through pixel `(px, py)` and the image position
+    /// it passes through.
+    fn jittered_ray(&self, px: u32, py: u32) -> (f32, f32, Ray) {
+        // px + rnd() can round up to px + 1, which would count the sample
+        // in the next pixel
+        let jitter = |p: u32| (p as f32 + rnd()).min((p + 1) as f32 * (1. - f32::EPSILON));
+        let xr = jitter(px);
+        let yr = jitter(py);
+        (xr, yr, self.ray_at(xr, yr))
+    }
+
+    /// Image position of `p`, `None` if it is behind the camera. Ignores the
+    /// lens, so points off the focus plane land where a pinhole sees them.
+    pub fn project(&self, p: Vec3) -> Option<(f32, f32)> {
+        let (right, up, forward) = self.camera.basis();
+        let d = p - self.camera.position;
+        let z = d.dot(forward);
+        if z <= 0. {
+            return None;
+        }
+        let fov_tan = (self.camera.fov * 0.5).tan();
+        let aspect = (self.dimensions.0 as f32) / (self.dimensions.1 as f32);
+        let x = d.dot(right) / (z * fov_tan * aspect);
+        let y = d.dot(up) / (z * fov_tan);
+        Some((
+            (x + 1.) * 0.5 * self.dimensions.0 as f32,
+            (1. - y) * 0.5 * self.dimensions.1 as f32,
+        ))
+    }
+
+    /// Image positions of the ends of segment `a`-`b`, with the part behind
+    /// the camera cut off.
+    pub fn project_segment(&self, a: Vec3, b: Vec3) -> Option<((f32, f32), (f32, f32))> {
+        const NEAR: f32 = 1e-3;
+        let (_, _, forward) = self.camera.basis();
+        let za = (a - self.camera.position).dot(forward);
+        let zb = (b - self.camera.position).dot(forward);
+        if za < NEAR && zb < NEAR {
+            return None;
+        }
+        let clip = |p: Vec3, zp: f32, q: Vec3, zq: f32| if zp < NEAR {
+            p + (q - p) * ((NEAR - zp) / (zq - zp))
         } else {
-            Vec3::new(1.0, 1.0, 1.0)
-        }        
-    }    
+            p
+        };
+        let (a, b) = (clip(a, za, b, zb), clip(b, zb, a, za));
+        match (self.project(a), self.project(b)) {
+            (Some(a), Some(b)) => Some((a, b)),
+            _ => None,
+        }
+    }
+
+    /// First surface seen through the center of pixel `(px, py)`, and the
+    /// ray that found it. Volume boundaries count as surfaces here.
+    pub fn pick(&self, px: u32, py: u32) -> (Ray, Option<IntersectionResult>) {
+        let ray = self.camera_ray(px as f32 + 0.5, py as f32 + 0.5, (0., 0.));
+        (ray, self.scene.intersect(ray, 0.))
+    }
+
+    /// Traces one jittered sample of pixel `(px, py)`.
+    pub fn sample(&self, px: u32, py: u32, with_hit: bool) -> Sample {
+        let (xr, yr, ray) = self.jittered_ray(px, py);
+        let mut path = PathState::new(false);
+        let color = self.integrator.radiance(self, ray, &mut path);
+        Sample {
+            x: xr,
+            y: yr,
+            ray,
+            color,
+            hit: if with_hit { self.first_surface(ray) } else { None },
+            rays: path.rays,
+            light_paths: path.light_paths,
+            splats: path.splats,
+        }
+    }
+
+    /// Traces one jittered sample of pixel `(px, py)` like `sample`, and
+    /// records every bounce on the way.
+    pub fn trace_path(&self, px: u32, py: u32) -> RecordedPath {
+        let (xr, yr, ray) = self.jittered_ray(px, py);
+        let mut path = PathState::new(true);
+        let radiance = self.integrator.radiance(self, ray, &mut path);
+        RecordedPath {
+            pixel: (px, py),
+            x: xr,
+            y: yr,
+            radiance,
+            vertices: path.vertices.unwrap_or_default(),
+        }
+    }
+
+    /// First hit along `ray` that isn't an invisible volume boundary.
+    fn first_surface(&self, ray: Ray) -> Option<IntersectionResult> {
+        first_surface(&self.scene, ray, 0.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lambertian_test() {
+        // scattering follows `pdf`, cos / pi: then cos^2 is uniform, so the
+        // cosines average 2/3 and a quarter of them lie below 1/2
+        let albedo = Vec3::new(0.5, 0.5, 0.5);
+        let material = Material::Lambertian(albedo);
+        let res = IntersectionResult {
+            t: 1.,
+            n: Vec3::new(0., 1., 0.),
+            uv: (0., 0.),
+            material: material.clone(),
+            object: 0,
+        };
+        let ray = Ray::new(Vec3::new(0., 1., 0.), Vec3::new(0., -1., 0.));
+        let n = 100000;
+        let (mut sum, mut below) = (0., 0);
+        for _ in 0..n {
+            let (scattered, attenuation) = material.scatter(&ray, &res).unwrap();
+            assert!(attenuation == albedo);
+            let cos = scattered.dir.dot(res.n);
+            sum += cos;
+            if cos < 0.5 {
+                below += 1;
+            }
+        }
+        assert!((sum / n as f32 - 2. / 3.).abs() < 0.01);
+        assert!((below as f32 / n as f32 - 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn dielectric_test() {
+        // light is reflected as often leaving glass as it is entering along
+        // the same path the other way
+        let glass = Material::Dielectric(Ior::Constant(1.5));
+        let res = IntersectionResult {
+            t: 1.,
+            n: Vec3::new(0., 1., 0.),
+            uv: (0., 0.),
+            material: glass.clone(),
+            object: 0,
+        };
+        let d = Vec3::new(3f32.sqrt() * 0.5, -0.5, 0.);
+        let inside = -d.refract(res.n, 1. / 1.5).unwrap();
+        let n = 100000;
+        let reflected = |dir: Vec3| {
+            let ray = Ray::new(-dir, dir);
+            (0..n).filter(|_| glass.scatter(&ray, &res).unwrap().0.dir.y * dir.y < 0.).count() as f32 / n as f32
+        };
+        let (entering, leaving) = (reflected(d), reflected(inside));
+        assert!((entering - 0.07).abs() < 0.005);
+        assert!((entering - leaving).abs() < 0.005, "{} {}", entering, leaving);
+    }
 }
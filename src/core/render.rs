@@ -0,0 +1,570 @@
+extern crate image;
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use core::raytracer::*;
+use core::scene::*;
+use core::film::*;
+use core::aov::*;
+use core::denoise::*;
+use core::filter::*;
+use core::tonemap::*;
+use core::exr::*;
+
+const SAMPLES_PER_PIXEL: u32 = 1;
+const TILE_SIZE: u32 = 32;
+
+/// Stops sampling pixels once their estimated error is small enough, see
+/// `Film::error`.
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveSampling {
+    /// Relative standard error below which a pixel counts as converged.
+    pub threshold: f32,
+    /// Samples every pixel gets before its error estimate is trusted.
+    pub min_samples: u32,
+    /// Pixels stop here even if they haven't converged.
+    pub max_samples: u32,
+}
+
+impl AdaptiveSampling {
+    pub fn new(threshold: f32) -> AdaptiveSampling {
+        AdaptiveSampling {
+            threshold,
+            min_samples: 16,
+            max_samples: 1024,
+        }
+    }
+
+    fn done(&self, film: &Film, x: u32, y: u32) -> bool {
+        let samples = film.samples(x, y);
+        samples >= self.max_samples || (samples >= self.min_samples && film.error(x, y) < self.threshold)
+    }
+}
+
+/// The image being refined, owned by the thread that shows or saves it.
+/// Samples come in from a `Renderer`.
+pub struct Frame {
+    pub dimensions: (u32, u32),
+    /// Linear radiance accumulated over all passes.
+    pub film: Film,
+    /// `film` after the output transform, see `refresh_image`.
+    pub image: image::DynamicImage,
+    pub output: OutputTransform,
+    /// Extra per-pixel outputs, only gathered when set.
+    pub aovs: Option<AovBuffers>,
+    /// Applied to `image` when set. It needs the albedo and normal buffers,
+    /// so those are gathered along with it.
+    pub denoiser: Option<Denoiser>,
+    /// How samples are spread over neighbouring pixels.
+    pub filter: Filter,
+    /// Uniform sampling when unset.
+    pub adaptive: Option<AdaptiveSampling>,
+    /// Completed passes since the last clear.
+    pub passes: u32,
+    /// Pixels sampled by the most recently scheduled pass.
+    pub active_pixels: u32,
+    /// Samples and rays traced since the last clear.
+    pub samples: u64,
+    pub rays: u64,
+    /// What `image` shows: the beauty image, or one of the AOVs if set.
+    pub display: Option<Aov>,
+}
+
+impl Frame {
+    pub fn new(dimensions: (u32, u32)) -> Frame {
+        Frame {
+            dimensions,
+            film: Film::new(dimensions),
+            image: image::DynamicImage::new_rgb8(dimensions.0, dimensions.1),
+            output: OutputTransform::new(),
+            aovs: None,
+            denoiser: None,
+            filter: Filter::Box { radius: 0.5 },
+            adaptive: None,
+            passes: 0,
+            active_pixels: 0,
+            samples: 0,
+            rays: 0,
+            display: None,
+        }
+    }
+
+    /// Drops everything accumulated so far.
+    pub fn clear(&mut self) {
+        self.film.clear();
+        if let Some(ref mut aovs) = self.aovs {
+            aovs.clear();
+        }
+        self.passes = 0;
+        self.active_pixels = 0;
+        self.samples = 0;
+        self.rays = 0;
+    }
+
+    /// Reallocates all buffers for a new resolution. Everything accumulated
+    /// so far is dropped.
+    pub fn resize(&mut self, dimensions: (u32, u32)) {
+        self.dimensions = dimensions;
+        self.film = Film::new(dimensions);
+        self.image = image::DynamicImage::new_rgb8(dimensions.0, dimensions.1);
+        self.aovs = self.aovs.take().map(|aovs| AovBuffers::new(dimensions, aovs.enabled));
+        self.clear();
+    }
+
+    fn wants_hits(&self) -> bool {
+        self.aovs.is_some() || self.denoiser.is_some()
+    }
+
+    /// Pixels the next pass should sample, `None` for all of them.
+    fn active_mask(&self) -> Option<Vec<bool>> {
+        self.adaptive.map(|adaptive| {
+            let (w, h) = self.dimensions;
+            (0..w * h).map(|i| !adaptive.done(&self.film, i % w, i / w)).collect()
+        })
+    }
+
+    pub fn add_samples(&mut self, samples: &[Sample]) {
+        if self.aovs.is_none() && self.denoiser.is_some() {
+            self.aovs = Some(AovBuffers::new(self.dimensions, Vec::new()));
+        }
+        for s in samples {
+            self.samples += 1;
+            self.rays += s.rays as u64;
+            self.film.splat(s.x, s.y, s.color, &self.filter);
+            self.film.add_light_paths(s.light_paths as u64);
+            for splat in &s.splats {
+                self.film.add_light(splat.x, splat.y, splat.radiance);
+            }
+            if let Some(ref mut aovs) = self.aovs {
+                aovs.add_sample(s.x as u32, s.y as u32, &s.ray, s.hit.as_ref());
+            }
+        }
+    }
+
+    /// True once adaptive sampling has nothing left to do.
+    pub fn converged(&self) -> bool {
+        self.adaptive.is_some() && self.passes > 0 && self.active_pixels == 0
+    }
+
+    /// `film` run through the denoiser, if one is set.
+    pub fn denoised(&self) -> Option<Film> {
+        match (self.denoiser, self.aovs.as_ref()) {
+            (Some(denoiser), Some(aovs)) => Some(denoiser.denoise(&self.film, aovs)),
+            _ => None,
+        }
+    }
+
+    /// Average samples per pixel since the last clear.
+    pub fn samples_per_pixel(&self) -> f32 {
+        self.samples as f32 / (self.dimensions.0 * self.dimensions.1) as f32
+    }
+
+    /// Saves by file extension: `.exr` and `.hdr` get the linear film,
+    /// anything else the tone mapped 8-bit image. AOVs become layers of an
+    /// OpenEXR file, other formats get one viewable image per AOV next to
+    /// the beauty image, e.g. `out.depth.png` for `out.png`. `half` stores
+    /// OpenEXR color as 16-bit floats.
+    pub fn save<P: AsRef<Path>>(&self, path: P, half: bool) -> io::Result<()> {
+        let denoised = self.denoised();
+        let film = denoised.as_ref().unwrap_or(&self.film);
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+        let extension = extension.as_deref();
+        if extension == Some("exr") {
+            let pixel_type = if half { ExrPixelType::Half } else { ExrPixelType::Float };
+            return match self.aovs {
+                Some(ref aovs) if !aovs.enabled.is_empty() => aovs.save_exr(path, film, pixel_type),
+                _ => film.save_exr(path, pixel_type),
+            };
+        }
+
+        match extension {
+            Some("hdr") => film.save_hdr(path)?,
+            _ => self.output.to_image(film).to_rgb().save(path)?,
+        }
+        if let Some(ref aovs) = self.aovs {
+            // the renderings are 8-bit, so HDR output gets PNG side files
+            let aov_extension = match extension {
+                Some("hdr") | None => "png",
+                Some(e) => e,
+            };
+            for &aov in &aovs.enabled {
+                let aov_path = path.with_extension(format!("{}.{}", aov.name(), aov_extension));
+                aovs.to_image(aov).to_rgb().save(aov_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Updates `image` from the film, or from the AOV picked by `display`.
+    /// Denoising makes this expensive.
+    pub fn refresh_image(&mut self) {
+        if let (Some(aov), Some(aovs)) = (self.display, self.aovs.as_ref()) {
+            self.image = aovs.to_image(aov);
+            return;
+        }
+        self.image = match self.denoised() {
+            Some(film) => self.output.to_image(&film),
+            None => self.output.to_image(&self.film),
+        };
+    }
+}
+
+struct Job {
+    generation: usize,
+    tracer: Arc<RayTracer>,
+    /// Pixel range, `x0, y0` inclusive and `x1, y1` exclusive.
+    tile: (u32, u32, u32, u32),
+    mask: Option<Arc<Vec<bool>>>,
+    with_hits: bool,
+}
+
+struct TileResult {
+    generation: usize,
+    samples: Vec<Sample>,
+}
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    shutdown: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+    /// Jobs of older generations are stale and get dropped.
+    generation: AtomicUsize,
+}
+
+/// Renders passes on background threads. Each pass is split into tiles that
+/// workers trace independently and send back as soon as they are done, so
+/// whoever owns the `Frame` only merges finished tiles and stays responsive.
+pub struct Renderer {
+    tracer: Arc<RayTracer>,
+    shared: Arc<Shared>,
+    results: Receiver<TileResult>,
+    workers: Vec<JoinHandle<()>>,
+    generation: usize,
+    // tiles of the current pass that haven't come back yet
+    pending: usize,
+    /// No more passes are scheduled once the frame has this many.
+    pub pass_limit: Option<u32>,
+    /// No new passes are scheduled while set, the one in flight still
+    /// completes.
+    pub paused: bool,
+}
+
+impl Renderer {
+    pub fn new(tracer: Arc<RayTracer>, threads: usize) -> Renderer {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                shutdown: false,
+            }),
+            ready: Condvar::new(),
+            generation: AtomicUsize::new(0),
+        });
+        let (sender, results) = channel();
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                let sender = sender.clone();
+                thread::spawn(move || worker(shared, sender))
+            })
+            .collect();
+        Renderer {
+            tracer,
+            shared,
+            results,
+            workers,
+            generation: 0,
+            pending: 0,
+            pass_limit: None,
+            paused: false,
+        }
+    }
+
+    pub fn tracer(&self) -> &Arc<RayTracer> {
+        &self.tracer
+    }
+
+    /// Abandons all work in flight and starts over with `tracer`, e.g.
+    /// after the camera moved. `frame` is cleared.
+    pub fn restart(&mut self, tracer: Arc<RayTracer>, frame: &mut Frame) {
+        self.generation += 1;
+        self.shared.generation.store(self.generation, Ordering::SeqCst);
+        self.shared.queue.lock().unwrap().jobs.clear();
+        self.tracer = tracer;
+        self.pending = 0;
+        frame.clear();
+    }
+
+    /// True when nothing is in flight and no further pass will be scheduled.
+    pub fn is_done(&self, frame: &Frame) -> bool {
+        self.pending == 0 && (self.limit_reached(frame) || frame.converged())
+    }
+
+    fn limit_reached(&self, frame: &Frame) -> bool {
+        match self.pass_limit {
+            Some(limit) => frame.passes >= limit,
+            None => false,
+        }
+    }
+
+    /// Queues the tiles of the next pass. Returns false if there is nothing
+    /// left to render.
+    fn schedule_pass(&mut self, frame: &mut Frame) -> bool {
+        if self.limit_reached(frame) {
+            return false;
+        }
+        let (w, h) = frame.dimensions;
+        let mask = if self.tracer.integrator.splats_only() { None } else { frame.active_mask() };
+        frame.active_pixels = mask.as_ref().map_or(w * h, |m| m.iter().filter(|&&a| a).count() as u32);
+        if frame.active_pixels == 0 {
+            return false;
+        }
+        let mask = mask.map(Arc::new);
+
+        let mut queue = self.shared.queue.lock().unwrap();
+        for y0 in (0..h).filter(|y| y % TILE_SIZE == 0) {
+            for x0 in (0..w).filter(|x| x % TILE_SIZE == 0) {
+                let tile = (x0, y0, (x0 + TILE_SIZE).min(w), (y0 + TILE_SIZE).min(h));
+                if let Some(ref mask) = mask {
+                    let active = (tile.1..tile.3).any(|y| (tile.0..tile.2).any(|x| mask[(y * w + x) as usize]));
+                    if !active {
+                        continue;
+                    }
+                }
+                queue.jobs.push_back(Job {
+                    generation: self.generation,
+                    tracer: self.tracer.clone(),
+                    tile,
+                    mask: mask.clone(),
+                    with_hits: frame.wants_hits(),
+                });
+                self.pending += 1;
+            }
+        }
+        self.shared.ready.notify_all();
+        true
+    }
+
+    /// Merges finished tiles into `frame` for up to `budget`, scheduling new
+    /// passes as old ones complete. Returns true if `frame` changed.
+    pub fn poll(&mut self, frame: &mut Frame, budget: Duration) -> bool {
+        let start = Instant::now();
+        let mut changed = false;
+        loop {
+            if self.pending == 0 && (self.paused || !self.schedule_pass(frame)) {
+                return changed;
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= budget {
+                return changed;
+            }
+            match self.results.recv_timeout(budget - elapsed) {
+                Ok(tile) => {
+                    if tile.generation != self.generation {
+                        continue;
+                    }
+                    frame.add_samples(&tile.samples);
+                    changed = true;
+                    self.pending -= 1;
+                    if self.pending == 0 {
+                        frame.passes += 1;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) |
+                Err(RecvTimeoutError::Disconnected) => return changed,
+            }
+        }
+    }
+
+    /// Renders into `frame` until `is_done`, calling `progress` whenever a
+    /// pass completes. Only returns if there is a pass limit or adaptive
+    /// sampling.
+    pub fn run<F: FnMut(&Frame)>(&mut self, frame: &mut Frame, mut progress: F) {
+        let mut reported = frame.passes;
+        while !self.is_done(frame) {
+            self.poll(frame, Duration::from_millis(100));
+            if frame.passes != reported {
+                reported = frame.passes;
+                progress(frame);
+            }
+        }
+    }
+}
+
+/// Renders `passes` samples per pixel of `scene` on `threads` threads and
+/// returns the linear radiance.
+pub fn render_scene(scene: Scene, dimensions: (u32, u32), passes: u32, threads: usize) -> Film {
+    let tracer = Arc::new(RayTracer::new(dimensions, scene));
+    let mut frame = Frame::new(dimensions);
+    let mut renderer = Renderer::new(tracer, threads);
+    renderer.pass_limit = Some(passes);
+    renderer.run(&mut frame, |_| ());
+    frame.film
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            queue.shutdown = true;
+            queue.jobs.clear();
+        }
+        self.shared.ready.notify_all();
+        // stale jobs bail out early, so this doesn't wait for a whole tile
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker(shared: Arc<Shared>, results: Sender<TileResult>) {
+    loop {
+        let job = {
+            let mut queue = shared.queue.lock().unwrap();
+            loop {
+                if queue.shutdown {
+                    return;
+                }
+                if let Some(job) = queue.jobs.pop_front() {
+                    break job;
+                }
+                queue = shared.ready.wait(queue).unwrap();
+            }
+        };
+
+        let (x0, y0, x1, y1) = job.tile;
+        let width = job.tracer.dimensions.0;
+        let mut samples = Vec::new();
+        for y in y0..y1 {
+            if shared.generation.load(Ordering::Relaxed) != job.generation {
+                break;
+            }
+            for x in x0..x1 {
+                if let Some(ref mask) = job.mask {
+                    if !mask[(y * width + x) as usize] {
+                        continue;
+                    }
+                }
+                for _ in 0..SAMPLES_PER_PIXEL {
+                    samples.push(job.tracer.sample(x, y, job.with_hits));
+                }
+            }
+        }
+        let result = TileResult {
+            generation: job.generation,
+            samples,
+        };
+        if results.send(result).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use math::*;
+
+    #[test]
+    fn adaptive_filter_test() {
+        let adaptive = AdaptiveSampling::new(0.01);
+        for name in &["tent", "mitchell"] {
+            let filter = Filter::from_name(name).unwrap();
+            // a flat pixel next to a noisy one, close enough to share samples
+            let mut film = Film::new((2, 1));
+            for i in 0..adaptive.min_samples {
+                let u = (i as f32 + 0.5) / adaptive.min_samples as f32;
+                film.splat(u, u, Vec3::new(1., 1., 1.), &filter);
+                let noisy = if i % 2 == 0 { 0. } else { 2. };
+                film.splat(1. + u, u, Vec3::new(noisy, noisy, noisy), &filter);
+            }
+            assert!(film.samples(0, 0) == adaptive.min_samples);
+            assert!(film.samples(1, 0) == adaptive.min_samples);
+            assert!(adaptive.done(&film, 0, 0));
+            assert!(!adaptive.done(&film, 1, 0));
+        }
+    }
+
+    // three tiles by two
+    const DIMENSIONS: (u32, u32) = (3 * TILE_SIZE, 2 * TILE_SIZE - 7);
+
+    fn tracer(dark: bool) -> Arc<RayTracer> {
+        let mut scene = Scene::new();
+        if dark {
+            // a black sphere around the camera hides the sky
+            scene.add(Box::new(Sphere {
+                origin: Vec3::zero(),
+                radius: 100.,
+                material: Material::Lambertian(Vec3::zero()),
+            }));
+        }
+        Arc::new(RayTracer::new(DIMENSIONS, scene))
+    }
+
+    fn assert_samples(frame: &Frame, passes: u32) {
+        let (w, h) = DIMENSIONS;
+        assert_eq!(frame.passes, passes);
+        assert_eq!(frame.samples, (passes * w * h) as u64);
+        for y in 0..h {
+            for x in 0..w {
+                assert_eq!(frame.film.samples(x, y), passes);
+            }
+        }
+    }
+
+    #[test]
+    fn pass_limit_test() {
+        let mut frame = Frame::new(DIMENSIONS);
+        let mut renderer = Renderer::new(tracer(false), 3);
+        renderer.pass_limit = Some(4);
+        let mut reported = Vec::new();
+        renderer.run(&mut frame, |frame| reported.push(frame.passes));
+        // one poll can finish several passes, progress sees the latest
+        assert!(reported.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(reported.last(), Some(&4));
+        assert!(renderer.is_done(&frame));
+        assert_samples(&frame, 4);
+
+        // nothing more gets scheduled
+        assert!(!renderer.poll(&mut frame, Duration::from_millis(50)));
+        assert_samples(&frame, 4);
+
+        // raising the limit continues where it stopped
+        renderer.pass_limit = Some(6);
+        renderer.run(&mut frame, |_| ());
+        assert_samples(&frame, 6);
+    }
+
+    #[test]
+    fn restart_test() {
+        for _ in 0..10 {
+            let mut frame = Frame::new(DIMENSIONS);
+            let mut renderer = Renderer::new(tracer(true), 4);
+            // get the workers busy with tiles of the dark scene, some of
+            // which finish after the restart
+            renderer.poll(&mut frame, Duration::from_millis(1));
+            thread::sleep(Duration::from_millis(2));
+            renderer.restart(tracer(false), &mut frame);
+            assert_samples(&frame, 0);
+
+            renderer.pass_limit = Some(2);
+            renderer.run(&mut frame, |_| ());
+            // only samples of the new generation count, so no pixel has too
+            // many and none is darkened by the old scene
+            assert_samples(&frame, 2);
+            assert!(frame.film.pixels().iter().all(|p| p.x > 0.1 && p.y > 0.1 && p.z > 0.1));
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Radiance `.hdr` (RGBE) writer with the standard run length encoding.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use math::*;
+
+/// Shared exponent encoding of a linear color.
+pub fn to_rgbe(c: Vec3) -> [u8; 4] {
+    let v = c.x.max(c.y).max(c.z);
+    if v.is_nan() || v <= 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // v = m * 2^e with m in [0.5, 1)
+    let mut e = v.log2().floor() as i32 + 1;
+    if v / 2f32.powi(e) >= 1. {
+        e += 1;
+    }
+    let scale = 256. / 2f32.powi(e);
+    let byte = |x: f32| (x.max(0.) * scale).min(255.) as u8;
+    [byte(c.x), byte(c.y), byte(c.z), (e + 128).clamp(0, 255) as u8]
+}
+
+const MIN_RUN: usize = 4;
+
+// Run length encodes one component of a scanline (after Bruce Walter's rgbe.c).
+fn write_runs(out: &mut Vec<u8>, data: &[u8]) {
+    let n = data.len();
+    let mut cur = 0;
+    while cur < n {
+        // find the next run of at least MIN_RUN equal bytes
+        let mut run_start = cur;
+        let mut run_count = 0;
+        let mut old_run_count = 0;
+        while run_count < MIN_RUN && run_start < n {
+            run_start += run_count;
+            old_run_count = run_count;
+            run_count = 1;
+            while run_start + run_count < n && run_count < 127 &&
+                data[run_start] == data[run_start + run_count]
+            {
+                run_count += 1;
+            }
+        }
+        // a short run right before the long one is still worth encoding
+        if old_run_count > 1 && old_run_count == run_start - cur {
+            out.push((128 + old_run_count) as u8);
+            out.push(data[cur]);
+            cur = run_start;
+        }
+        while cur < run_start {
+            let count = (run_start - cur).min(128);
+            out.push(count as u8);
+            out.extend_from_slice(&data[cur..cur + count]);
+            cur += count;
+        }
+        if run_count >= MIN_RUN {
+            out.push((128 + run_count) as u8);
+            out.push(data[run_start]);
+            cur += run_count;
+        }
+    }
+}
+
+/// Writes `pixels` (rows from the top) as a Radiance picture.
+pub fn write_hdr<W: Write>(w: &mut W, width: u32, height: u32, pixels: &[Vec3]) -> io::Result<()> {
+    if pixels.len() != (width * height) as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixel count does not match size"));
+    }
+    write!(w, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)?;
+
+    let width = width as usize;
+    let mut line = Vec::with_capacity(width * 4);
+    let mut component = Vec::with_capacity(width);
+    for row in pixels.chunks(width) {
+        line.clear();
+        let rgbe: Vec<[u8; 4]> = row.iter().map(|&c| to_rgbe(c)).collect();
+        if !(8..=0x7fff).contains(&width) {
+            // run length encoding is only defined for these widths
+            for p in &rgbe {
+                line.extend_from_slice(p);
+            }
+        } else {
+            line.extend_from_slice(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+            for i in 0..4 {
+                component.clear();
+                component.extend(rgbe.iter().map(|p| p[i]));
+                write_runs(&mut line, &component);
+            }
+        }
+        w.write_all(&line)?;
+    }
+    Ok(())
+}
+
+pub fn save_hdr<P: AsRef<Path>>(path: P, width: u32, height: u32, pixels: &[Vec3]) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write_hdr(&mut w, width, height, pixels)?;
+    w.flush()
+}
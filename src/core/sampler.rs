@@ -0,0 +1,178 @@
+//! Primary sample space: the random numbers `rnd` hands out, recorded so
+//! that Metropolis sampling can replay and mutate them.
+
+extern crate rand;
+
+use std::cell::RefCell;
+use std::f32::consts::PI;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+/// One coordinate of a point in primary sample space.
+#[derive(Clone, Copy)]
+struct PrimarySample {
+    value: f32,
+    /// Iteration it was last changed in.
+    modified: u64,
+    /// `value` and `modified` before the current mutation.
+    backup: (f32, u64),
+}
+
+/// Point in primary sample space: the random numbers `rnd` hands out while
+/// a camera path is traced, and the mutations that move it. Coordinates are
+/// made up as the path asks for them, and only catch up with the mutations
+/// they missed then.
+pub struct PrimarySamples {
+    samples: Vec<PrimarySample>,
+    index: usize,
+    rng: XorShiftRng,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    large_step_probability: f32,
+    sigma: f32,
+}
+
+impl PrimarySamples {
+    /// Fresh uniform samples. The same `seed` gives the same ones again,
+    /// as long as nothing else draws from `rng`.
+    pub fn new(seed: u32, large_step_probability: f32, sigma: f32) -> PrimarySamples {
+        PrimarySamples {
+            samples: Vec::new(),
+            index: 0,
+            // neighbouring seeds have to give unrelated streams
+            rng: XorShiftRng::from_seed([hash(seed), hash(seed ^ 0x9e37_79b9), hash(seed ^ 0x85eb_ca6b), 1]),
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            large_step_probability,
+            sigma,
+        }
+    }
+
+    /// Starts a mutation: either all new samples (a large step) or small
+    /// perturbations of the current ones.
+    pub fn mutate(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f32>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    /// Keeps the mutated samples.
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    /// Goes back to the samples before the mutation.
+    pub fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.modified == self.iteration {
+                sample.value = sample.backup.0;
+                sample.modified = sample.backup.1;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn next(&mut self) -> f32 {
+        let iteration = self.iteration;
+        if self.index >= self.samples.len() {
+            // new coordinates start out uniform, however the rest moves;
+            // starting them anywhere in particular would leave rejection
+            // sampling stuck there
+            let value = self.rng.gen::<f32>();
+            self.samples.push(PrimarySample {
+                value,
+                modified: iteration,
+                backup: (value, 0),
+            });
+            self.index += 1;
+            return value;
+        }
+        let sample = &mut self.samples[self.index];
+        self.index += 1;
+        if sample.modified < self.last_large_step {
+            sample.value = self.rng.gen::<f32>();
+            sample.modified = self.last_large_step;
+        }
+        sample.backup = (sample.value, sample.modified);
+        if self.large_step {
+            sample.value = self.rng.gen::<f32>();
+        } else {
+            // the small steps missed since the last change add up to one
+            // with the combined variance
+            let (u1, u2) = (self.rng.gen::<f32>(), self.rng.gen::<f32>());
+            let normal = (-2. * (1. - u1).ln()).sqrt() * (2. * PI * u2).cos();
+            let sigma = self.sigma * ((iteration - sample.modified) as f32).sqrt();
+            sample.value += normal * sigma;
+            sample.value -= sample.value.floor();
+            if sample.value >= 1. {
+                sample.value = 0.;
+            }
+        }
+        sample.modified = iteration;
+        sample.value
+    }
+}
+
+/// Integer hash with good avalanche (Chris Wellons' lowbias32).
+fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^ (x >> 16)
+}
+
+thread_local!(static PRIMARY: RefCell<Option<PrimarySamples>> = const { RefCell::new(None) });
+
+/// Next of the primary samples `with_primary_samples` put in place, if any.
+pub fn next_primary_sample() -> Option<f32> {
+    PRIMARY.with(|primary| primary.borrow_mut().as_mut().map(|samples| samples.next()))
+}
+
+/// Runs `f` with `rnd` handing out `samples`, and gives them back.
+pub fn with_primary_samples<R, F: FnOnce() -> R>(samples: PrimarySamples, f: F) -> (R, PrimarySamples) {
+    PRIMARY.with(|primary| *primary.borrow_mut() = Some(samples));
+    let result = f();
+    let samples = PRIMARY.with(|primary| primary.borrow_mut().take());
+    (result, samples.expect("primary samples taken away"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::util::rnd;
+
+    fn values(samples: &PrimarySamples) -> Vec<f32> {
+        samples.samples.iter().map(|s| s.value).collect()
+    }
+
+    #[test]
+    fn primary_samples_test() {
+        // the same seed hands out the same numbers through `rnd`
+        let draw = |seed: u32| with_primary_samples(PrimarySamples::new(seed, 0.3, 0.01), || {
+            (0..8).map(|_| rnd()).collect::<Vec<f32>>()
+        }).0;
+        assert!(draw(5) == draw(5));
+        assert!(draw(5) != draw(6));
+
+        let mut samples = PrimarySamples::new(1, 0.3, 0.01);
+        for _ in 0..8 {
+            samples.next();
+        }
+        samples.accept();
+        for _ in 0..20 {
+            let before = values(&samples);
+            samples.mutate();
+            for _ in 0..8 {
+                samples.next();
+            }
+            assert!(values(&samples) != before);
+            samples.reject();
+            assert!(values(&samples) == before);
+        }
+    }
+}
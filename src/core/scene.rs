@@ -1,9 +1,73 @@
+use std::f32;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
 use math::*;
-use raytracer::*;
+use core::raytracer::*;
+use core::bvh::*;
+use core::medium::*;
+use core::light::*;
+use core::integrator::*;
+
+
+/// Longitude/latitude texture coordinates of a direction from the center.
+pub fn spherical_uv(n: Vec3) -> (f32, f32) {
+    let u = 0.5 + n.z.atan2(n.x) / (2. * PI);
+    let v = 0.5 + n.y.clamp(-1., 1.).asin() / PI;
+    (u, v)
+}
+
+/// Anything rays can hit. Render threads share objects, hence `Send + Sync`.
+pub trait SceneObject: Send + Sync {
+    /// Closest hit with `t_min < t < t_max`. Closed objects report outward
+    /// facing normals, open surfaces like planes face the incoming ray.
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult>;
+
+    /// World space bounds, `None` if the object is unbounded.
+    fn bounds(&self) -> Option<Aabb>;
+
+    /// Every span of the whole line through `ray` that lies inside the
+    /// object, ascending, with outward normals at both ends. Only closed
+    /// solids can take part in CSG; open surfaces have no inside and keep
+    /// the default of no intervals.
+    fn intervals(&self, _ray: &Ray) -> Vec<Interval> {
+        Vec::new()
+    }
+}
 
+/// Part of a ray inside a solid, see `SceneObject::intervals`.
+#[derive(Clone)]
+pub struct Interval {
+    pub enter: IntersectionResult,
+    pub exit: IntersectionResult,
+}
 
-pub trait SceneObject {
-    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult>;
+impl Interval {
+    /// Intervals of a closed surface from all of its crossings with a line,
+    /// in any order. Crossings closer than a hair are one, as where a ray
+    /// hits the edge shared by two faces; an odd leftover from a grazing hit
+    /// is dropped.
+    pub fn from_crossings(mut crossings: Vec<IntersectionResult>) -> Vec<Interval> {
+        crossings.sort_by(|a, b| a.t.partial_cmp(&b.t).unwrap_or(::std::cmp::Ordering::Equal));
+        let mut distinct: Vec<IntersectionResult> = Vec::with_capacity(crossings.len());
+        for crossing in crossings {
+            let duplicate = match distinct.last() {
+                Some(last) => crossing.t - last.t <= 1e-4 * (1. + crossing.t.abs()),
+                None => false,
+            };
+            if !duplicate {
+                distinct.push(crossing);
+            }
+        }
+        distinct
+            .chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| Interval {
+                enter: pair[0].clone(),
+                exit: pair[1].clone(),
+            })
+            .collect()
+    }
 }
 
 pub struct Sphere {
@@ -12,69 +76,306 @@ pub struct Sphere {
     pub material: Material
 }
 
+impl Sphere {
+    fn surface(&self, ray: &Ray, t: f32) -> IntersectionResult {
+        let normal = (ray.point(t) - self.origin).normalize();
+        IntersectionResult {
+            t,
+            n: normal,
+            uv: spherical_uv(normal),
+            material: self.material.clone(),
+            object: 0,
+        }
+    }
+
+    // both roots of the ray/sphere quadratic, ascending
+    fn roots(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let to_origin = ray.origin - self.origin;
+        let b = to_origin.dot(ray.dir);
+        let c = to_origin.square_length() - self.radius * self.radius;
+        let d = b * b - c;
+        if d < 0. {
+            return None;
+        }
+        let sqrt_d = d.sqrt();
+        Some((-b - sqrt_d, -b + sqrt_d))
+    }
+}
+
 impl SceneObject for Sphere {
-    fn intersect(&self, ray: &Ray) -> Option<IntersectionResult> {
-        let to_center = self.origin - ray.origin;
-        let to_nearest = to_center.dot(ray.dir);
-        if to_nearest < 0. {
-            None
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        let (t0, t1) = self.roots(ray)?;
+        let t = if t0 > t_min && t0 < t_max {
+            t0
+        } else if t1 > t_min && t1 < t_max {
+            t1
         } else {
-            let d2 = to_center.square_length() - to_nearest * to_nearest;
-            let r2 = self.radius * self.radius;
-            if d2 > r2 {
-                None
-            } else {
-                let tt = (r2 - d2).sqrt();
-                let t0 = to_nearest + tt;
-                let t1 = to_nearest - tt;
-                let mut t = t0.min(t1);
-                if t < 0. {
-                    t = t0.max(t1);
-                }
-                if t < 0. {
-                    None
-                } else {
-                    let normal = (ray.point(t) - self.origin).normalize();
-                    Some(IntersectionResult { t: t, n: normal, material: self.material.clone()})
-                }
-            }
+            return None;
+        };
+        Some(self.surface(ray, t))
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        Some(Aabb::new(self.origin - r, self.origin + r))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        match self.roots(ray) {
+            Some((t0, t1)) => vec![Interval {
+                enter: self.surface(ray, t0),
+                exit: self.surface(ray, t1),
+            }],
+            None => Vec::new(),
         }
     }
 }
 
+/// Pinhole camera. In the default orientation it looks down +z with +x to
+/// the right and +y up.
+#[derive(Clone, Copy, Debug)]
 pub struct Camera {
     pub position: Vec3,
-    // orientation
+    /// Viewing direction, need not be normalized.
+    pub direction: Vec3,
+    /// Vertical field of view in radians.
+    pub fov: f32,
+    /// Lens radius, 0 for a pinhole camera with everything in focus.
+    pub aperture: f32,
+    /// Distance along the view direction of the plane that is in focus.
+    pub focus_distance: f32,
+}
+
+impl Camera {
+    pub fn new(position: Vec3, direction: Vec3) -> Camera {
+        Camera {
+            position,
+            direction,
+            fov: PI / 3.,
+            aperture: 0.,
+            focus_distance: 1.,
+        }
+    }
+
+    /// Right, up and forward unit vectors, with up as close to +y as the
+    /// direction allows.
+    pub fn basis(&self) -> (Vec3, Vec3, Vec3) {
+        let forward = self.direction.normalize();
+        let mut right = Vec3::new(0., 1., 0.).cross(forward);
+        if right.square_length() < 1e-8 {
+            // looking straight up or down
+            right = Vec3::new(1., 0., 0.);
+        }
+        let right = right.normalize();
+        (right, forward.cross(right), forward)
+    }
 }
 
 pub struct Scene {
-    pub objects: Vec<Box<SceneObject>>,
+    // only changed through `add`, so the BVH can't go stale
+    objects: Vec<Box<dyn SceneObject>>,
+    /// Initial view, renderers keep their own copy that can move.
     pub camera: Camera,
+    /// Medium filling all space outside of volume objects, e.g. for fog.
+    /// It has no end, so a dense atmosphere hides the sky entirely.
+    pub atmosphere: Option<Medium>,
+    pub lights: Vec<PointLight>,
+    /// Radiance of the sky, arriving from every direction nothing blocks.
+    pub background: Vec3,
+    /// Light transport algorithm to render with.
+    pub integrator: Arc<dyn Integrator>,
+    // top level acceleration structure over `bounded`, see `build`
+    bvh: Option<Bvh>,
+    bounded: Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+impl Default for Scene {
+    fn default() -> Scene {
+        Scene::new()
+    }
 }
 
 impl Scene {
     pub fn new() -> Scene {
         Scene {
             objects: Vec::new(),
-            camera: Camera {
-                position: Vec3::zero(),
-            },
+            camera: Camera::new(Vec3::zero(), Vec3::new(0., 0., 1.)),
+            atmosphere: None,
+            lights: Vec::new(),
+            background: Vec3::new(1., 1., 1.),
+            integrator: Arc::new(PathTracer::new()),
+            bvh: None,
+            bounded: Vec::new(),
+            unbounded: Vec::new(),
         }
     }
 
-    pub fn intersect(&self, ray: Ray, min_t: f32) -> Option<IntersectionResult> {
-        let mut best_result: Option<IntersectionResult> = None;
-        for object in &self.objects {
-            if let Some(result) = object.intersect(&ray) {
-                let ok = match best_result {
-                    Some(ref r) => r.t > result.t,
-                    None => true
-                };
-                if ok && result.t > min_t {
-                    best_result = Some(result)
+    /// Adds `object` and returns its index, the `object` reported in its
+    /// hits. The BVH is dropped until the next `build`.
+    pub fn add(&mut self, object: Box<dyn SceneObject>) -> usize {
+        self.objects.push(object);
+        self.bvh = None;
+        self.objects.len() - 1
+    }
+
+    pub fn objects(&self) -> &[Box<dyn SceneObject>] {
+        &self.objects
+    }
+
+    /// Builds the top level BVH over object bounds. Meshes keep their own
+    /// BVHs and instances map rays into their object's space, so together
+    /// this forms a two level hierarchy.
+    pub fn build(&mut self) {
+        let mut bounds = Vec::new();
+        self.bounded.clear();
+        self.unbounded.clear();
+        for (i, object) in self.objects.iter().enumerate() {
+            match object.bounds() {
+                Some(b) => {
+                    self.bounded.push(i);
+                    bounds.push(b);
                 }
+                None => self.unbounded.push(i),
+            }
+        }
+        self.bvh = Some(Bvh::build(&bounds));
+    }
+
+    /// Whether `build` ran and no objects were added since.
+    pub fn is_built(&self) -> bool {
+        self.bvh.is_some()
+    }
+
+    pub fn intersect(&self, ray: Ray, min_t: f32) -> Option<IntersectionResult> {
+        match self.bvh {
+            Some(ref bvh) => {
+                let result = bvh.intersect(&ray, min_t, f32::INFINITY, |i, t_min, t_max| {
+                    let object = self.bounded[i];
+                    self.objects[object].intersect(&ray, t_min, t_max).map(|mut result| {
+                        result.object = object;
+                        result
+                    })
+                });
+                self.intersect_each(self.unbounded.iter().cloned(), &ray, min_t, result)
+            }
+            // not built yet, test everything
+            None => self.intersect_each(0..self.objects.len(), &ray, min_t, None),
+        }
+    }
+
+    fn intersect_each<I>(
+        &self,
+        indices: I,
+        ray: &Ray,
+        min_t: f32,
+        mut best_result: Option<IntersectionResult>,
+    ) -> Option<IntersectionResult>
+    where
+        I: Iterator<Item = usize>,
+    {
+        for i in indices {
+            let closest = best_result.as_ref().map_or(f32::INFINITY, |r| r.t);
+            if let Some(mut result) = self.objects[i].intersect(ray, min_t, closest) {
+                result.object = i;
+                best_result = Some(result);
             }
         }
         best_result
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::util::*;
+    use core::primitives::*;
+    use core::instance::*;
+
+    fn grey() -> Material {
+        Material::Lambertian(Vec3::new(0.5, 0.5, 0.5))
+    }
+
+    fn rnd_point(size: f32) -> Vec3 {
+        Vec3::new(rnd() - 0.5, rnd() - 0.5, rnd() - 0.5) * size
+    }
+
+    // closest hit over every object, without the BVH
+    fn brute_force(scene: &Scene, ray: Ray) -> Option<(usize, f32)> {
+        let mut best = None;
+        for (i, object) in scene.objects().iter().enumerate() {
+            let closest = best.map_or(f32::INFINITY, |(_, t)| t);
+            if let Some(result) = object.intersect(&ray, 0., closest) {
+                best = Some((i, result.t));
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn bvh_test() {
+        let mut scene = Scene::new();
+        let sphere: Arc<dyn SceneObject> = Arc::new(Sphere {
+            origin: Vec3::zero(),
+            radius: 1.,
+            material: grey(),
+        });
+        for i in 0..60 {
+            let origin = rnd_point(20.);
+            match i % 3 {
+                0 => scene.add(Box::new(Sphere {
+                    origin,
+                    radius: 0.2 + rnd(),
+                    material: grey(),
+                })),
+                1 => scene.add(Box::new(AxisBox {
+                    min: origin,
+                    max: origin + Vec3::new(rnd(), rnd(), rnd()) + Vec3::new(0.1, 0.1, 0.1),
+                    material: grey(),
+                })),
+                _ => scene.add(Box::new(Instance::new(
+                    sphere.clone(),
+                    Transform::translate(origin) * Transform::scale(Vec3::new(0.5 + rnd(), 0.5, 0.3 + rnd())),
+                ))),
+            };
+        }
+        // unbounded, stays out of the BVH
+        scene.add(Box::new(Plane {
+            origin: Vec3::new(0., -8., 0.),
+            normal: Vec3::new(0., 1., 0.),
+            size: None,
+            material: grey(),
+        }));
+        assert!(!scene.is_built());
+        scene.build();
+        assert!(scene.is_built());
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let ray = Ray::new(rnd_point(30.), rnd_unit_vector());
+            let expected = brute_force(&scene, ray);
+            let found = scene.intersect(ray, 0.).map(|result| (result.object, result.t));
+            match (expected, found) {
+                (Some((i, t)), Some((j, u))) => {
+                    assert_eq!(i, j);
+                    assert!((t - u).abs() < 1e-4);
+                    hits += 1;
+                }
+                (None, None) => (),
+                _ => panic!("BVH found {:?}, expected {:?}", found, expected),
+            }
+        }
+        assert!(hits > 500);
+
+        // adding drops the BVH, so the new object is found before a rebuild
+        let ray = Ray::new(Vec3::new(0., 50., 0.), Vec3::new(0., 1., 0.));
+        let index = scene.add(Box::new(Sphere {
+            origin: Vec3::new(0., 60., 0.),
+            radius: 1.,
+            material: grey(),
+        }));
+        assert!(!scene.is_built());
+        assert_eq!(scene.intersect(ray, 0.).map(|result| result.object), Some(index));
+        scene.build();
+        assert_eq!(scene.intersect(ray, 0.).map(|result| result.object), Some(index));
+    }
+}
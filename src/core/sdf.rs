@@ -0,0 +1,407 @@
+use std::f32;
+
+use math::*;
+use core::raytracer::*;
+use core::scene::*;
+
+/// Signed distance field built from primitives and operators. Primitives
+/// are centered on the origin; use `Translate` (or an `Instance` around the
+/// whole object) to place them.
+pub enum Sdf {
+    Sphere { radius: f32 },
+    Box { half_extents: Vec3 },
+    RoundBox { half_extents: Vec3, radius: f32 },
+    /// Torus around the y axis.
+    Torus { major_radius: f32, minor_radius: f32 },
+    Capsule { a: Vec3, b: Vec3, radius: f32 },
+    /// Distance estimate of the Mandelbulb fractal, roughly within a radius of 1.2.
+    Mandelbulb { power: f32, iterations: u32 },
+
+    Translate(Vec3, Box<Sdf>),
+    Union(Box<Sdf>, Box<Sdf>),
+    /// Union blended over a distance of about `k`.
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f32),
+    /// First operand with the second carved out, blended over about `k`.
+    SmoothSubtract(Box<Sdf>, Box<Sdf>, f32),
+    /// Infinite repetition with the given period along each axis; zero disables an axis.
+    Repeat(Vec3, Box<Sdf>),
+    /// Rotates around the y axis by `rate` radians per unit of height.
+    Twist(f32, Box<Sdf>),
+}
+
+fn clamp(x: f32, lo: f32, hi: f32) -> f32 {
+    x.max(lo).min(hi)
+}
+
+fn mix(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn repeat_axis(x: f32, period: f32) -> f32 {
+    if period > 0. {
+        x - period * (x / period).round()
+    } else {
+        x
+    }
+}
+
+impl Sdf {
+    pub fn distance(&self, p: Vec3) -> f32 {
+        match *self {
+            Sdf::Sphere { radius } => p.length() - radius,
+            Sdf::Box { half_extents } => Sdf::box_distance(p, half_extents),
+            Sdf::RoundBox { half_extents, radius } => Sdf::box_distance(p, half_extents) - radius,
+            Sdf::Torus { major_radius, minor_radius } => {
+                let ring = (p.x * p.x + p.z * p.z).sqrt() - major_radius;
+                (ring * ring + p.y * p.y).sqrt() - minor_radius
+            }
+            Sdf::Capsule { a, b, radius } => {
+                let pa = p - a;
+                let ba = b - a;
+                let h = clamp(pa.dot(ba) / ba.dot(ba), 0., 1.);
+                (pa - ba * h).length() - radius
+            }
+            Sdf::Mandelbulb { power, iterations } => Sdf::mandelbulb(p, power, iterations),
+
+            Sdf::Translate(offset, ref sdf) => sdf.distance(p - offset),
+            Sdf::Union(ref a, ref b) => a.distance(p).min(b.distance(p)),
+            Sdf::SmoothUnion(ref a, ref b, k) => {
+                let (da, db) = (a.distance(p), b.distance(p));
+                let h = clamp(0.5 + 0.5 * (db - da) / k, 0., 1.);
+                mix(db, da, h) - k * h * (1. - h)
+            }
+            Sdf::SmoothSubtract(ref a, ref b, k) => {
+                let (da, db) = (a.distance(p), b.distance(p));
+                let h = clamp(0.5 - 0.5 * (da + db) / k, 0., 1.);
+                mix(da, -db, h) + k * h * (1. - h)
+            }
+            Sdf::Repeat(period, ref sdf) => sdf.distance(Vec3::new(
+                repeat_axis(p.x, period.x),
+                repeat_axis(p.y, period.y),
+                repeat_axis(p.z, period.z),
+            )),
+            Sdf::Twist(rate, ref sdf) => {
+                let (s, c) = (rate * p.y).sin_cos();
+                let q = Vec3::new(c * p.x - s * p.z, p.y, s * p.x + c * p.z);
+                // twisting shears space by rate * radius, which stretches
+                // distances by up to this much. The radius grows along a
+                // step, so scale down by the stretch at the farthest radius
+                // the step could reach to keep the field a safe bound.
+                let stretch = |r: f32| {
+                    let a = (rate * r).abs();
+                    0.5 * (a + (a * a + 4.).sqrt())
+                };
+                let r = (p.x * p.x + p.z * p.z).sqrt();
+                let d = sdf.distance(q);
+                d / stretch(r + d.abs() / stretch(r))
+            }
+        }
+    }
+
+    fn box_distance(p: Vec3, half_extents: Vec3) -> f32 {
+        let q = Vec3::new(p.x.abs(), p.y.abs(), p.z.abs()) - half_extents;
+        q.max(Vec3::zero()).length() + q.x.max(q.y.max(q.z)).min(0.)
+    }
+
+    fn mandelbulb(p: Vec3, power: f32, iterations: u32) -> f32 {
+        let mut z = p;
+        let mut dr = 1.;
+        let mut r = 0.;
+        for _ in 0..iterations {
+            r = z.length();
+            if r > 2. {
+                break;
+            }
+            let theta = (z.z / r).acos() * power;
+            let phi = z.y.atan2(z.x) * power;
+            dr = r.powf(power - 1.) * power * dr + 1.;
+            let zr = r.powf(power);
+            z = Vec3::new(theta.sin() * phi.cos(), phi.sin() * theta.sin(), theta.cos()) * zr + p;
+        }
+        if r == 0. {
+            return 0.;
+        }
+        0.5 * r.ln() * r / dr
+    }
+
+    /// Outward normal from the central difference gradient.
+    pub fn normal(&self, p: Vec3, h: f32) -> Vec3 {
+        let dx = Vec3::new(h, 0., 0.);
+        let dy = Vec3::new(0., h, 0.);
+        let dz = Vec3::new(0., 0., h);
+        Vec3::new(
+            self.distance(p + dx) - self.distance(p - dx),
+            self.distance(p + dy) - self.distance(p - dy),
+            self.distance(p + dz) - self.distance(p - dz),
+        ).normalize()
+    }
+}
+
+/// Scene object for an `Sdf`, intersected by sphere tracing. Marching is
+/// limited to `bounds`, which must contain the whole surface.
+pub struct SdfObject {
+    pub sdf: Sdf,
+    pub bounds: Aabb,
+    pub material: Material,
+    /// Distance at which the march counts as a hit, also the gradient step.
+    pub epsilon: f32,
+    pub max_steps: u32,
+}
+
+impl SdfObject {
+    pub fn new(sdf: Sdf, bounds: Aabb, material: Material) -> SdfObject {
+        SdfObject {
+            sdf,
+            bounds,
+            material,
+            epsilon: 1e-4,
+            max_steps: 256,
+        }
+    }
+}
+
+impl SceneObject for SdfObject {
+    fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<IntersectionResult> {
+        let (t0, t1) = self.bounds.hit(ray, ray.inv_dir(), t_min, t_max)?;
+
+        // the absolute distance is marched so rays starting inside find the
+        // surface on their way out as well
+        let mut t = t0;
+        // a ray leaving the surface starts within epsilon of it and grazing
+        // ones stay there for a while, so hits only count once the march got
+        // farther away than that. Rays entering the bounds after t_min don't
+        // start on this surface.
+        let mut left = t0 > t_min;
+        for _ in 0..self.max_steps {
+            if t > t1 {
+                return None;
+            }
+            let d = self.sdf.distance(ray.point(t)).abs();
+            left |= d >= self.epsilon;
+            if d < self.epsilon && left {
+                let normal = self.sdf.normal(ray.point(t), self.epsilon);
+                return Some(IntersectionResult {
+                    t,
+                    n: normal,
+                    uv: spherical_uv(normal),
+                    material: self.material.clone(),
+                    object: 0,
+                });
+            }
+            t += d.max(self.epsilon);
+        }
+        None
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        Some(self.bounds)
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let (t0, t1) = match self.bounds.hit(ray, ray.inv_dir(), f32::NEG_INFINITY, f32::INFINITY) {
+            Some(range) => range,
+            None => return Vec::new(),
+        };
+        let surface = |t: f32| {
+            let normal = self.sdf.normal(ray.point(t), self.epsilon);
+            IntersectionResult {
+                t,
+                n: normal,
+                uv: spherical_uv(normal),
+                material: self.material.clone(),
+                object: 0,
+            }
+        };
+
+        // march through the whole range and note where the sign flips, the
+        // steps shrink to epsilon close to the surface so none is missed
+        let mut result = Vec::new();
+        let mut enter = None;
+        let mut t = t0;
+        for _ in 0..self.max_steps {
+            if t > t1 {
+                break;
+            }
+            let d = self.sdf.distance(ray.point(t));
+            if d < 0. && enter.is_none() {
+                enter = Some(surface(t));
+            } else if d >= 0. && enter.is_some() {
+                result.push(Interval {
+                    enter: enter.take().unwrap(),
+                    exit: surface(t),
+                });
+            }
+            t += d.abs().max(self.epsilon);
+        }
+        // the surface lies within the bounds, so only running out of steps
+        // leaves an interval open
+        if let Some(enter) = enter {
+            result.push(Interval {
+                enter,
+                exit: surface(t.min(t1)),
+            });
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::util::*;
+
+    fn close(a: Vec3, b: Vec3, tolerance: f32) -> bool {
+        (a - b).length() < tolerance
+    }
+
+    fn sphere(radius: f32) -> Box<Sdf> {
+        Box::new(Sdf::Sphere { radius })
+    }
+
+    fn object(sdf: Sdf, size: f32) -> SdfObject {
+        let half = Vec3::new(size, size, size);
+        SdfObject::new(sdf, Aabb::new(-half, half), Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)))
+    }
+
+    // sphere tracing needs a safe bound: no point closer than the distance
+    // at p lies on the other side of the surface
+    fn assert_safe(sdf: &Sdf, size: f32) {
+        for _ in 0..2000 {
+            let p = rnd_in_unit_sphere() * size;
+            let d = sdf.distance(p);
+            for _ in 0..10 {
+                let q = p + rnd_in_unit_sphere() * (d.abs() * 0.99);
+                assert!(sdf.distance(q) * d.signum() >= -1e-5, "{} is {} from the surface, {} crosses it", p, d, q);
+            }
+        }
+    }
+
+    #[test]
+    fn normal_test() {
+        let sdf = Sdf::Sphere { radius: 2. };
+        for _ in 0..100 {
+            let n = rnd_unit_vector();
+            assert!(close(sdf.normal(n * 2., 1e-3), n, 1e-3));
+        }
+        let sdf = Sdf::Box { half_extents: Vec3::new(1., 2., 3.) };
+        assert!(close(sdf.normal(Vec3::new(1., 0.5, 0.5), 1e-3), Vec3::new(1., 0., 0.), 1e-4));
+        assert!(close(sdf.normal(Vec3::new(0.2, -2., 1.), 1e-3), Vec3::new(0., -1., 0.), 1e-4));
+        let sdf = Sdf::Torus { major_radius: 2., minor_radius: 0.5 };
+        // on the tube the normal points away from the ring through its middle
+        let p = Vec3::new(0., 0.3, 2. + 0.4);
+        assert!(close(sdf.normal(p, 1e-3), Vec3::new(0., 0.6, 0.8), 1e-3));
+
+        let object = object(Sdf::Sphere { radius: 1. }, 1.5);
+        let hit = object.intersect(&Ray::new(Vec3::new(0.6, 0., -5.), Vec3::new(0., 0., 1.)), 0., f32::INFINITY).unwrap();
+        assert!((hit.t - 4.2).abs() < 1e-3, "t {}", hit.t);
+        assert!(close(hit.n, Vec3::new(0.6, 0., -0.8), 1e-3));
+    }
+
+    #[test]
+    fn self_hit_test() {
+        let object = object(Sdf::Sphere { radius: 1. }, 1.5);
+        for i in 0..200 {
+            let n = rnd_unit_vector();
+            let (tu, _) = n.orthonormal_basis();
+            // leaving almost tangentially at the surface the ray starts on
+            let dir = (tu + n * (i as f32 * 1e-4)).normalize();
+            let hit = object.intersect(&Ray::new(n, dir), 1e-3, f32::INFINITY);
+            assert!(hit.is_none(), "hit itself at t {}", hit.unwrap().t);
+            // refracted inwards it finds the far side of the sphere
+            let dir = (tu - n * 0.5).normalize();
+            let hit = object.intersect(&Ray::new(n, dir), 1e-3, f32::INFINITY).unwrap();
+            assert!((hit.t - 2. * n.dot(-dir)).abs() < 1e-3, "t {}", hit.t);
+        }
+        // a surface touching the bounds is still hit where the ray enters them
+        let ray = Ray::new(Vec3::new(0., 0., -5.), Vec3::new(0., 0., 1.));
+        let tight = SdfObject { bounds: Aabb::new(Vec3::new(-1., -1., -1.), Vec3::new(1., 1., 1.)), ..object };
+        assert!((tight.intersect(&ray, 0., f32::INFINITY).unwrap().t - 4.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn step_budget_test() {
+        // from the edge of the bounds one step reaches the sphere, and a
+        // second finds it there
+        let mut object = object(Sdf::Sphere { radius: 1. }, 10.);
+        let ray = Ray::new(Vec3::new(0., 0., -20.), Vec3::new(0., 0., 1.));
+        object.max_steps = 1;
+        assert!(object.intersect(&ray, 0., f32::INFINITY).is_none());
+        object.max_steps = 2;
+        assert!((object.intersect(&ray, 0., f32::INFINITY).unwrap().t - 19.).abs() < 1e-3);
+        // grazing rays converge slowly and run out of steps
+        let ray = Ray::new(Vec3::new(0., 1. - 1e-3, -20.), Vec3::new(0., 0., 1.));
+        object.max_steps = 8;
+        assert!(object.intersect(&ray, 0., f32::INFINITY).is_none());
+        object.max_steps = 256;
+        assert!(object.intersect(&ray, 0., f32::INFINITY).is_some());
+    }
+
+    #[test]
+    fn smooth_union_test() {
+        let a = Sdf::Translate(Vec3::new(-1.5, 0., 0.), sphere(1.));
+        let b = Sdf::Translate(Vec3::new(1.5, 0., 0.), sphere(1.));
+        let union = Sdf::Union(Box::new(a), Box::new(b));
+        let a = Sdf::Translate(Vec3::new(-1.5, 0., 0.), sphere(1.));
+        let b = Sdf::Translate(Vec3::new(1.5, 0., 0.), sphere(1.));
+        let smooth = Sdf::SmoothUnion(Box::new(a), Box::new(b), 1.);
+
+        // the gap between the spheres narrows, far from it nothing changes
+        assert!((union.distance(Vec3::zero()) - 0.5).abs() < 1e-5);
+        assert!((smooth.distance(Vec3::zero()) - 0.25).abs() < 1e-5);
+        for &p in &[Vec3::new(-2.5, 0., 0.), Vec3::new(4., 0., 0.), Vec3::new(1.5, 3., 0.)] {
+            assert!((smooth.distance(p) - union.distance(p)).abs() < 1e-5);
+        }
+        // blending never grows the distance
+        for _ in 0..1000 {
+            let p = rnd_in_unit_sphere() * 4.;
+            assert!(smooth.distance(p) <= union.distance(p) + 1e-5);
+        }
+        assert_safe(&smooth, 4.);
+    }
+
+    #[test]
+    fn repeat_test() {
+        let period = Vec3::new(4., 0., 4.);
+        let sdf = Sdf::Repeat(period, sphere(1.));
+        for _ in 0..100 {
+            let p = rnd_in_unit_sphere() * 2.;
+            let shifted = p + Vec3::new(4. * 3., 0., -4.);
+            assert!((sdf.distance(p) - sdf.distance(shifted)).abs() < 1e-4);
+        }
+        // zero disables the y axis
+        assert!((sdf.distance(Vec3::new(0., 5., 0.)) - 4.).abs() < 1e-5);
+
+        // a row of copies along x, the ray misses the first one and hits
+        // the next
+        let object = SdfObject::new(
+            sdf,
+            Aabb::new(Vec3::new(-1., -1., -1.), Vec3::new(9., 1., 1.)),
+            Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+        );
+        let ray = Ray::new(Vec3::new(-2., 0., 0.), Vec3::new(1., 0., 0.));
+        assert!((object.intersect(&ray, 0., f32::INFINITY).unwrap().t - 1.).abs() < 1e-3);
+        let hit = object.intersect(&ray, 1.1, f32::INFINITY).unwrap();
+        assert!((hit.t - 3.).abs() < 1e-3, "t {}", hit.t);
+        let hit = object.intersect(&ray, 3.1, f32::INFINITY).unwrap();
+        assert!((hit.t - 5.).abs() < 1e-3, "t {}", hit.t);
+    }
+
+    #[test]
+    fn twist_test() {
+        let half_extents = Vec3::new(1., 2., 0.25);
+        let rate = 0.5;
+        let sdf = Sdf::Twist(rate, Box::new(Sdf::Box { half_extents }));
+        let untwisted = Sdf::Box { half_extents };
+        // the surface at height y is the box turned by rate * y
+        for i in 0..20 {
+            let y = -2. + i as f32 * 0.2;
+            let (s, c) = (rate * y).sin_cos();
+            let corner = Vec3::new(c * 1. + s * 0.25, y, -s * 1. + c * 0.25);
+            assert!(sdf.distance(corner).abs() < 1e-5, "{} at {}", sdf.distance(corner), corner);
+            assert!(untwisted.distance(corner).abs() > 1e-3 || y.abs() < 1e-3);
+        }
+        assert!(sdf.distance(Vec3::zero()) < 0.);
+        assert!(sdf.distance(Vec3::new(0., 0., 2.)) > 0.);
+        assert_safe(&sdf, 3.);
+    }
+}
@@ -0,0 +1,213 @@
+use math::*;
+use core::medium::*;
+
+/// Wavelengths spectral rendering covers, in nanometres.
+pub const LAMBDA_MIN: f32 = 360.;
+pub const LAMBDA_MAX: f32 = 830.;
+
+/// Integral of `cie_xyz(lambda).y` over the covered wavelengths, the
+/// luminance of a spectrum that is 1 everywhere.
+const CIE_Y_INTEGRAL: f32 = 106.922_07;
+
+/// CIE 1931 color matching functions at `lambda`, by the multi-lobe Gaussian
+/// fit of Wyman, Sloan and Shirley (2013).
+pub fn cie_xyz(lambda: f32) -> Vec3 {
+    let lobe = |mu: f32, below: f32, above: f32| {
+        let t = (lambda - mu) / if lambda < mu { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    Vec3::new(
+        1.056 * lobe(599.8, 37.9, 31.0) + 0.362 * lobe(442.0, 16.0, 26.7) - 0.065 * lobe(501.1, 20.4, 26.2),
+        0.821 * lobe(568.8, 46.9, 40.5) + 0.286 * lobe(530.9, 16.3, 31.1),
+        1.217 * lobe(437.0, 11.8, 36.0) + 0.681 * lobe(459.0, 26.0, 13.8),
+    )
+}
+
+/// Linear sRGB of `xyz`. The white point is adapted (Bradford) from that of
+/// a constant spectrum to D65, so grey spectra come out grey.
+pub fn xyz_to_rgb(xyz: Vec3) -> Vec3 {
+    Vec3::new(
+        3.147_809 * xyz.x - 1.662_846 * xyz.y - 0.480_574 * xyz.z,
+        -0.994_747 * xyz.x + 1.953_571 * xyz.y + 0.039_740 * xyz.z,
+        0.063_516 * xyz.x - 0.214_511 * xyz.y + 1.151_595 * xyz.z,
+    )
+}
+
+// Smits' basis spectra in ten bins from 380 to 720 nm.
+const SMITS_WHITE: [f32; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f32; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f32; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f32; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f32; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f32; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f32; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+/// Value at `lambda` of a smooth spectrum with the color `rgb`, built from
+/// white and the primary and secondary colors (Smits 1999). It scales with
+/// `rgb`, so it serves for light as well as for reflectances. Saturated
+/// colors come back slightly off: pure red about 10% too strong.
+pub fn rgb_to_spectrum(rgb: Vec3, lambda: f32) -> f32 {
+    let bin = ((lambda - 380.) / 34.).clamp(0., 9.) as usize;
+    let (r, g, b) = (rgb.x, rgb.y, rgb.z);
+    // the smallest component is white, the middle one the secondary color
+    // of the two largest and the rest the primary of the largest
+    if r <= g && r <= b {
+        r * SMITS_WHITE[bin] + if g <= b {
+            (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+        } else {
+            (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+        }
+    } else if g <= r && g <= b {
+        g * SMITS_WHITE[bin] + if r <= b {
+            (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+        } else {
+            (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+        }
+    } else {
+        b * SMITS_WHITE[bin] + if r <= g {
+            (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+        } else {
+            (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+        }
+    }
+}
+
+/// Refractive index, possibly depending on the wavelength.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ior {
+    Constant(f32),
+    /// Cauchy's equation `a + b / l^2`, wavelength `l` in micrometres.
+    Cauchy { a: f32, b: f32 },
+    /// Sellmeier equation `n^2 = 1 + sum b[i] l^2 / (l^2 - c[i])`,
+    /// wavelength `l` in micrometres and `c` in square micrometres.
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Ior {
+    /// Schott N-BK7 crown glass, with the coefficients as published.
+    #[allow(clippy::excessive_precision)]
+    pub fn bk7() -> Ior {
+        Ior::Sellmeier {
+            b: [1.039_612_1, 0.231_792_34, 1.010_469_5],
+            c: [0.006_000_699, 0.020_017_914, 103.560_65],
+        }
+    }
+
+    /// Index at `lambda` nanometres.
+    pub fn at(&self, lambda: f32) -> f32 {
+        let l = lambda * 1e-3;
+        match *self {
+            Ior::Constant(n) => n,
+            Ior::Cauchy { a, b } => a + b / (l * l),
+            Ior::Sellmeier { b, c } => {
+                let l2 = l * l;
+                (1. + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>()).sqrt()
+            }
+        }
+    }
+
+    /// Index at the helium d line, 587.6 nm, which is what glass catalogues
+    /// quote and RGB rendering uses.
+    pub fn nd(&self) -> f32 {
+        self.at(587.6)
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(*self, Ior::Constant(_))
+    }
+}
+
+/// Wavelengths a spectral path is traced at: a uniformly picked hero
+/// wavelength and two more evenly spaced from it over the range, wrapping
+/// around (Wilkie et al. 2014). Spectral quantities along the path are
+/// `Vec3`s of their values at these wavelengths, so the usual color math
+/// works on them unchanged.
+#[derive(Clone, Copy, Debug)]
+pub struct Wavelengths {
+    /// In nanometres, the hero first.
+    pub lambda: [f32; 3],
+    /// Whether only the hero is left, see `terminate_secondary`.
+    pub single: bool,
+}
+
+impl Wavelengths {
+    /// Wavelengths for the uniform random number `u`.
+    pub fn sample(u: f32) -> Wavelengths {
+        let at = |offset: f32| {
+            let v = u + offset;
+            LAMBDA_MIN + (v - v.floor()) * (LAMBDA_MAX - LAMBDA_MIN)
+        };
+        Wavelengths {
+            lambda: [at(0.), at(1. / 3.), at(2. / 3.)],
+            single: false,
+        }
+    }
+
+    /// Spectral samples of the color `rgb`.
+    pub fn spectrum(&self, rgb: Vec3) -> Vec3 {
+        Vec3::new(
+            rgb_to_spectrum(rgb, self.lambda[0]),
+            rgb_to_spectrum(rgb, self.lambda[1]),
+            rgb_to_spectrum(rgb, self.lambda[2]),
+        )
+    }
+
+    /// `medium` with its colors sampled at the wavelengths.
+    pub fn medium(&self, medium: &Medium) -> Medium {
+        match *medium {
+            Medium::Homogeneous(ref m) => Medium::Homogeneous(HomogeneousMedium {
+                sigma_a: self.spectrum(m.sigma_a),
+                sigma_s: self.spectrum(m.sigma_s),
+                g: m.g,
+            }),
+            Medium::Grid(ref m) => {
+                let mut m = m.clone();
+                m.albedo = self.spectrum(m.albedo);
+                Medium::Grid(m)
+            }
+        }
+    }
+
+    /// Weight for a path that from here on can only follow one wavelength,
+    /// e.g. through glass that splits light up: it drops the others and
+    /// makes up for them with the hero.
+    pub fn terminate_secondary(&mut self) -> Vec3 {
+        if self.single {
+            return Vec3::new(1., 1., 1.);
+        }
+        self.single = true;
+        Vec3::new(3., 0., 0.)
+    }
+
+    /// Linear sRGB of the radiance samples, each estimating the spectrum's
+    /// color over the uniform density of its wavelength.
+    pub fn to_rgb(&self, radiance: Vec3) -> Vec3 {
+        let xyz = cie_xyz(self.lambda[0]) * radiance.x + cie_xyz(self.lambda[1]) * radiance.y +
+            cie_xyz(self.lambda[2]) * radiance.z;
+        xyz_to_rgb(xyz * ((LAMBDA_MAX - LAMBDA_MIN) / (3. * CIE_Y_INTEGRAL)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spectral_test() {
+        // a grey spectrum comes back grey, averaged over all wavelengths
+        let grey = Vec3::new(0.5, 0.5, 0.5);
+        let mut sum = Vec3::zero();
+        let n = 1000;
+        for i in 0..n {
+            let wavelengths = Wavelengths::sample((i as f32 + 0.5) / n as f32);
+            sum = sum + wavelengths.to_rgb(wavelengths.spectrum(grey));
+        }
+        let rgb = sum / n as f32;
+        assert!((rgb.x - 0.5).abs() < 0.01 && (rgb.y - 0.5).abs() < 0.01 && (rgb.z - 0.5).abs() < 0.01);
+
+        assert!((Ior::bk7().nd() - 1.5168).abs() < 1e-3);
+        // blue bends more than red
+        assert!(Ior::bk7().at(450.) > Ior::bk7().at(650.));
+        assert!(!Ior::Constant(1.5).is_dispersive());
+    }
+}
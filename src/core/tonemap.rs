@@ -0,0 +1,153 @@
+extern crate image;
+extern crate rand;
+
+use math::*;
+use core::film::*;
+use rand::Rng;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ToneMapper {
+    /// No curve, anything above 1 clips.
+    Clamp,
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    Aces,
+    /// Approximation of Troy Sobotka's AgX, desaturates bright colors
+    /// towards white instead of skewing their hue.
+    Agx,
+}
+
+impl ToneMapper {
+    pub fn from_name(name: &str) -> Option<ToneMapper> {
+        match name {
+            "clamp" => Some(ToneMapper::Clamp),
+            "reinhard" => Some(ToneMapper::Reinhard),
+            "aces" => Some(ToneMapper::Aces),
+            "agx" => Some(ToneMapper::Agx),
+            _ => None,
+        }
+    }
+
+    /// Maps scene-referred linear color to display-referred linear color in [0, 1].
+    pub fn apply(&self, c: Vec3) -> Vec3 {
+        let mapped = match *self {
+            ToneMapper::Clamp => c,
+            ToneMapper::Reinhard => per_channel(c, |x| x / (1. + x)),
+            ToneMapper::Aces => per_channel(c, |x| {
+                let x = x * 0.6;
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+            }),
+            ToneMapper::Agx => agx(c),
+        };
+        mapped.max(Vec3::zero()).min(Vec3::new(1., 1., 1.))
+    }
+}
+
+fn per_channel<F: Fn(f32) -> f32>(c: Vec3, f: F) -> Vec3 {
+    Vec3::new(f(c.x), f(c.y), f(c.z))
+}
+
+fn mat3(m: &[[f32; 3]; 3], v: Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    )
+}
+
+fn agx(c: Vec3) -> Vec3 {
+    const INSET: [[f32; 3]; 3] = [
+        [0.84247905, 0.0784336, 0.079223745],
+        [0.042328242, 0.87846863, 0.07916613],
+        [0.042375654, 0.0784336, 0.879143],
+    ];
+    const OUTSET: [[f32; 3]; 3] = [
+        [1.196879, -0.09802088, -0.09902974],
+        [-0.052896854, 1.1519032, -0.098961174],
+        [-0.052971635, -0.09804345, 1.1510737],
+    ];
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let v = mat3(&INSET, c);
+    let curve = |x: f32| {
+        // log2 encoding followed by a polynomial fit of the AgX sigmoid
+        let x = (x.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x -
+            0.00232
+    };
+    let v = mat3(&OUTSET, per_channel(v, curve));
+    // the curve produces display encoded values, linearize them again
+    per_channel(v, |x| x.max(0.).powf(2.2))
+}
+
+/// Piecewise sRGB transfer function, linear to encoded.
+pub fn srgb_encode(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Piecewise sRGB transfer function, encoded to linear.
+pub fn srgb_decode(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Turns linear radiance into 8-bit sRGB: exposure, tone mapping, transfer
+/// function and quantization with optional dithering. Used for everything
+/// that ends up on screen or in an LDR file.
+#[derive(Clone, Copy, Debug)]
+pub struct OutputTransform {
+    /// Exposure adjustment in stops.
+    pub exposure: f32,
+    pub tone_mapper: ToneMapper,
+    /// Adds triangular noise of one code value before quantization to hide banding.
+    pub dither: bool,
+}
+
+impl Default for OutputTransform {
+    fn default() -> OutputTransform {
+        OutputTransform::new()
+    }
+}
+
+impl OutputTransform {
+    pub fn new() -> OutputTransform {
+        OutputTransform {
+            exposure: 0.,
+            tone_mapper: ToneMapper::Clamp,
+            dither: true,
+        }
+    }
+
+    /// Display encoded color in [0, 1], before quantization.
+    pub fn encode(&self, linear: Vec3) -> Vec3 {
+        let c = self.tone_mapper.apply(linear * 2f32.powf(self.exposure));
+        Vec3::new(srgb_encode(c.x), srgb_encode(c.y), srgb_encode(c.z))
+    }
+
+    pub fn to_image(&self, film: &Film) -> image::DynamicImage {
+        let mut rng = rand::thread_rng();
+        let buffer = image::ImageBuffer::from_fn(film.width, film.height, |x, y| {
+            let c = self.encode(film.pixel(x, y));
+            let mut quantize = |v: f32| {
+                let noise = if self.dither {
+                    rng.gen::<f32>() - rng.gen::<f32>()
+                } else {
+                    0.
+                };
+                (v * 255. + noise + 0.5).floor().clamp(0., 255.) as u8
+            };
+            image::Rgb([quantize(c.x), quantize(c.y), quantize(c.z)])
+        });
+        image::DynamicImage::ImageRgb8(buffer)
+    }
+}
@@ -2,24 +2,16 @@ extern crate image;
 extern crate rand;
 
 use math::*;
+use core::sampler::next_primary_sample;
 use rand::Rng;
 
 
-pub fn gamma(v: Vec3) -> Vec3 {
-    Vec3::new(v.x.sqrt(), v.y.sqrt(), v.z.sqrt())
-}
-
-pub fn igamma(v: Vec3) -> Vec3 {
-    Vec3::new(v.x * v.x, v.y * v.y, v.z * v.z)
+fn to_u8(v: f32) -> u8 {
+    (v * 255.0 + 0.5).clamp(0.0, 255.0) as u8
 }
 
 pub fn rgba(r: f32, g: f32, b: f32, a: f32) -> image::Rgba<u8> {
-    image::Rgba([
-        (r * 255.0) as u8,
-        (g * 255.0) as u8,
-        (b * 255.0) as u8,
-        (a * 255.0) as u8,
-    ])
+    image::Rgba([to_u8(r), to_u8(g), to_u8(b), to_u8(a)])
 }
 
 pub fn rgb(r: f32, g: f32, b: f32) -> image::Rgba<u8> {
@@ -37,7 +29,7 @@ pub fn rgb_to_vec3(p: image::Rgba<u8>) -> Vec3 {
 pub fn lerp(a: u8, b: u8, t: f32) -> u8 {
     let x = a as f32;
     let y = b as f32;
-    ((x + (y - x) * t) as u8)
+    (x + (y - x) * t) as u8
 }
 
 pub fn lerp_rgba(im0: image::Rgba<u8>, im1: image::Rgba<u8>, x: f32) -> image::Rgba<u8> {
@@ -49,15 +41,46 @@ pub fn lerp_rgba(im0: image::Rgba<u8>, im1: image::Rgba<u8>, x: f32) -> image::R
     ])
 }
 
+/// Uniform random number in [0, 1). Light transport draws all its random
+/// decisions from here, so Metropolis sampling can take them over, see
+/// `with_primary_samples`.
+pub fn rnd() -> f32 {
+    match next_primary_sample() {
+        Some(u) => u,
+        None => rand::thread_rng().gen::<f32>(),
+    }
+}
+
+/// Uniformly picked index below `len`.
+pub fn rnd_index(len: usize) -> usize {
+    ((rnd() * len as f32) as usize).min(len - 1)
+}
+
+/// Uniformly distributed point in the unit disk.
+pub fn rnd_in_unit_disk() -> (f32, f32) {
+    loop {
+        let (x, y) = (rnd() * 2. - 1., rnd() * 2. - 1.);
+        if x * x + y * y <= 1. {
+            return (x, y);
+        }
+    }
+}
+
+/// Uniformly distributed direction.
+pub fn rnd_unit_vector() -> Vec3 {
+    loop {
+        let p = rnd_in_unit_sphere();
+        let len = p.square_length();
+        if len > 1e-6 {
+            return p / len.sqrt();
+        }
+    }
+}
+
 pub fn rnd_in_unit_sphere() -> Vec3 {
-    let mut rng = rand::thread_rng();
     let mut p: Vec3;
     loop {
-        p = Vec3::new(
-            rng.gen_range(-1., 1.),
-            rng.gen_range(-1., 1.),
-            rng.gen_range(-1., 1.),
-        );
+        p = Vec3::new(rnd() * 2. - 1., rnd() * 2. - 1., rnd() * 2. - 1.);
         if p.square_length() <= 1. {
             break;
         }
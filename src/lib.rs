@@ -0,0 +1,60 @@
+//! A progressive path tracer.
+//!
+//! Build a `Scene`, wrap it in a `RayTracer` and either call `render_scene`
+//! for a finished float framebuffer, or drive a `Renderer` into a `Frame`
+//! yourself to watch the image refine, save it or gather AOVs:
+//!
+//! ```no_run
+//! extern crate rusty_ray;
+//!
+//! use rusty_ray::*;
+//!
+//! fn main() {
+//!     let mut scene = Scene::new();
+//!     scene.add(Box::new(Sphere {
+//!         origin: Vec3::new(0., 0., 5.),
+//!         radius: 1.,
+//!         material: Material::Lambertian(Vec3::new(0.9, 0.2, 0.2)),
+//!     }));
+//!     let film = render_scene(scene, (320, 240), 16, 4);
+//!     let pixels: Vec<Vec3> = film.pixels();
+//! }
+//! ```
+//!
+//! The interactive glium viewer belongs to the `rusty-ray` binary and is
+//! only built with the `viewer` feature, which is on by default.
+
+extern crate rand;
+extern crate image;
+
+pub mod math;
+pub mod core;
+
+// The types needed to build, light and render a scene. Everything else is
+// reachable through the `math` and `core` modules.
+pub use math::{Vec3, Ray, Mat4, Transform, Aabb};
+pub use core::scene::{Scene, SceneObject, Sphere, Camera, Interval};
+pub use core::raytracer::{RayTracer, Material, IntersectionResult};
+pub use core::primitives::{Plane, Disk, AxisBox, Cylinder, Cone, Torus};
+pub use core::mesh::Mesh;
+pub use core::instance::Instance;
+pub use core::csg::{Csg, CsgOp};
+pub use core::sdf::{Sdf, SdfObject};
+pub use core::medium::{Medium, HomogeneousMedium, GridMedium};
+pub use core::grid::DensityGrid;
+pub use core::light::PointLight;
+pub use core::spectral::Ior;
+pub use core::integrator::{Integrator, integrator_from_name, INTEGRATOR_NAMES};
+pub use core::integrator::{PathTracer, Whitted, AmbientOcclusion, DebugIntegrator};
+pub use core::bdpt::BidirectionalPathTracer;
+pub use core::photon::PhotonMapper;
+pub use core::mlt::MetropolisTracer;
+pub use core::path::RecordedPath;
+pub use core::render::{render_scene, Renderer, Frame, AdaptiveSampling};
+pub use core::film::Film;
+pub use core::filter::Filter;
+pub use core::tonemap::{OutputTransform, ToneMapper};
+pub use core::aov::{Aov, AovBuffers, ALL_AOVS};
+pub use core::denoise::Denoiser;
+pub use core::exr::save_exr;
+pub use core::rgbe::save_hdr;
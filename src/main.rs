@@ -1,103 +1,260 @@
-extern crate rand;
-
-//#[macro_use]
+extern crate rusty_ray;
+#[cfg(feature = "viewer")]
 extern crate glium;
-extern crate image;
+#[cfg(feature = "viewer")]
 extern crate time;
 
-use glium::{glutin, Surface};
-//use time::PreciseTime;
-
+#[cfg(feature = "viewer")]
 mod support;
-mod math;
-mod core;
+#[cfg(feature = "viewer")]
+mod viewer;
+
+use std::sync::Arc;
+use std::time::Instant;
 
-use math::*;
-use core::*;
+use rusty_ray::*;
 
 
 const IMAGE_SIZE: (u32, u32) = (1024, 768);
+const DEFAULT_PASSES: u32 = 64;
+const DEFAULT_THREADS: usize = 4;
 
+struct Options {
+    /// Render without a window and save the result here.
+    output: Option<String>,
+    passes: u32,
+    /// Store OpenEXR output as 16-bit half floats instead of 32-bit floats.
+    half: bool,
+    aovs: Vec<Aov>,
+    denoise: bool,
+    adaptive: Option<AdaptiveSampling>,
+    filter: Filter,
+    threads: usize,
+    /// Window only: render resolution as a fraction of the window size.
+    scale: f32,
+    aperture: f32,
+    focus_distance: Option<f32>,
+    /// Overrides the scene's integrator.
+    integrator: Option<Arc<dyn Integrator>>,
+    /// Adds a point light to the scene, which otherwise is lit by the sky.
+    point_light: bool,
+    transform: OutputTransform,
+}
 
-fn main() {
-    // Building the display, ie. the main object
-    let mut events_loop = glutin::EventsLoop::new();
-    let window = glutin::WindowBuilder::new()
-        .with_dimensions(IMAGE_SIZE.0, IMAGE_SIZE.1)
-        .with_title("Rusty Ray");
-    let context = glutin::ContextBuilder::new().with_vsync(true);
-    let display = glium::Display::new(window, context, &events_loop).unwrap();
-
-    // building a texture with "OpenGL" drawn on it
-    let mut rt = RayTracer::new(IMAGE_SIZE);
-
-    // scene setup
-    rt.scene.objects.push(Box::new(Sphere {
+fn usage() -> ! {
+    eprintln!(
+        "usage: rusty-ray [--output <file> [--passes <n>] [--half] [--aov <names>]] \
+         [--denoise] [--adaptive <error> [--max-samples <n>]] [--exposure <ev>] \
+         [--tonemap clamp|reinhard|aces|agx] [--no-dither] \
+         [--filter box|tent|gaussian|mitchell [--filter-radius <pixels>]] [--threads <n>] [--scale <fraction>] \
+         [--aperture <radius> [--focus <distance>]] [--integrator <name>] [--point-light]\n\
+         With --adaptive, headless rendering runs until every pixel converged \
+         instead of for a number of passes.\n\
+         --scale renders the window at a fraction of its resolution.\n\
+         AOV names: all, or a comma separated list of depth, normal, albedo, \
+         position, object, material\n\
+         Integrators: {}",
+        INTEGRATOR_NAMES.join(", ")
+    );
+    std::process::exit(1)
+}
+
+fn parse_options<I: Iterator<Item = String>>(mut args: I) -> Options {
+    let mut options = Options {
+        output: None,
+        passes: DEFAULT_PASSES,
+        half: false,
+        aovs: Vec::new(),
+        denoise: false,
+        adaptive: None,
+        filter: Filter::Box { radius: 0.5 },
+        threads: DEFAULT_THREADS,
+        scale: 1.,
+        aperture: 0.,
+        focus_distance: None,
+        integrator: None,
+        point_light: false,
+        transform: OutputTransform::new(),
+    };
+    // applied once all flags are in, so it may come before --adaptive
+    let mut max_samples = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--output" => options.output = Some(value()),
+            "--passes" => options.passes = value().parse().unwrap_or_else(|_| usage()),
+            "--exposure" => options.transform.exposure = value().parse().unwrap_or_else(|_| usage()),
+            "--tonemap" => {
+                options.transform.tone_mapper =
+                    ToneMapper::from_name(&value()).unwrap_or_else(|| usage())
+            }
+            "--filter" => options.filter = Filter::from_name(&value()).unwrap_or_else(|| usage()),
+            "--filter-radius" => {
+                let radius = value().parse().unwrap_or_else(|_| usage());
+                options.filter = options.filter.with_radius(radius)
+            }
+            "--threads" => options.threads = value().parse().unwrap_or_else(|_| usage()),
+            "--scale" => {
+                options.scale = value().parse().unwrap_or_else(|_| usage());
+                if !(options.scale > 0. && options.scale <= 1.) {
+                    usage()
+                }
+            }
+            "--aperture" => options.aperture = value().parse().unwrap_or_else(|_| usage()),
+            "--focus" => options.focus_distance = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--integrator" => {
+                options.integrator = Some(integrator_from_name(&value()).unwrap_or_else(|| usage()))
+            }
+            "--point-light" => options.point_light = true,
+            "--half" => options.half = true,
+            "--denoise" => options.denoise = true,
+            "--adaptive" => {
+                let threshold = value().parse().unwrap_or_else(|_| usage());
+                options.adaptive = Some(AdaptiveSampling::new(threshold))
+            }
+            "--max-samples" => max_samples = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--aov" => {
+                let names = value();
+                options.aovs = if names == "all" {
+                    ALL_AOVS.to_vec()
+                } else {
+                    names
+                        .split(',')
+                        .map(|name| Aov::from_name(name).unwrap_or_else(|| usage()))
+                        .collect()
+                }
+            }
+            "--no-dither" => options.transform.dither = false,
+            _ => usage(),
+        }
+    }
+    if let Some(max_samples) = max_samples {
+        match options.adaptive {
+            Some(ref mut adaptive) => adaptive.max_samples = max_samples,
+            None => usage(),
+        }
+    }
+    options
+}
+
+fn setup_scene(scene: &mut Scene) {
+    scene.camera.focus_distance = 5.;
+    scene.add(Box::new(Sphere {
         origin: Vec3::new(0., 0., 5.),
         radius: 1.,
         material: Material::Lambertian(Vec3::new(0.9, 0.2, 0.2)),
     }));
-    rt.scene.objects.push(Box::new(Sphere {
+    scene.add(Box::new(Sphere {
         origin: Vec3::new(3., 0., 5.),
         radius: 1.,
         material: Material::Metal(Vec3::new(0.1, 0.1, 1.0)),
     }));
-    rt.scene.objects.push(Box::new(Sphere {
+    scene.add(Box::new(Sphere {
         origin: Vec3::new(-3., 0., 5.),
         radius: 1.,
         material: Material::Metal(Vec3::new(0.8, 0.8, 0.7)),
     }));
-    rt.scene.objects.push(Box::new(Sphere {
+    scene.add(Box::new(Sphere {
         origin: Vec3::new(1.1, -0.8, 2.5),
         radius: 0.2,
         material: Material::Metal(Vec3::new(0.4, 0.8, 0.7)),
     }));
-    rt.scene.objects.push(Box::new(Sphere {
-        origin: Vec3::new(0., -201., 5.),
-        radius: 200.,
+    scene.add(Box::new(Plane {
+        origin: Vec3::new(0., -1., 0.),
+        normal: Vec3::new(0., 1., 0.),
+        size: None,
         material: Material::Lambertian(Vec3::new(0.1, 0.2, 0.1)),
     }));
+}
 
-    let mut frames = 0;
-
-    // the main loop
-    support::start_loop(|| {
-        // for now update only once
-        if frames >= 0 {
-            rt.update();
-        }
+fn render_headless(renderer: &mut Renderer, frame: &mut Frame, path: &str, passes: u32, half: bool) {
+    if frame.adaptive.is_none() {
+        renderer.pass_limit = Some(passes);
+    }
+    let start = Instant::now();
+    renderer.run(frame, |frame| match frame.adaptive {
+        Some(_) => println!("pass {}, {} pixels left", frame.passes, frame.active_pixels),
+        None => println!("pass {}/{}", frame.passes, passes),
+    });
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    println!(
+        "{:.1} spp in {:.1}s, {:.2} Mrays/s",
+        frame.samples_per_pixel(),
+        seconds,
+        frame.rays as f64 / seconds * 1e-6
+    );
+    if let Err(e) = frame.save(path, half) {
+        eprintln!("failed to save {}: {}", path, e);
+        std::process::exit(1);
+    }
+}
 
-        // drawing a frame
-        //let start = PreciseTime::now();
-        {
-            let target = display.draw();
+#[cfg(feature = "viewer")]
+fn run_window(renderer: Renderer, frame: Frame, scale: f32) {
+    viewer::run(renderer, frame, scale)
+}
 
-            let image =
-                glium::texture::RawImage2d::from_raw_rgb(rt.image.raw_pixels(), rt.dimensions);
+#[cfg(not(feature = "viewer"))]
+fn run_window(_: Renderer, _: Frame, _: f32) {
+    eprintln!("built without the viewer feature, use --output");
+    std::process::exit(1)
+}
 
-            let opengl_texture = glium::Texture2d::new(&display, image).unwrap();
+fn main() {
+    let options = parse_options(std::env::args().skip(1));
 
-            opengl_texture
-                .as_surface()
-                .fill(&target, glium::uniforms::MagnifySamplerFilter::Linear);
+    let mut scene = Scene::new();
+    setup_scene(&mut scene);
+    if options.point_light {
+        scene.lights.push(PointLight::new(Vec3::new(-2., 4., 1.), Vec3::new(20., 20., 20.)));
+    }
+    scene.camera.aperture = options.aperture;
+    if let Some(distance) = options.focus_distance {
+        scene.camera.focus_distance = distance;
+    }
+    if let Some(ref integrator) = options.integrator {
+        scene.integrator = integrator.clone();
+    }
+    if options.adaptive.is_some() && scene.integrator.splats_only() {
+        eprintln!("--adaptive doesn't work with the {} integrator", scene.integrator.name());
+        std::process::exit(1);
+    }
+    let tracer = Arc::new(RayTracer::new(IMAGE_SIZE, scene));
 
-            target.finish().unwrap();
-        }
-        //println!("{} seconds", start.to(PreciseTime::now()));
+    let mut frame = Frame::new(IMAGE_SIZE);
+    frame.output = options.transform;
+    if !options.aovs.is_empty() {
+        frame.aovs = Some(AovBuffers::new(IMAGE_SIZE, options.aovs.clone()));
+    }
+    if options.denoise {
+        frame.denoiser = Some(Denoiser::new());
+    }
+    frame.adaptive = options.adaptive;
+    frame.filter = options.filter;
 
-        let mut action = support::Action::Continue;
+    let mut renderer = Renderer::new(tracer, options.threads);
+    match options.output {
+        Some(ref path) => render_headless(&mut renderer, &mut frame, path, options.passes, options.half),
+        None => run_window(renderer, frame, options.scale),
+    }
+}
 
-        // polling and handling the events received by the window
-        events_loop.poll_events(|event| match event {
-            glutin::Event::WindowEvent { event, .. } => match event {
-                glutin::WindowEvent::Closed => action = support::Action::Stop,
-                _ => (),
-            },
-            _ => (),
-        });
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        frames += 1;
+    fn parse(args: &str) -> Options {
+        parse_options(args.split_whitespace().map(String::from))
+    }
 
-        action
-    });
+    #[test]
+    fn max_samples_test() {
+        for args in &["--adaptive 0.05 --max-samples 8", "--max-samples 8 --adaptive 0.05"] {
+            let adaptive = parse(args).adaptive.unwrap();
+            assert_eq!(adaptive.threshold, 0.05);
+            assert_eq!(adaptive.max_samples, 8);
+        }
+        assert_eq!(parse("--adaptive 0.05").adaptive.unwrap().max_samples, AdaptiveSampling::new(0.05).max_samples);
+    }
 }
@@ -0,0 +1,109 @@
+use std::f32;
+
+use math::vec3::*;
+use math::ray::*;
+use math::transform::*;
+
+/// Axis-aligned bounding box.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    #[inline]
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        Aabb { min, max }
+    }
+
+    /// Box that contains nothing; growing it by anything yields that thing.
+    pub fn empty() -> Aabb {
+        Aabb::new(
+            Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    pub fn grow(self, p: Vec3) -> Aabb {
+        Aabb::new(self.min.min(p), self.max.max(p))
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.;
+        }
+        let d = self.extent();
+        2. * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Index of the longest axis.
+    pub fn largest_axis(&self) -> usize {
+        let d = self.extent();
+        if d.x > d.y && d.x > d.z {
+            0
+        } else if d.y > d.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn contains(&self, p: Vec3) -> bool {
+        p.x >= self.min.x && p.x <= self.max.x && p.y >= self.min.y && p.y <= self.max.y &&
+            p.z >= self.min.z && p.z <= self.max.z
+    }
+
+    /// Bounds of the box after transforming all eight corners.
+    pub fn transform(&self, t: &Transform) -> Aabb {
+        let mut result = Aabb::empty();
+        for i in 0..8 {
+            let corner = Vec3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            result = result.grow(t.point(corner));
+        }
+        result
+    }
+
+    /// Slab test. `inv_dir` is the componentwise reciprocal of the ray
+    /// direction, precomputed by the caller since it is shared by many boxes.
+    /// Returns the parametric range where the ray is inside the box.
+    pub fn hit(&self, ray: &Ray, inv_dir: Vec3, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+        for axis in 0..3 {
+            let inv = inv_dir.axis(axis);
+            let mut near = (self.min.axis(axis) - ray.origin.axis(axis)) * inv;
+            let mut far = (self.max.axis(axis) - ray.origin.axis(axis)) * inv;
+            if near > far {
+                ::std::mem::swap(&mut near, &mut far);
+            }
+            // max/min rather than comparisons so NaNs from 0 * inf drop out
+            t0 = near.max(t0);
+            t1 = far.min(t1);
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+}
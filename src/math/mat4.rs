@@ -0,0 +1,127 @@
+use std::ops::Mul;
+
+use math::vec3::*;
+
+/// Row-major 4x4 matrix. Points and vectors are treated as columns, so
+/// `a * b` applies `b` first.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Mat4 {
+    pub m: [[f32; 4]; 4],
+}
+
+impl Mat4 {
+    #[inline]
+    pub fn new(m: [[f32; 4]; 4]) -> Self {
+        Mat4 { m }
+    }
+
+    pub fn identity() -> Mat4 {
+        Mat4::new([
+            [1., 0., 0., 0.],
+            [0., 1., 0., 0.],
+            [0., 0., 1., 0.],
+            [0., 0., 0., 1.],
+        ])
+    }
+
+    pub fn transpose(&self) -> Mat4 {
+        let m = &self.m;
+        let mut r = [[0.; 4]; 4];
+        for i in 0..4 {
+            for j in 0..4 {
+                r[i][j] = m[j][i];
+            }
+        }
+        Mat4::new(r)
+    }
+
+    /// General inverse by cofactor expansion. Returns `None` for singular matrices.
+    pub fn inverse(&self) -> Option<Mat4> {
+        let m = &self.m;
+        let s0 = m[0][0] * m[1][1] - m[1][0] * m[0][1];
+        let s1 = m[0][0] * m[1][2] - m[1][0] * m[0][2];
+        let s2 = m[0][0] * m[1][3] - m[1][0] * m[0][3];
+        let s3 = m[0][1] * m[1][2] - m[1][1] * m[0][2];
+        let s4 = m[0][1] * m[1][3] - m[1][1] * m[0][3];
+        let s5 = m[0][2] * m[1][3] - m[1][2] * m[0][3];
+
+        let c5 = m[2][2] * m[3][3] - m[3][2] * m[2][3];
+        let c4 = m[2][1] * m[3][3] - m[3][1] * m[2][3];
+        let c3 = m[2][1] * m[3][2] - m[3][1] * m[2][2];
+        let c2 = m[2][0] * m[3][3] - m[3][0] * m[2][3];
+        let c1 = m[2][0] * m[3][2] - m[3][0] * m[2][2];
+        let c0 = m[2][0] * m[3][1] - m[3][0] * m[2][1];
+
+        let det = s0 * c5 - s1 * c4 + s2 * c3 + s3 * c2 - s4 * c1 + s5 * c0;
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let id = 1. / det;
+
+        Some(Mat4::new([
+            [
+                (m[1][1] * c5 - m[1][2] * c4 + m[1][3] * c3) * id,
+                (-m[0][1] * c5 + m[0][2] * c4 - m[0][3] * c3) * id,
+                (m[3][1] * s5 - m[3][2] * s4 + m[3][3] * s3) * id,
+                (-m[2][1] * s5 + m[2][2] * s4 - m[2][3] * s3) * id,
+            ],
+            [
+                (-m[1][0] * c5 + m[1][2] * c2 - m[1][3] * c1) * id,
+                (m[0][0] * c5 - m[0][2] * c2 + m[0][3] * c1) * id,
+                (-m[3][0] * s5 + m[3][2] * s2 - m[3][3] * s1) * id,
+                (m[2][0] * s5 - m[2][2] * s2 + m[2][3] * s1) * id,
+            ],
+            [
+                (m[1][0] * c4 - m[1][1] * c2 + m[1][3] * c0) * id,
+                (-m[0][0] * c4 + m[0][1] * c2 - m[0][3] * c0) * id,
+                (m[3][0] * s4 - m[3][1] * s2 + m[3][3] * s0) * id,
+                (-m[2][0] * s4 + m[2][1] * s2 - m[2][3] * s0) * id,
+            ],
+            [
+                (-m[1][0] * c3 + m[1][1] * c1 - m[1][2] * c0) * id,
+                (m[0][0] * c3 - m[0][1] * c1 + m[0][2] * c0) * id,
+                (-m[3][0] * s3 + m[3][1] * s1 - m[3][2] * s0) * id,
+                (m[2][0] * s3 - m[2][1] * s1 + m[2][2] * s0) * id,
+            ],
+        ]))
+    }
+
+    /// Transforms a point (w = 1), dividing by w for projective matrices.
+    pub fn transform_point(&self, p: Vec3) -> Vec3 {
+        let m = &self.m;
+        let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+        let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+        let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        if w == 1. {
+            Vec3::new(x, y, z)
+        } else {
+            Vec3::new(x, y, z) / w
+        }
+    }
+
+    /// Transforms a direction (w = 0), ignoring translation.
+    pub fn transform_vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+}
+
+impl Mul for Mat4 {
+    type Output = Mat4;
+
+    fn mul(self, other: Mat4) -> Mat4 {
+        let mut r = [[0.; 4]; 4];
+        for (i, row) in r.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = self.m[i][0] * other.m[0][j] + self.m[i][1] * other.m[1][j] +
+                    self.m[i][2] * other.m[2][j] + self.m[i][3] * other.m[3][j];
+            }
+        }
+        Mat4::new(r)
+    }
+}
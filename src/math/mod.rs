@@ -1,5 +1,12 @@
 pub mod vec3;
 pub mod ray;
+pub mod mat4;
+pub mod transform;
+pub mod aabb;
+pub mod poly;
 
 pub use self::vec3::*;
-pub use self::ray::*;
\ No newline at end of file
+pub use self::ray::*;
+pub use self::mat4::*;
+pub use self::transform::*;
+pub use self::aabb::*;
\ No newline at end of file
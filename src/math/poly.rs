@@ -0,0 +1,175 @@
+//! Real roots of low degree polynomials, used by primitives whose
+//! intersection reduces to one (the torus needs a quartic). Everything is
+//! done in f64 since quartic roots lose precision quickly in f32.
+
+use std::f64::consts::PI;
+
+const EPSILON: f64 = 1e-9;
+
+fn is_zero(x: f64) -> bool {
+    x.abs() < EPSILON
+}
+
+// degenerate input can produce NaN or infinite roots, drop those
+fn sorted(mut roots: Vec<f64>) -> Vec<f64> {
+    roots.retain(|x| x.is_finite());
+    roots.sort_by(|a, b| a.total_cmp(b));
+    roots
+}
+
+// square root of a term that is non-negative up to rounding, relative to
+// `scale`; clearly negative terms mean there is no real solution
+fn rounded_sqrt(x: f64, scale: f64) -> Option<f64> {
+    if x >= -1e-7 * scale.max(1.) {
+        Some(x.max(0.).sqrt())
+    } else {
+        None
+    }
+}
+
+/// Real roots of `a x^2 + b x + c`, ascending.
+pub fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if is_zero(a) {
+        return if is_zero(b) { vec![] } else { vec![-c / b] };
+    }
+    let d = b * b - 4. * a * c;
+    if d < 0. {
+        vec![]
+    } else if d == 0. {
+        vec![-0.5 * b / a]
+    } else {
+        // avoids cancellation between -b and sqrt(d)
+        let q = -0.5 * (b + if b < 0. { -d.sqrt() } else { d.sqrt() });
+        sorted(vec![q / a, c / q])
+    }
+}
+
+/// Real roots of `a x^3 + b x^2 + c x + d`, ascending.
+pub fn solve_cubic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    if is_zero(a) {
+        return solve_quadratic(b, c, d);
+    }
+    let (a, b, c) = (b / a, c / a, d / a);
+
+    // substitute x = y - a / 3 to eliminate the quadratic term: y^3 + 3 p y + 2 q = 0
+    let sq_a = a * a;
+    let p = (-sq_a / 3. + b) / 3.;
+    let q = (2. / 27. * a * sq_a - a * b / 3. + c) / 2.;
+    let cb_p = p * p * p;
+    let discriminant = q * q + cb_p;
+
+    let roots = if is_zero(discriminant) {
+        if is_zero(q) {
+            vec![0.]
+        } else {
+            let u = (-q).cbrt();
+            vec![2. * u, -u]
+        }
+    } else if discriminant < 0. {
+        // three real roots
+        let phi = (-q / (-cb_p).sqrt()).clamp(-1., 1.).acos() / 3.;
+        let t = 2. * (-p).sqrt();
+        vec![
+            t * phi.cos(),
+            -t * (phi + PI / 3.).cos(),
+            -t * (phi - PI / 3.).cos(),
+        ]
+    } else {
+        let sqrt_d = discriminant.sqrt();
+        vec![(sqrt_d - q).cbrt() - (sqrt_d + q).cbrt()]
+    };
+
+    sorted(roots.into_iter().map(|y| y - a / 3.).collect())
+}
+
+/// Real roots of `a x^4 + b x^3 + c x^2 + d x + e`, ascending. Uses
+/// Ferrari's method and polishes each root with a few Newton steps on the
+/// original polynomial.
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    if is_zero(a) {
+        return solve_cubic(b, c, d, e);
+    }
+    let (a, b, c, d) = (b / a, c / a, d / a, e / a);
+
+    // substitute x = y - a / 4 to eliminate the cubic term: y^4 + p y^2 + q y + r = 0
+    let sq_a = a * a;
+    let p = -3. / 8. * sq_a + b;
+    let q = sq_a * a / 8. - a * b / 2. + c;
+    let r = -3. / 256. * sq_a * sq_a + sq_a * b / 16. - a * c / 4. + d;
+
+    let mut roots = if is_zero(r) {
+        // no absolute term: y (y^3 + p y + q) = 0
+        let mut roots = solve_cubic(1., 0., p, q);
+        roots.push(0.);
+        roots
+    } else {
+        // any real root of the resolvent cubic works, the largest is the
+        // least likely to make u or v negative
+        let z = match solve_cubic(1., -p / 2., -r, r * p / 2. - q * q / 8.).last() {
+            Some(&z) => z,
+            None => return vec![],
+        };
+
+        // rounding still pushes u or v slightly below zero for tangent rays,
+        // bailing out there would lose grazing hits
+        let u = match rounded_sqrt(z * z - r, z * z + r.abs()) {
+            Some(u) => u,
+            None => return vec![],
+        };
+        let v = match rounded_sqrt(2. * z - p, 2. * z.abs() + p.abs()) {
+            Some(v) => v,
+            None => return vec![],
+        };
+
+        let mut roots = solve_quadratic(1., if q < 0. { -v } else { v }, z - u);
+        roots.extend(solve_quadratic(1., if q < 0. { v } else { -v }, z + u));
+        roots
+    };
+
+    let f = |x: f64| (((x + a) * x + b) * x + c) * x + d;
+    for x in roots.iter_mut() {
+        *x -= a / 4.;
+        for _ in 0..2 {
+            let df = ((4. * *x + 3. * a) * *x + 2. * b) * *x + c;
+            // near a double root df vanishes and a step can overshoot into
+            // another root, only keep steps that improve the residual
+            let next = *x - f(*x) / df;
+            if f(next).abs() < f(*x).abs() {
+                *x = next;
+            }
+        }
+    }
+    sorted(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx(roots: Vec<f64>, expected: &[f64]) -> bool {
+        roots.len() == expected.len() &&
+            roots.iter().zip(expected).all(|(a, b)| (a - b).abs() < 1e-6)
+    }
+
+    #[test]
+    fn poly_test() {
+        assert!(approx(solve_quadratic(1., -3., 2.), &[1., 2.]));
+        assert!(approx(solve_quadratic(1., 0., 1.), &[]));
+        assert!(approx(solve_cubic(1., -6., 11., -6.), &[1., 2., 3.]));
+        assert!(approx(solve_cubic(2., 0., 0., -16.), &[2.]));
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        assert!(approx(solve_quartic(1., -10., 35., -50., 24.), &[1., 2., 3., 4.]));
+        // (x^2 + 1)(x - 0.5)(x + 3)
+        assert!(approx(solve_quartic(1., 2.5, -0.5, 2.5, -1.5), &[-3., 0.5]));
+        assert!(approx(solve_quartic(1., 0., 0., 0., 1.), &[]));
+        // tangent rays give double roots, with u or v a hair below zero
+        for &(a, b) in &[(1., 3.), (-0.7, 2.2), (0.3, 4.1), (-2.5, -0.4)] {
+            let (s, p): (f64, f64) = (a + b, a * b);
+            let roots = solve_quartic(1., -2. * s, s * s + 2. * p, -2. * s * p, p * p);
+            assert!(!roots.is_empty(), "lost the roots {} and {}", a, b);
+            assert!(roots.iter().all(|x| (x - a).abs() < 1e-3 || (x - b).abs() < 1e-3), "{:?}", roots);
+        }
+        // NaN coefficients give no roots rather than a panic
+        assert!(solve_quartic(1., f64::NAN, 0., 0., -1.).is_empty());
+    }
+}
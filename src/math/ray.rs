@@ -1,4 +1,4 @@
-use vec3::*;
+use math::vec3::*;
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct Ray {
@@ -9,10 +9,15 @@ pub struct Ray {
 impl Ray {
     #[inline]
     pub fn new(origin: Vec3, dir: Vec3) -> Self {
-        Ray { origin: origin, dir: dir }
+        Ray { origin, dir }
     }
 
     pub fn point(self, t: f32) -> Vec3 {
         self.origin + self.dir * t
     }
+
+    /// Componentwise reciprocal of the direction, for slab tests.
+    pub fn inv_dir(&self) -> Vec3 {
+        Vec3::new(1. / self.dir.x, 1. / self.dir.y, 1. / self.dir.z)
+    }
 }
@@ -0,0 +1,185 @@
+use std::ops::Mul;
+
+use math::vec3::*;
+use math::ray::*;
+use math::mat4::*;
+
+/// Affine transform that keeps its inverse alongside, so points, vectors,
+/// normals and rays can be mapped both ways without inverting per call.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Transform {
+    pub m: Mat4,
+    pub inv: Mat4,
+}
+
+impl Transform {
+    #[inline]
+    pub fn new(m: Mat4, inv: Mat4) -> Self {
+        Transform { m, inv }
+    }
+
+    /// Builds a transform from an arbitrary matrix. Returns `None` if it is singular.
+    pub fn from_matrix(m: Mat4) -> Option<Transform> {
+        m.inverse().map(|inv| Transform::new(m, inv))
+    }
+
+    pub fn identity() -> Transform {
+        Transform::new(Mat4::identity(), Mat4::identity())
+    }
+
+    pub fn translate(d: Vec3) -> Transform {
+        let m = Mat4::new([
+            [1., 0., 0., d.x],
+            [0., 1., 0., d.y],
+            [0., 0., 1., d.z],
+            [0., 0., 0., 1.],
+        ]);
+        let inv = Mat4::new([
+            [1., 0., 0., -d.x],
+            [0., 1., 0., -d.y],
+            [0., 0., 1., -d.z],
+            [0., 0., 0., 1.],
+        ]);
+        Transform::new(m, inv)
+    }
+
+    pub fn scale(s: Vec3) -> Transform {
+        let m = Mat4::new([
+            [s.x, 0., 0., 0.],
+            [0., s.y, 0., 0.],
+            [0., 0., s.z, 0.],
+            [0., 0., 0., 1.],
+        ]);
+        let inv = Mat4::new([
+            [1. / s.x, 0., 0., 0.],
+            [0., 1. / s.y, 0., 0.],
+            [0., 0., 1. / s.z, 0.],
+            [0., 0., 0., 1.],
+        ]);
+        Transform::new(m, inv)
+    }
+
+    pub fn uniform_scale(s: f32) -> Transform {
+        Transform::scale(Vec3::new(s, s, s))
+    }
+
+    pub fn rotate_x(angle: f32) -> Transform {
+        Transform::rotate(Vec3::new(1., 0., 0.), angle)
+    }
+
+    pub fn rotate_y(angle: f32) -> Transform {
+        Transform::rotate(Vec3::new(0., 1., 0.), angle)
+    }
+
+    pub fn rotate_z(angle: f32) -> Transform {
+        Transform::rotate(Vec3::new(0., 0., 1.), angle)
+    }
+
+    /// Rotation by `angle` radians around `axis`.
+    pub fn rotate(axis: Vec3, angle: f32) -> Transform {
+        let a = axis.normalize();
+        let (s, c) = angle.sin_cos();
+        let t = 1. - c;
+        let m = Mat4::new([
+            [t * a.x * a.x + c, t * a.x * a.y - s * a.z, t * a.x * a.z + s * a.y, 0.],
+            [t * a.x * a.y + s * a.z, t * a.y * a.y + c, t * a.y * a.z - s * a.x, 0.],
+            [t * a.x * a.z - s * a.y, t * a.y * a.z + s * a.x, t * a.z * a.z + c, 0.],
+            [0., 0., 0., 1.],
+        ]);
+        // rotations are orthonormal, the inverse is the transpose
+        Transform::new(m, m.transpose())
+    }
+
+    /// Places the local frame at `eye` with +z pointing at `target` and +y
+    /// as close to `up` as possible. This matches the camera convention of
+    /// looking down +z.
+    pub fn look_at(eye: Vec3, target: Vec3, up: Vec3) -> Transform {
+        let f = (target - eye).normalize();
+        let r = up.cross(f).normalize();
+        let u = f.cross(r);
+        let m = Mat4::new([
+            [r.x, u.x, f.x, eye.x],
+            [r.y, u.y, f.y, eye.y],
+            [r.z, u.z, f.z, eye.z],
+            [0., 0., 0., 1.],
+        ]);
+        let inv = Mat4::new([
+            [r.x, r.y, r.z, -r.dot(eye)],
+            [u.x, u.y, u.z, -u.dot(eye)],
+            [f.x, f.y, f.z, -f.dot(eye)],
+            [0., 0., 0., 1.],
+        ]);
+        Transform::new(m, inv)
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform::new(self.inv, self.m)
+    }
+
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        self.m.transform_point(p)
+    }
+
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        self.m.transform_vector(v)
+    }
+
+    /// Normals transform by the inverse transpose. The result is not normalized.
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        self.inv.transpose().transform_vector(n)
+    }
+
+    /// Transforms a ray. The direction is left unnormalized so that the ray
+    /// parameter `t` means the same point in both spaces.
+    pub fn ray(&self, r: &Ray) -> Ray {
+        Ray::new(self.point(r.origin), self.vector(r.dir))
+    }
+}
+
+impl Mul for Transform {
+    type Output = Transform;
+
+    /// `a * b` applies `b` first, then `a`.
+    fn mul(self, other: Transform) -> Transform {
+        Transform::new(self.m * other.m, other.inv * self.inv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approx(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1e-4
+    }
+
+    #[test]
+    fn transform_test() {
+        let t = Transform::translate(Vec3::new(1., 2., 3.)) *
+            Transform::rotate_y(0.7) *
+            Transform::scale(Vec3::new(2., 3., 4.));
+        let p = Vec3::new(0.5, -1., 2.);
+        assert!(approx(t.inverse().point(t.point(p)), p));
+        assert!(approx(t.inv.inverse().unwrap().transform_point(p), t.point(p)));
+        assert!(approx(t.vector(Vec3::zero()), Vec3::zero()));
+        assert!(approx(
+            Transform::translate(Vec3::new(1., 0., 0.)).point(Vec3::zero()),
+            Vec3::new(1., 0., 0.)
+        ));
+        assert!(approx(
+            Transform::rotate_z(::std::f32::consts::PI / 2.).vector(Vec3::new(1., 0., 0.)),
+            Vec3::new(0., 1., 0.)
+        ));
+
+        // normals stay perpendicular to transformed tangents
+        let n = Vec3::new(1., 1., 0.).normalize();
+        let tangent = Vec3::new(1., -1., 0.);
+        assert!(t.normal(n).dot(t.vector(tangent)).abs() < 1e-4);
+
+        let eye = Vec3::new(1., 2., -3.);
+        let look = Transform::look_at(eye, Vec3::zero(), Vec3::new(0., 1., 0.));
+        assert!(approx(look.point(Vec3::zero()), eye));
+        assert!(approx(look.vector(Vec3::new(0., 0., 1.)), (-eye).normalize()));
+        assert!(approx(look.inverse().point(eye), Vec3::zero()));
+    }
+}
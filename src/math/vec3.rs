@@ -11,7 +11,7 @@ pub struct Vec3 {
 impl Vec3 {
     #[inline]
     pub fn new(x: f32, y: f32, z: f32) -> Self {
-        Vec3 { x: x, y: y, z: z }
+        Vec3 { x, y, z }
     }
 
     pub fn zero() -> Vec3 {
@@ -51,6 +51,20 @@ impl Vec3 {
         self - n.scale(2. * self.dot(n))
     }
 
+    /// Refracts the unit vector `self` through a surface with unit normal
+    /// `n` facing against it, `eta` being the ratio of the refractive
+    /// indices on the incoming and outgoing sides. `None` on total internal
+    /// reflection.
+    pub fn refract(self, n: Vec3, eta: f32) -> Option<Self> {
+        let cos = -self.dot(n);
+        let k = 1. - eta * eta * (1. - cos * cos);
+        if k < 0. {
+            None
+        } else {
+            Some(self.scale(eta) + n.scale(eta * cos - k.sqrt()))
+        }
+    }
+
     pub fn square_length(&self) -> f32 {
         self.x * self.x + self.y * self.y + self.z * self.z
     }
@@ -58,6 +72,35 @@ impl Vec3 {
     pub fn length(&self) -> f32 {
         self.square_length().sqrt()
     }
+
+    pub fn min(self, other: Vec3) -> Self {
+        Vec3::new(self.x.min(other.x), self.y.min(other.y), self.z.min(other.z))
+    }
+
+    pub fn max(self, other: Vec3) -> Self {
+        Vec3::new(self.x.max(other.x), self.y.max(other.y), self.z.max(other.z))
+    }
+
+    /// Two unit vectors that form an orthonormal basis with `self`, which
+    /// must be normalized (Duff et al. 2017).
+    pub fn orthonormal_basis(self) -> (Vec3, Vec3) {
+        let sign = if self.z >= 0. { 1. } else { -1. };
+        let a = -1. / (sign + self.z);
+        let b = self.x * self.y * a;
+        (
+            Vec3::new(1. + sign * self.x * self.x * a, sign * b, -sign * self.x),
+            Vec3::new(b, sign + self.y * self.y * a, -self.y),
+        )
+    }
+
+    /// Component by axis index: 0 is x, 1 is y, anything else is z.
+    pub fn axis(&self, axis: usize) -> f32 {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z,
+        }
+    }
 }
 
 impl Add for Vec3 {
@@ -112,6 +155,18 @@ impl Div<f32> for Vec3 {
     }
 }
 
+impl Div<Vec3> for Vec3 {
+    type Output = Vec3;
+
+    fn div(self, other: Vec3) -> Vec3 {
+        Vec3 {
+            x: self.x / other.x,
+            y: self.y / other.y,
+            z: self.z / other.z,
+        }
+    }
+}
+
 impl Neg for Vec3 {
     type Output = Vec3;
 
@@ -151,4 +206,18 @@ mod tests {
         assert!(v1.square_length() == (1. + 2. * 2. + 3. * 3.));
         assert!(Vec3::new(1., 0., 0.).cross(Vec3::new(0., 1., 0.)) == Vec3::new(0., 0., 1.));
     }
+
+    #[test]
+    fn refract_test() {
+        let n = Vec3::new(0., 1., 0.);
+        let down = Vec3::new(0., -1., 0.);
+        assert!(down.refract(n, 1.5) == Some(down));
+        // 45 degrees into glass, Snell's law holds
+        let d = Vec3::new(1., -1., 0.).normalize();
+        let r = d.refract(n, 1. / 1.5).unwrap();
+        assert!((r.length() - 1.).abs() < 1e-6);
+        assert!((r.x * 1.5 - d.x).abs() < 1e-6);
+        // and back out at the same angle is beyond the critical angle
+        assert!(d.refract(n, 1.5).is_none());
+    }
 }
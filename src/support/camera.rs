@@ -1,4 +1,9 @@
-extern crate glutin;
+use glium::glutin;
+
+use std::f32::consts::PI;
+
+/// Forward, right and up vectors of the view.
+type Basis = ((f32, f32, f32), (f32, f32, f32), (f32, f32, f32));
 
 pub struct CameraState {
     aspect_ratio: f32,
@@ -11,8 +16,20 @@ pub struct CameraState {
     moving_right: bool,
     moving_forward: bool,
     moving_backward: bool,
+
+    // mouse look, active while the left button is held and the cursor
+    // moved far enough, otherwise releasing the button counts as a click
+    pressed_at: Option<(f64, f64)>,
+    looking: bool,
+    cursor: Option<(f64, f64)>,
+    clicked: Option<(f64, f64)>,
 }
 
+/// Radians of rotation per pixel of mouse movement.
+const LOOK_SPEED: f32 = 0.003;
+/// Pixels the cursor may move between press and release of a click.
+const CLICK_DISTANCE: f64 = 3.;
+
 impl CameraState {
     pub fn new() -> CameraState {
         CameraState {
@@ -25,9 +42,32 @@ impl CameraState {
             moving_right: false,
             moving_forward: false,
             moving_backward: false,
+            pressed_at: None,
+            looking: false,
+            cursor: None,
+            clicked: None,
         }
     }
 
+    pub fn get_position(&self) -> (f32, f32, f32) {
+        self.position
+    }
+
+    pub fn get_direction(&self) -> (f32, f32, f32) {
+        self.direction
+    }
+
+    /// Last known cursor position in window pixels.
+    pub fn get_cursor(&self) -> Option<(f64, f64)> {
+        self.cursor
+    }
+
+    /// Where the left button was last clicked without dragging, if that
+    /// hasn't been asked for yet.
+    pub fn take_click(&mut self) -> Option<(f64, f64)> {
+        self.clicked.take()
+    }
+
     pub fn set_position(&mut self, pos: (f32, f32, f32)) {
         self.position = pos;
     }
@@ -37,7 +77,7 @@ impl CameraState {
     }
 
     pub fn get_perspective(&self) -> [[f32; 4]; 4] {
-        let fov: f32 = 3.141592 / 2.0;
+        let fov: f32 = PI / 2.0;
         let zfar = 1024.0;
         let znear = 0.1;
 
@@ -52,7 +92,10 @@ impl CameraState {
         ]
     }
 
-    pub fn get_view(&self) -> [[f32; 4]; 4] {
+    /// Unit forward, right and up vectors. Like the ray tracer's camera,
+    /// the view looks down +z with +x to the right, so right is
+    /// up x forward.
+    fn basis(&self) -> Basis {
         let f = {
             let f = self.direction;
             let len = f.0 * f.0 + f.1 * f.1 + f.2 * f.2;
@@ -62,19 +105,25 @@ impl CameraState {
 
         let up = (0.0, 1.0, 0.0);
 
-        let s = (f.1 * up.2 - f.2 * up.1,
-                 f.2 * up.0 - f.0 * up.2,
-                 f.0 * up.1 - f.1 * up.0);
+        let s = (up.1 * f.2 - up.2 * f.1,
+                 up.2 * f.0 - up.0 * f.2,
+                 up.0 * f.1 - up.1 * f.0);
 
-        let s_norm = {
+        let s = {
             let len = s.0 * s.0 + s.1 * s.1 + s.2 * s.2;
             let len = len.sqrt();
             (s.0 / len, s.1 / len, s.2 / len)
         };
 
-        let u = (s_norm.1 * f.2 - s_norm.2 * f.1,
-                 s_norm.2 * f.0 - s_norm.0 * f.2,
-                 s_norm.0 * f.1 - s_norm.1 * f.0);
+        let u = (f.1 * s.2 - f.2 * s.1,
+                 f.2 * s.0 - f.0 * s.2,
+                 f.0 * s.1 - f.1 * s.0);
+
+        (f, s, u)
+    }
+
+    pub fn get_view(&self) -> [[f32; 4]; 4] {
+        let (f, s, u) = self.basis();
 
         let p = (-self.position.0 * s.0 - self.position.1 * s.1 - self.position.2 * s.2,
                  -self.position.0 * u.0 - self.position.1 * u.1 - self.position.2 * u.2,
@@ -82,36 +131,15 @@ impl CameraState {
 
         // note: remember that this is column-major, so the lines of code are actually columns
         [
-            [s_norm.0, u.0, f.0, 0.0],
-            [s_norm.1, u.1, f.1, 0.0],
-            [s_norm.2, u.2, f.2, 0.0],
-            [p.0, p.1,  p.2, 1.0],
+            [s.0, u.0, f.0, 0.0],
+            [s.1, u.1, f.1, 0.0],
+            [s.2, u.2, f.2, 0.0],
+            [p.0, p.1, p.2, 1.0],
         ]
     }
 
     pub fn update(&mut self) {
-        let f = {
-            let f = self.direction;
-            let len = f.0 * f.0 + f.1 * f.1 + f.2 * f.2;
-            let len = len.sqrt();
-            (f.0 / len, f.1 / len, f.2 / len)
-        };
-
-        let up = (0.0, 1.0, 0.0);
-
-        let s = (f.1 * up.2 - f.2 * up.1,
-                 f.2 * up.0 - f.0 * up.2,
-                 f.0 * up.1 - f.1 * up.0);
-
-        let s = {
-            let len = s.0 * s.0 + s.1 * s.1 + s.2 * s.2;
-            let len = len.sqrt();
-            (s.0 / len, s.1 / len, s.2 / len)
-        };
-
-        let u = (s.1 * f.2 - s.2 * f.1,
-                 s.2 * f.0 - s.0 * f.2,
-                 s.0 * f.1 - s.1 * f.0);
+        let (f, s, u) = self.basis();
 
         if self.moving_up {
             self.position.0 += u.0 * 0.01;
@@ -150,11 +178,29 @@ impl CameraState {
         }
     }
 
+    /// Turns the view by a cursor movement in pixels, clamping the pitch
+    /// short of straight up or down.
+    fn look(&mut self, dx: f32, dy: f32) {
+        let d = self.direction;
+        let len = (d.0 * d.0 + d.1 * d.1 + d.2 * d.2).sqrt();
+        let yaw = d.0.atan2(d.2) + dx * LOOK_SPEED;
+        let pitch = ((d.1 / len).asin() - dy * LOOK_SPEED).clamp(-1.5, 1.5);
+        self.direction = (pitch.cos() * yaw.sin(), pitch.sin(), pitch.cos() * yaw.cos());
+    }
+
     pub fn process_input(&mut self, event: &glutin::WindowEvent) {
-        let input = match *event {
-            glutin::WindowEvent::KeyboardInput { input, .. } => input,
-            _ => return,
-        };
+        match *event {
+            glutin::WindowEvent::KeyboardInput { input, .. } => self.key_input(input),
+            glutin::WindowEvent::MouseInput { state, button: glutin::MouseButton::Left, .. } => {
+                self.left_button(state)
+            }
+            glutin::WindowEvent::MouseMoved { position, .. } => self.cursor_moved(position),
+            _ => (),
+        }
+    }
+
+    /// Starts or stops moving along one of the camera axes.
+    pub fn key_input(&mut self, input: glutin::KeyboardInput) {
         let pressed = input.state == glutin::ElementState::Pressed;
         let key = match input.virtual_keycode {
             Some(key) => key,
@@ -163,11 +209,36 @@ impl CameraState {
         match key {
             glutin::VirtualKeyCode::Up => self.moving_up = pressed,
             glutin::VirtualKeyCode::Down => self.moving_down = pressed,
-            glutin::VirtualKeyCode::A => self.moving_left = pressed,
-            glutin::VirtualKeyCode::D => self.moving_right = pressed,
+            glutin::VirtualKeyCode::A | glutin::VirtualKeyCode::Left => self.moving_left = pressed,
+            glutin::VirtualKeyCode::D | glutin::VirtualKeyCode::Right => self.moving_right = pressed,
             glutin::VirtualKeyCode::W => self.moving_forward = pressed,
             glutin::VirtualKeyCode::S => self.moving_backward = pressed,
             _ => (),
         };
     }
+
+    /// Pressing starts a click or a drag, releasing ends it.
+    pub fn left_button(&mut self, state: glutin::ElementState) {
+        if state == glutin::ElementState::Pressed {
+            self.pressed_at = self.cursor;
+        } else {
+            if !self.looking {
+                self.clicked = self.pressed_at;
+            }
+            self.pressed_at = None;
+            self.looking = false;
+        }
+    }
+
+    /// Tracks the cursor, turning the view while dragging.
+    pub fn cursor_moved(&mut self, position: (f64, f64)) {
+        if let Some(start) = self.pressed_at {
+            let (dx, dy) = (position.0 - start.0, position.1 - start.1);
+            self.looking |= dx * dx + dy * dy > CLICK_DISTANCE * CLICK_DISTANCE;
+        }
+        if let (true, Some(last)) = (self.looking, self.cursor) {
+            self.look((position.0 - last.0) as f32, (position.1 - last.1) as f32);
+        }
+        self.cursor = Some(position);
+    }
 }
@@ -0,0 +1,680 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use glium::{self, glutin, Surface};
+use time;
+
+use rusty_ray::*;
+
+use support;
+use support::camera::CameraState;
+
+/// How long the window waits for finished tiles before it redraws.
+const POLL_BUDGET_MS: u64 = 10;
+/// How often the statistics in the title bar are updated.
+const TITLE_INTERVAL_MS: u64 = 500;
+
+/// Interactive view of a `Frame` that keeps refining while it is shown.
+/// The frame follows the window size, scaled by `scale`, and the result is
+/// stretched over the window.
+///
+/// Keys, besides the camera controls of `CameraState`:
+///
+/// * Space pauses and resumes rendering
+/// * R throws away the accumulated samples and starts over
+/// * F12 saves the current frame as `rusty-ray-<time>.png` and `.exr`
+/// * Tab cycles through the beauty image and the AOVs
+/// * N toggles the denoiser
+/// * T traces one sample of the pixel under the cursor, saves every bounce
+///   to `path-<x>-<y>.json` and draws the path over the image
+/// * C removes the drawn path
+/// * I cycles through the integrators
+///
+/// Clicking a pixel prints what its primary ray hit, right clicking one
+/// focuses the camera on it.
+struct Viewer {
+    renderer: Renderer,
+    frame: Frame,
+    camera: CameraState,
+    /// Render resolution as a fraction of the window resolution.
+    scale: f32,
+    window_size: (u32, u32),
+    // recorded path drawn over the image
+    path: Option<RecordedPath>,
+    // the frame has samples the texture doesn't show yet
+    dirty: bool,
+    force_refresh: bool,
+    shown_passes: u32,
+    // time spent rendering since the last restart, pauses excluded
+    render_time: Duration,
+    // rays per second over the last title interval
+    ray_rate: f64,
+    title_rays: u64,
+    title_time: Instant,
+}
+
+impl Viewer {
+    fn new(renderer: Renderer, frame: Frame, scale: f32) -> Viewer {
+        let mut camera = CameraState::new();
+        {
+            let view = renderer.tracer().camera;
+            camera.set_position((view.position.x, view.position.y, view.position.z));
+            camera.set_direction((view.direction.x, view.direction.y, view.direction.z));
+        }
+        Viewer {
+            renderer,
+            frame,
+            camera,
+            scale,
+            window_size: (0, 0),
+            path: None,
+            dirty: false,
+            force_refresh: false,
+            shown_passes: 0,
+            render_time: Duration::new(0, 0),
+            ray_rate: 0.,
+            title_rays: 0,
+            title_time: Instant::now(),
+        }
+    }
+
+    /// Starts the accumulation over with `tracer`.
+    fn restart(&mut self, tracer: Arc<RayTracer>) {
+        self.renderer.restart(tracer, &mut self.frame);
+        self.render_time = Duration::new(0, 0);
+        self.title_rays = 0;
+        self.dirty = true;
+    }
+
+    /// Reallocates the frame for a window of `size` pixels and starts over.
+    fn resize(&mut self, size: (u32, u32)) {
+        let scaled = |x: u32| ((x as f32 * self.scale).round() as u32).max(1);
+        let dimensions = (scaled(size.0), scaled(size.1));
+        if size.0 == 0 || size.1 == 0 {
+            return;
+        }
+        self.window_size = size;
+        if dimensions == self.frame.dimensions {
+            return;
+        }
+        self.frame.resize(dimensions);
+        let tracer = Arc::new(self.renderer.tracer().with_dimensions(dimensions));
+        self.restart(tracer);
+    }
+
+    /// Frame pixel under a cursor position in window pixels.
+    fn pixel_at(&self, cursor: (f64, f64)) -> Option<(u32, u32)> {
+        let (w, h) = self.frame.dimensions;
+        let x = cursor.0 * w as f64 / self.window_size.0 as f64;
+        let y = cursor.1 * h as f64 / self.window_size.1 as f64;
+        if x < 0. || y < 0. || x >= w as f64 || y >= h as f64 {
+            return None;
+        }
+        Some((x as u32, y as u32))
+    }
+
+    /// Prints what the primary ray through the pixel under `cursor` hits,
+    /// along with the radiance accumulated there.
+    fn inspect(&self, cursor: (f64, f64)) {
+        let (x, y) = match self.pixel_at(cursor) {
+            Some(pixel) => pixel,
+            None => return,
+        };
+        println!("pixel ({}, {}):", x, y);
+        match self.renderer.tracer().pick(x, y) {
+            (ray, Some(hit)) => {
+                println!("  object {}, {}", hit.object, describe_material(&hit.material));
+                println!("  distance {}, point {}", hit.t, ray.point(hit.t));
+                println!("  normal {}, uv ({} {})", hit.n, hit.uv.0, hit.uv.1);
+            }
+            (_, None) => println!("  nothing hit"),
+        }
+        println!(
+            "  radiance {} from {} samples",
+            self.frame.film.pixel(x, y),
+            self.frame.film.samples(x, y)
+        );
+    }
+
+    /// Sets the focus distance to the surface under `cursor`.
+    fn focus_at(&mut self, cursor: (f64, f64)) {
+        let (x, y) = match self.pixel_at(cursor) {
+            Some(pixel) => pixel,
+            None => return,
+        };
+        let (ray, hit) = self.renderer.tracer().pick(x, y);
+        let hit = match hit {
+            Some(hit) => hit,
+            None => return,
+        };
+        let mut view = self.renderer.tracer().camera;
+        let (_, _, forward) = view.basis();
+        // the focus plane is perpendicular to the view direction
+        view.focus_distance = hit.t * ray.dir.dot(forward);
+        println!("focus distance {}", view.focus_distance);
+        let tracer = Arc::new(self.renderer.tracer().with_camera(view));
+        self.restart(tracer);
+    }
+
+    /// Records one sample of the pixel under `cursor` and saves it.
+    fn trace_path(&mut self, cursor: (f64, f64)) {
+        let (x, y) = match self.pixel_at(cursor) {
+            Some(pixel) => pixel,
+            None => return,
+        };
+        let path = self.renderer.tracer().trace_path(x, y);
+        let file = format!("path-{}-{}.json", x, y);
+        match path.save_json(&file) {
+            Ok(()) => println!(
+                "pixel ({}, {}): {} segments, radiance {}, saved to {}",
+                x,
+                y,
+                path.vertices.len(),
+                path.radiance,
+                file
+            ),
+            Err(e) => eprintln!("failed to save {}: {}", file, e),
+        }
+        self.path = Some(path);
+        self.force_refresh = true;
+    }
+
+    /// The image to show, with the recorded path drawn over it.
+    fn display_pixels(&self) -> Vec<u8> {
+        match self.path {
+            Some(ref path) => {
+                let mut image = self.frame.image.to_rgb();
+                path.draw(&mut image, self.renderer.tracer());
+                image.into_raw()
+            }
+            None => self.frame.image.raw_pixels(),
+        }
+    }
+
+    fn toggle_pause(&mut self) {
+        self.renderer.paused = !self.renderer.paused;
+    }
+
+    /// Beauty image, then every AOV in turn, then back to the beauty image.
+    fn cycle_display(&mut self) {
+        let next = match self.frame.display {
+            None => Some(ALL_AOVS[0]),
+            Some(aov) => ALL_AOVS
+                .iter()
+                .position(|&a| a == aov)
+                .and_then(|i| ALL_AOVS.get(i + 1))
+                .cloned(),
+        };
+        self.frame.display = next;
+        if next.is_some() && self.frame.aovs.is_none() {
+            // AOVs weren't gathered so far, they need a fresh start
+            self.frame.aovs = Some(AovBuffers::new(self.frame.dimensions, Vec::new()));
+            let tracer = self.renderer.tracer().clone();
+            self.restart(tracer);
+        }
+        self.force_refresh = true;
+    }
+
+    /// Switches to the next integrator in `INTEGRATOR_NAMES`.
+    fn cycle_integrator(&mut self) {
+        let current = self.renderer.tracer().integrator.name();
+        let index = INTEGRATOR_NAMES.iter().position(|&name| name == current).map_or(0, |i| i + 1);
+        let name = INTEGRATOR_NAMES[index % INTEGRATOR_NAMES.len()];
+        if let Some(integrator) = integrator_from_name(name) {
+            println!("integrator {}", name);
+            let tracer = Arc::new(self.renderer.tracer().with_integrator(integrator));
+            self.restart(tracer);
+        }
+    }
+
+    fn toggle_denoiser(&mut self) {
+        self.frame.denoiser = match self.frame.denoiser {
+            Some(_) => None,
+            None => Some(Denoiser::new()),
+        };
+        self.force_refresh = true;
+    }
+
+    /// Saves the frame as it is now, tone mapped and linear, under a name
+    /// made from the current time.
+    fn save(&self) {
+        let stamp = match time::now().strftime("%Y%m%d-%H%M%S") {
+            Ok(stamp) => stamp.to_string(),
+            Err(_) => return,
+        };
+        for extension in &["png", "exr"] {
+            let path = format!("rusty-ray-{}.{}", stamp, extension);
+            match self.frame.save(&path, false) {
+                Ok(()) => println!("saved {}", path),
+                Err(e) => eprintln!("failed to save {}: {}", path, e),
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: glutin::VirtualKeyCode) {
+        match key {
+            glutin::VirtualKeyCode::Space => self.toggle_pause(),
+            glutin::VirtualKeyCode::R => {
+                let tracer = self.renderer.tracer().clone();
+                self.restart(tracer);
+            }
+            glutin::VirtualKeyCode::F12 => self.save(),
+            glutin::VirtualKeyCode::Tab => self.cycle_display(),
+            glutin::VirtualKeyCode::N => self.toggle_denoiser(),
+            glutin::VirtualKeyCode::I => self.cycle_integrator(),
+            glutin::VirtualKeyCode::T => {
+                if let Some(cursor) = self.camera.get_cursor() {
+                    self.trace_path(cursor);
+                }
+            }
+            glutin::VirtualKeyCode::C => {
+                self.path = None;
+                self.force_refresh = true;
+            }
+            _ => (),
+        }
+    }
+
+    /// Passes `event` on to the camera and acts on the viewer's own keys
+    /// and buttons.
+    fn handle_event(&mut self, event: &glutin::WindowEvent) -> support::Action {
+        self.camera.process_input(event);
+        match *event {
+            glutin::WindowEvent::Closed => return support::Action::Stop,
+            glutin::WindowEvent::Resized(width, height) => self.resize((width, height)),
+            glutin::WindowEvent::MouseInput {
+                state: glutin::ElementState::Pressed,
+                button: glutin::MouseButton::Right,
+                ..
+            } => {
+                if let Some(cursor) = self.camera.get_cursor() {
+                    self.focus_at(cursor);
+                }
+            }
+            glutin::WindowEvent::KeyboardInput { input, .. } => {
+                if let (glutin::ElementState::Pressed, Some(key)) = (input.state, input.virtual_keycode) {
+                    self.handle_key(key);
+                }
+            }
+            _ => (),
+        }
+        support::Action::Continue
+    }
+
+    /// Restarts the rendering if the camera moved, by key or by mouse look.
+    fn update_camera(&mut self) {
+        self.camera.update();
+        let (p, d) = (self.camera.get_position(), self.camera.get_direction());
+        let mut view = self.renderer.tracer().camera;
+        let (position, direction) = (Vec3::new(p.0, p.1, p.2), Vec3::new(d.0, d.1, d.2));
+        if view.position != position || view.direction != direction {
+            view.position = position;
+            view.direction = direction;
+            let tracer = Arc::new(self.renderer.tracer().with_camera(view));
+            self.restart(tracer);
+        }
+    }
+
+    /// Merges finished tiles and returns true if the texture needs updating.
+    fn poll(&mut self, elapsed: Duration) -> bool {
+        if !self.renderer.paused && !self.renderer.is_done(&self.frame) {
+            self.render_time += elapsed;
+        }
+        self.dirty |= self.renderer.poll(&mut self.frame, Duration::from_millis(POLL_BUDGET_MS));
+
+        // denoising takes a while, so with the denoiser on only whole
+        // passes are shown
+        let pass_done = self.frame.passes != self.shown_passes;
+        let denoised = self.frame.denoiser.is_some() && self.frame.display.is_none();
+        if self.force_refresh || (self.dirty && (!denoised || pass_done)) {
+            self.frame.refresh_image();
+            self.dirty = false;
+            self.force_refresh = false;
+            self.shown_passes = self.frame.passes;
+            return true;
+        }
+        false
+    }
+
+    /// Window title with the render statistics, `None` until it is time
+    /// for an update.
+    fn title(&mut self) -> Option<String> {
+        let since = self.title_time.elapsed();
+        if since < Duration::from_millis(TITLE_INTERVAL_MS) {
+            return None;
+        }
+        let seconds = since.as_secs() as f64 + since.subsec_nanos() as f64 * 1e-9;
+        self.ray_rate = (self.frame.rays - self.title_rays) as f64 / seconds;
+        self.title_rays = self.frame.rays;
+        self.title_time = Instant::now();
+
+        let secs = self.render_time.as_secs();
+        let mut title = format!(
+            "Rusty Ray - {}, pass {}, {:.1} spp, {:.2} Mrays/s, {}:{:02}",
+            self.renderer.tracer().integrator.name(),
+            self.frame.passes,
+            self.frame.samples_per_pixel(),
+            self.ray_rate * 1e-6,
+            secs / 60,
+            secs % 60
+        );
+        if let Some(aov) = self.frame.display {
+            title.push_str(&format!(" [{}]", aov.name()));
+        }
+        if self.renderer.paused {
+            title.push_str(" [paused]");
+        }
+        Some(title)
+    }
+}
+
+/// Opens a window the size of `frame` and renders into it at `scale` times
+/// the window resolution until it is closed.
+pub fn run(renderer: Renderer, frame: Frame, scale: f32) {
+    // Building the display, ie. the main object
+    let mut events_loop = glutin::EventsLoop::new();
+    let window = glutin::WindowBuilder::new()
+        .with_dimensions(frame.dimensions.0, frame.dimensions.1)
+        .with_title("Rusty Ray");
+    let context = glutin::ContextBuilder::new().with_vsync(true);
+    let display = glium::Display::new(window, context, &events_loop).unwrap();
+
+    let mut viewer = Viewer::new(renderer, frame, scale);
+    let size = display.gl_window().get_inner_size().unwrap_or(viewer.frame.dimensions);
+    viewer.resize(size);
+    let mut texture = None;
+    let mut last_loop = Instant::now();
+
+    // the main loop
+    support::start_loop(|| {
+        // rendering happens on the renderer's threads, here finished tiles
+        // are only merged and shown
+        let now = Instant::now();
+        if viewer.poll(now - last_loop) {
+            // the image is stored top to bottom, textures bottom to top
+            let image = glium::texture::RawImage2d::from_raw_rgb_reversed(
+                &viewer.display_pixels(),
+                viewer.frame.dimensions,
+            );
+            texture = Some(glium::Texture2d::new(&display, image).unwrap());
+        }
+        last_loop = now;
+        if let Some(title) = viewer.title() {
+            display.gl_window().set_title(&title);
+        }
+
+        // drawing a frame
+        {
+            let target = display.draw();
+            if let Some(ref texture) = texture {
+                texture
+                    .as_surface()
+                    .fill(&target, glium::uniforms::MagnifySamplerFilter::Linear);
+            }
+            target.finish().unwrap();
+        }
+
+        let mut action = support::Action::Continue;
+
+        // polling and handling the events received by the window
+        events_loop.poll_events(|event| {
+            if let glutin::Event::WindowEvent { event, .. } = event {
+                if let support::Action::Stop = viewer.handle_event(&event) {
+                    action = support::Action::Stop;
+                }
+            }
+        });
+
+        if let Some(cursor) = viewer.camera.take_click() {
+            viewer.inspect(cursor);
+        }
+
+        // any camera movement starts the accumulation over
+        viewer.update_camera();
+
+        action
+    });
+}
+
+fn describe_material(material: &Material) -> String {
+    match *material {
+        Material::Lambertian(albedo) => format!("lambertian {}", albedo),
+        Material::Metal(albedo) => format!("metal {}", albedo),
+        Material::Dielectric(ref ior) => format!("dielectric, index {}", ior.nd()),
+        Material::Volume(_) => "volume boundary".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a grey sphere filling the middle of a small frame
+    fn viewer(scale: f32) -> Viewer {
+        let mut scene = Scene::new();
+        scene.add(Box::new(Sphere {
+            origin: Vec3::new(0., 0., 5.),
+            radius: 2.,
+            material: Material::Lambertian(Vec3::new(0.5, 0.5, 0.5)),
+        }));
+        let dimensions = (16, 12);
+        let tracer = Arc::new(RayTracer::new(dimensions, scene));
+        let mut viewer = Viewer::new(Renderer::new(tracer, 2), Frame::new(dimensions), scale);
+        viewer.resize(dimensions);
+        viewer
+    }
+
+    fn render(viewer: &mut Viewer, passes: u32) {
+        while viewer.frame.passes < passes {
+            viewer.poll(Duration::from_millis(1));
+        }
+    }
+
+    fn key(key: glutin::VirtualKeyCode, state: glutin::ElementState) -> glutin::KeyboardInput {
+        glutin::KeyboardInput {
+            scancode: 0,
+            state,
+            virtual_keycode: Some(key),
+            modifiers: Default::default(),
+        }
+    }
+
+    #[test]
+    fn camera_test() {
+        let mut viewer = viewer(1.);
+        render(&mut viewer, 2);
+        let view = viewer.renderer.tracer().camera;
+
+        // W moves along the view direction, the up arrow vertically
+        viewer.camera.key_input(key(glutin::VirtualKeyCode::W, glutin::ElementState::Pressed));
+        viewer.update_camera();
+        viewer.camera.key_input(key(glutin::VirtualKeyCode::W, glutin::ElementState::Released));
+        let moved = viewer.renderer.tracer().camera;
+        assert!(moved.position.z > view.position.z && moved.position.y == view.position.y);
+        assert_eq!(viewer.frame.passes, 0);
+        viewer.camera.key_input(key(glutin::VirtualKeyCode::Up, glutin::ElementState::Pressed));
+        viewer.update_camera();
+        viewer.camera.key_input(key(glutin::VirtualKeyCode::Up, glutin::ElementState::Released));
+        assert!(viewer.renderer.tracer().camera.position.y > moved.position.y);
+
+        // releasing every key stops the camera and leaves the render alone
+        render(&mut viewer, 1);
+        let passes = viewer.frame.passes;
+        viewer.update_camera();
+        assert_eq!(viewer.frame.passes, passes);
+
+        // dragging turns the view instead of clicking
+        let direction = viewer.renderer.tracer().camera.direction;
+        viewer.camera.cursor_moved((8., 6.));
+        viewer.camera.left_button(glutin::ElementState::Pressed);
+        viewer.camera.cursor_moved((40., 6.));
+        viewer.camera.left_button(glutin::ElementState::Released);
+        viewer.update_camera();
+        assert!(viewer.camera.take_click().is_none());
+        assert!(viewer.renderer.tracer().camera.direction.x > direction.x);
+    }
+
+    // files in the working directory whose name starts with `prefix`
+    fn files(prefix: &str) -> Vec<String> {
+        ::std::fs::read_dir(".")
+            .unwrap()
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.starts_with(prefix))
+            .collect()
+    }
+
+    #[test]
+    fn keys_test() {
+        let mut viewer = viewer(1.);
+        render(&mut viewer, 2);
+
+        // paused, the pass in flight finishes and no more are scheduled
+        viewer.handle_key(glutin::VirtualKeyCode::Space);
+        assert!(viewer.renderer.paused);
+        while viewer.poll(Duration::from_millis(10)) {}
+        let passes = viewer.frame.passes;
+        viewer.poll(Duration::from_millis(10));
+        assert_eq!(viewer.frame.passes, passes);
+        viewer.title_time = Instant::now() - Duration::from_secs(1);
+        let title = viewer.title().unwrap();
+        assert!(title.contains(&format!("pass {}", passes)) && title.ends_with("[paused]"));
+        viewer.handle_key(glutin::VirtualKeyCode::Space);
+        assert!(!viewer.renderer.paused);
+
+        viewer.handle_key(glutin::VirtualKeyCode::R);
+        assert_eq!((viewer.frame.passes, viewer.frame.samples), (0, 0));
+
+        // the AOVs in turn, then back to the beauty image
+        for &aov in ALL_AOVS.iter() {
+            viewer.handle_key(glutin::VirtualKeyCode::Tab);
+            assert_eq!(viewer.frame.display, Some(aov));
+        }
+        assert!(viewer.frame.aovs.is_some());
+        render(&mut viewer, 1);
+        viewer.title_time = Instant::now() - Duration::from_secs(1);
+        assert!(viewer.title().unwrap().ends_with(&format!("[{}]", ALL_AOVS[ALL_AOVS.len() - 1].name())));
+        viewer.handle_key(glutin::VirtualKeyCode::Tab);
+        assert_eq!(viewer.frame.display, None);
+
+        viewer.handle_key(glutin::VirtualKeyCode::N);
+        assert!(viewer.frame.denoiser.is_some());
+        viewer.handle_key(glutin::VirtualKeyCode::N);
+        assert!(viewer.frame.denoiser.is_none());
+
+        let name = viewer.renderer.tracer().integrator.name();
+        viewer.handle_key(glutin::VirtualKeyCode::I);
+        assert!(viewer.renderer.tracer().integrator.name() != name);
+
+        let before = files("rusty-ray-");
+        viewer.handle_key(glutin::VirtualKeyCode::F12);
+        let saved: Vec<String> = files("rusty-ray-").into_iter().filter(|name| !before.contains(name)).collect();
+        for name in &saved {
+            ::std::fs::remove_file(name).unwrap();
+        }
+        assert!(saved.iter().any(|name| name.ends_with(".png")));
+        assert!(saved.iter().any(|name| name.ends_with(".exr")));
+
+        match viewer.handle_event(&glutin::WindowEvent::Closed) {
+            support::Action::Stop => (),
+            support::Action::Continue => panic!("closing the window didn't stop the viewer"),
+        }
+    }
+
+    #[test]
+    fn resize_test() {
+        let mut viewer = viewer(0.5);
+        assert_eq!(viewer.frame.dimensions, (8, 6));
+        assert_eq!(viewer.renderer.tracer().dimensions, (8, 6));
+        render(&mut viewer, 1);
+
+        viewer.handle_event(&glutin::WindowEvent::Resized(40, 20));
+        assert_eq!(viewer.window_size, (40, 20));
+        assert_eq!(viewer.frame.dimensions, (20, 10));
+        assert_eq!(viewer.renderer.tracer().dimensions, (20, 10));
+        assert_eq!(viewer.frame.film.pixels().len(), 200);
+        assert_eq!(viewer.frame.passes, 0);
+        render(&mut viewer, 1);
+
+        // minimizing reports a zero size, which keeps the frame as it is
+        let passes = viewer.frame.passes;
+        viewer.resize((0, 0));
+        viewer.resize((40, 20));
+        assert_eq!(viewer.frame.dimensions, (20, 10));
+        assert_eq!(viewer.frame.passes, passes);
+
+        // the frame is never empty
+        viewer.resize((1, 1));
+        assert_eq!(viewer.frame.dimensions, (1, 1));
+        render(&mut viewer, 1);
+    }
+
+    #[test]
+    fn pick_test() {
+        let mut viewer = viewer(0.5);
+        viewer.resize((32, 24));
+        render(&mut viewer, 1);
+
+        // window pixels map onto the smaller frame
+        assert_eq!(viewer.pixel_at((16., 12.)), Some((8, 6)));
+        assert_eq!(viewer.pixel_at((31.9, 0.)), Some((15, 0)));
+        assert_eq!(viewer.pixel_at((32., 0.)), None);
+        assert_eq!(viewer.pixel_at((-1., 0.)), None);
+
+        // a click without dragging is handed out once
+        viewer.camera.cursor_moved((16., 12.));
+        viewer.camera.left_button(glutin::ElementState::Pressed);
+        viewer.camera.cursor_moved((17., 12.));
+        viewer.camera.left_button(glutin::ElementState::Released);
+        let click = viewer.camera.take_click().unwrap();
+        assert_eq!(click, (16., 12.));
+        assert!(viewer.camera.take_click().is_none());
+        viewer.inspect(click);
+        viewer.inspect((0., 0.));
+        viewer.inspect((100., 100.));
+
+        // the camera looks down +z, so the focus distance is the depth of
+        // the point hit on the sphere
+        let (ray, hit) = viewer.renderer.tracer().pick(8, 6);
+        let depth = ray.point(hit.unwrap().t).z;
+        assert!(depth > 3. && depth < 3.1);
+        viewer.focus_at(click);
+        let focus = viewer.renderer.tracer().camera.focus_distance;
+        assert!((focus - depth).abs() < 1e-4, "focus distance {}", focus);
+        assert_eq!(viewer.frame.passes, 0);
+
+        // the sky has no surface to focus on
+        viewer.focus_at((0., 0.));
+        assert_eq!(viewer.renderer.tracer().camera.focus_distance, focus);
+    }
+
+    #[test]
+    fn path_test() {
+        let mut viewer = viewer(1.);
+        render(&mut viewer, 1);
+        viewer.frame.refresh_image();
+
+        // without a cursor there is no pixel to trace
+        viewer.handle_key(glutin::VirtualKeyCode::T);
+        assert!(viewer.path.is_none());
+
+        viewer.camera.cursor_moved((8., 6.));
+        viewer.handle_key(glutin::VirtualKeyCode::T);
+        let saved = ::std::fs::read_to_string("path-8-6.json");
+        ::std::fs::remove_file("path-8-6.json").unwrap();
+        assert!(saved.unwrap().contains("\"vertices\""));
+        assert!(viewer.path.as_ref().unwrap().vertices.len() >= 2);
+        assert!(viewer.force_refresh);
+
+        // the overlay marks the first hit in the middle of the image
+        let raw = viewer.frame.image.raw_pixels();
+        let shown = viewer.display_pixels();
+        assert_eq!(shown.len(), raw.len());
+        let changed = (0..raw.len()).filter(|&i| raw[i] != shown[i]).collect::<Vec<_>>();
+        assert!(!changed.is_empty());
+        assert!(changed.iter().any(|&i| i / 3 == 6 * 16 + 8));
+
+        viewer.handle_key(glutin::VirtualKeyCode::C);
+        assert!(viewer.path.is_none());
+        assert!(viewer.display_pixels() == viewer.frame.image.raw_pixels());
+    }
+}
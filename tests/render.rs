@@ -0,0 +1,30 @@
+extern crate rusty_ray;
+
+use rusty_ray::*;
+
+fn close(a: Vec3, b: Vec3) -> bool {
+    (a - b).length() < 1e-4
+}
+
+#[test]
+fn render_scene_test() {
+    // a black sphere in front of the camera, everything else sees the sky
+    let sky = Vec3::new(0.5, 0.25, 1.);
+    let mut scene = Scene::new();
+    scene.background = sky;
+    scene.add(Box::new(Sphere {
+        origin: Vec3::new(0., 0., 5.),
+        radius: 1.,
+        material: Material::Lambertian(Vec3::zero()),
+    }));
+    let (width, height) = (16, 12);
+    let film = render_scene(scene, (width, height), 4, 2);
+    let pixels = film.pixels();
+    assert_eq!(pixels.len(), (width * height) as usize);
+
+    let pixel = |x: u32, y: u32| pixels[(y * width + x) as usize];
+    assert!(close(pixel(width / 2, height / 2), Vec3::zero()));
+    for &(x, y) in &[(0, 0), (width - 1, 0), (0, height - 1), (width - 1, height - 1)] {
+        assert!(close(pixel(x, y), sky));
+    }
+}